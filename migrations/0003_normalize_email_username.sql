@@ -0,0 +1,10 @@
+-- 规范化邮箱（大小写不敏感唯一），由应用启动时用与注册相同的规则（去空白、NFC、小写）回填；
+-- 规范化后重复的旧账号保留为空，避免唯一索引创建失败
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_normalized VARCHAR(255);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email_normalized ON users (email_normalized)
+    WHERE email_normalized IS NOT NULL;
+
+-- 用户名混淆骨架（UTS #39 skeleton），由应用启动时回填
+ALTER TABLE users ADD COLUMN IF NOT EXISTS username_skeleton VARCHAR(255);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_username_skeleton ON users (username_skeleton)
+    WHERE username_skeleton IS NOT NULL;
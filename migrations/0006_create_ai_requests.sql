@@ -0,0 +1,23 @@
+-- 每次 AI 调用的记录，用户可查询和删除自己的记录
+CREATE TABLE IF NOT EXISTS ai_requests (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- text、image 或 conversation
+    endpoint VARCHAR(50) NOT NULL,
+    stream BOOLEAN NOT NULL DEFAULT FALSE,
+    provider VARCHAR(50),
+    model VARCHAR(100),
+    input_summary TEXT NOT NULL,
+    response TEXT,
+    finish_reason VARCHAR(50),
+    input_tokens BIGINT,
+    output_tokens BIGINT,
+    total_tokens BIGINT,
+    latency_ms BIGINT NOT NULL,
+    -- success、error，或 incomplete（流式响应未正常结束）
+    status VARCHAR(20) NOT NULL,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_ai_requests_user ON ai_requests (user_id, id DESC);
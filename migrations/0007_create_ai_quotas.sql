@@ -0,0 +1,38 @@
+-- AI token 额度方案，额度为 NULL 表示不限
+CREATE TABLE IF NOT EXISTS ai_plans (
+    name VARCHAR(50) PRIMARY KEY,
+    daily_token_limit BIGINT CHECK (daily_token_limit >= 0),
+    monthly_token_limit BIGINT CHECK (monthly_token_limit >= 0),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO ai_plans (name, daily_token_limit, monthly_token_limit) VALUES
+    ('free', 100000, 1000000),
+    ('unlimited', NULL, NULL)
+ON CONFLICT (name) DO NOTHING;
+
+-- 用户所属方案及单独设置的额度；单独设置的额度优先于方案，没有记录的用户使用默认方案
+CREATE TABLE IF NOT EXISTS ai_user_quotas (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    plan VARCHAR(50) REFERENCES ai_plans(name),
+    daily_token_limit BIGINT CHECK (daily_token_limit >= 0),
+    monthly_token_limit BIGINT CHECK (monthly_token_limit >= 0),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- 按用户、日期（UTC）和模型汇总的 token 用量，删除调用历史不影响用量
+CREATE TABLE IF NOT EXISTS ai_usage_daily (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    -- 未知时为空字符串
+    provider VARCHAR(50) NOT NULL DEFAULT '',
+    model VARCHAR(100) NOT NULL DEFAULT '',
+    request_count BIGINT NOT NULL DEFAULT 0,
+    input_tokens BIGINT NOT NULL DEFAULT 0,
+    output_tokens BIGINT NOT NULL DEFAULT 0,
+    total_tokens BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, day, provider, model)
+);
+
+CREATE INDEX IF NOT EXISTS idx_ai_usage_daily_day ON ai_usage_daily (day);
@@ -0,0 +1,3 @@
+-- 命中响应缓存的调用：记录在历史中，计入请求数但不消耗 token 额度
+ALTER TABLE ai_requests ADD COLUMN IF NOT EXISTS cached BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE ai_usage_daily ADD COLUMN IF NOT EXISTS cache_hits BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,155 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Datelike, Utc};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::auth::audit;
+use crate::auth::auth_handlers::require_admin;
+use crate::errors::AppError;
+use crate::models::ai_quota::{
+    AIPlan, UpdatePlanRequest, UpdateUserQuotaRequest, UsageReportQuery, UserQuota, UserUsageReport,
+};
+use crate::models::audit::AuditEventType;
+use crate::models::pagination::Paginated;
+use crate::models::user::User;
+use super::service::AIServiceImpl;
+
+// 与 ai_plans.name 的列宽一致
+const PLAN_NAME_MAX_CHARS: usize = 50;
+
+// 套餐名会出现在 URL 和配额记录中，只允许小写字母、数字、下划线和连字符
+fn validate_plan_name(name: &str) -> Result<(), AppError> {
+    if name.is_empty() || name.len() > PLAN_NAME_MAX_CHARS {
+        return Err(AppError::ValidationError(format!("套餐名长度必须为 1 到 {} 个字符", PLAN_NAME_MAX_CHARS)));
+    }
+    if !name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-') {
+        return Err(AppError::ValidationError("套餐名只能包含小写字母、数字、下划线和连字符".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn provider_status(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    ai_service: web::Data<AIServiceImpl>,
+) -> Result<HttpResponse, AppError> {
+    require_admin(&req, &db).await?;
+
+    Ok(HttpResponse::Ok().json(ai_service.provider_status()))
+}
+
+// 手动关闭熔断器，用于确认上游恢复后立即放行流量
+pub async fn reset_provider_circuit(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    ai_service: web::Data<AIServiceImpl>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let admin = require_admin(&req, &db).await?;
+    let provider = path.into_inner();
+
+    if !ai_service.reset_circuit(&provider) {
+        return Err(AppError::NotFound(format!("AI 提供商不存在: {}", provider)));
+    }
+
+    audit::record(&db, &req, AuditEventType::AdminAction, Some(admin.id), None, json!({
+        "action": "reset_ai_circuit",
+        "provider": provider,
+    })).await;
+
+    Ok(HttpResponse::Ok().json(ai_service.provider_status()))
+}
+
+// 按用户汇总的 token 用量，默认为本月
+pub async fn usage_report(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    query: web::Query<UsageReportQuery>,
+) -> Result<HttpResponse, AppError> {
+    require_admin(&req, &db).await?;
+
+    let today = Utc::now().date_naive();
+    let from = query.from.unwrap_or_else(|| today.with_day(1).unwrap_or(today));
+    let to = query.to.unwrap_or(today);
+    if from > to {
+        return Err(AppError::ValidationError("开始日期不能晚于结束日期".to_string()));
+    }
+
+    let pagination = query.pagination();
+    let (rows, total) = UserUsageReport::search(&db, from, to, &pagination).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "from": from,
+        "to": to,
+        "report": Paginated::new(rows, &pagination, total),
+    })))
+}
+
+pub async fn list_plans(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    require_admin(&req, &db).await?;
+
+    Ok(HttpResponse::Ok().json(AIPlan::list(&db).await?))
+}
+
+pub async fn update_plan(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    path: web::Path<String>,
+    body: web::Json<UpdatePlanRequest>,
+) -> Result<HttpResponse, AppError> {
+    let admin = require_admin(&req, &db).await?;
+    let name = path.into_inner();
+    validate_plan_name(&name)?;
+
+    let plan = AIPlan::upsert(&db, &name, &body).await?;
+
+    audit::record(&db, &req, AuditEventType::AdminAction, Some(admin.id), None, json!({
+        "action": "update_ai_plan",
+        "plan": name,
+        "daily_token_limit": plan.daily_token_limit,
+        "monthly_token_limit": plan.monthly_token_limit,
+    })).await;
+
+    Ok(HttpResponse::Ok().json(plan))
+}
+
+pub async fn update_user_quota(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    path: web::Path<Uuid>,
+    body: web::Json<UpdateUserQuotaRequest>,
+) -> Result<HttpResponse, AppError> {
+    let admin = require_admin(&req, &db).await?;
+    let user_id = path.into_inner();
+
+    if User::find_by_id(&db, user_id).await?.is_none() {
+        return Err(AppError::NotFound("用户不存在".to_string()));
+    }
+    let quota = UserQuota::upsert(&db, user_id, &body).await?;
+
+    audit::record(&db, &req, AuditEventType::AdminAction, Some(admin.id), Some(user_id), json!({
+        "action": "update_ai_quota",
+        "plan": quota.plan,
+        "daily_token_limit": quota.daily_token_limit,
+        "monthly_token_limit": quota.monthly_token_limit,
+    })).await;
+
+    Ok(HttpResponse::Ok().json(quota))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_plan_names() {
+        for name in ["free", "unlimited", "pro-2024", "team_plus", &"a".repeat(PLAN_NAME_MAX_CHARS)] {
+            assert!(validate_plan_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "Pro", "pro plan", "套餐", "free/../x", &"a".repeat(PLAN_NAME_MAX_CHARS + 1)] {
+            assert!(matches!(validate_plan_name(name), Err(AppError::ValidationError(_))), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::{AICacheConfig, AIEmbeddingConfig};
+use crate::errors::AppError;
+use crate::models::ai::{
+    AIRequest, AIResponse, ChatMessage, ContentPart, EmbeddingRequest, EmbeddingResponse, GenerationParams,
+};
+use crate::service::redis_service::RedisService;
+use super::service::{AIService, AIServiceImpl};
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheMode {
+    // temperature 大于 0 时不使用缓存
+    #[default]
+    Auto,
+    // 无论 temperature 如何都使用缓存
+    Force,
+    Off,
+}
+
+// 文本去掉首尾空白，图片按内容哈希，保证相同输入得到相同的键
+fn normalize_message(message: &ChatMessage) -> Value {
+    let content: Vec<Value> = message
+        .content
+        .iter()
+        .filter_map(|part| match part {
+            ContentPart::Text { text } => Some(json!({ "text": text.trim() })),
+            ContentPart::Image { data } => Some(json!({ "image": hex::encode(Sha256::digest(data)) })),
+            // 带图片 URL 的请求不使用缓存，见 cache_key
+            ContentPart::ImageUrl { .. } => None,
+        })
+        .collect();
+    json!({
+        "role": message.role.as_str(),
+        "content": content,
+        "tool_calls": message.tool_calls,
+        "tool_call_id": message.tool_call_id,
+    })
+}
+
+// 请求的缓存键，不应使用缓存时返回 None。
+// URL 指向的图片内容可能变化，只按 URL 计算的键会返回过期的结果，因此不缓存
+fn cache_key(provider: &str, model: &str, params: &GenerationParams, request: &AIRequest, mode: CacheMode) -> Option<String> {
+    // 未设置 temperature 时使用提供商的默认值，同样缓存
+    if mode == CacheMode::Auto && params.temperature.is_some_and(|temperature| temperature > 0.0) {
+        return None;
+    }
+    let has_image_urls = request
+        .messages
+        .iter()
+        .any(|message| message.content.iter().any(|part| matches!(part, ContentPart::ImageUrl { .. })));
+    if has_image_urls {
+        return None;
+    }
+
+    let messages: Vec<Value> = request.messages.iter().map(normalize_message).collect();
+    let fingerprint = json!({
+        "provider": provider,
+        "model": model,
+        "messages": messages,
+        "tools": request.tools,
+        "response_schema": request.response_schema,
+        "params": params,
+    });
+    Some(format!("ai:cache:{}", hex::encode(Sha256::digest(fingerprint.to_string()))))
+}
+
+// 非流式 AI 响应的 Redis 缓存，键由提供商、模型、消息和生成参数计算
+#[derive(Clone)]
+pub struct ResponseCache {
+    redis: RedisService,
+    config: AICacheConfig,
+}
+
+impl ResponseCache {
+    pub fn new(redis: RedisService, config: &AICacheConfig) -> Self {
+        Self {
+            redis,
+            config: config.clone(),
+        }
+    }
+
+    // 返回缓存键和请求路由到的主提供商
+    fn key_for(
+        &self,
+        ai_service: &AIServiceImpl,
+        request: &AIRequest,
+        mode: CacheMode,
+    ) -> Result<Option<(String, String)>, AppError> {
+        if !self.config.enabled || mode == CacheMode::Off {
+            return Ok(None);
+        }
+        let (provider, model, params) = ai_service.primary_target(request)?;
+        Ok(cache_key(&provider, &model, &params, request, mode).map(|key| (key, provider)))
+    }
+
+    // 命中时直接返回缓存的响应；Redis 不可用时照常调用提供商
+    pub async fn analyze(
+        &self,
+        ai_service: &AIServiceImpl,
+        request: AIRequest,
+        mode: CacheMode,
+    ) -> Result<AIResponse, AppError> {
+        let started = Instant::now();
+        let Some((key, primary)) = self.key_for(ai_service, &request, mode)? else {
+            return ai_service.analyze(request).await;
+        };
+
+        match self.redis.query::<Option<String>>(redis::cmd("GET").arg(&key)).await {
+            Ok(Some(cached)) => match serde_json::from_str::<AIResponse>(&cached) {
+                Ok(mut response) => {
+                    response.cached = true;
+                    response.latency_ms = started.elapsed().as_millis() as u64;
+                    return Ok(response);
+                }
+                Err(e) => log::warn!("AI 响应缓存解析失败: {:?}", e),
+            },
+            Ok(None) => {}
+            Err(e) => log::warn!("读取 AI 响应缓存失败: {:?}", e),
+        }
+
+        let response = ai_service.analyze(request).await?;
+        // 键按主提供商和模型计算，备用提供商的回答不能以它的名义缓存
+        if response.provider != primary {
+            return Ok(response);
+        }
+        match serde_json::to_string(&response) {
+            Ok(value) => {
+                let set = redis::cmd("SET").arg(&key).arg(value).arg("EX").arg(self.config.ttl_secs).clone();
+                if let Err(e) = self.redis.query::<()>(&set).await {
+                    log::warn!("写入 AI 响应缓存失败: {:?}", e);
+                }
+            }
+            Err(e) => log::warn!("AI 响应序列化失败: {:?}", e),
+        }
+        Ok(response)
+    }
+}
+
+// 向量按小端 f32 字节存储，比 JSON 更紧凑且不损失精度
+fn encode_embedding(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn decode_embedding(bytes: &[u8]) -> Option<Vec<f32>> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect(),
+    )
+}
+
+// 向量嵌入的 Redis 缓存，键由提供商、模型和原始文本的 SHA-256 计算
+#[derive(Clone)]
+pub struct EmbeddingCache {
+    redis: RedisService,
+    config: AIEmbeddingConfig,
+}
+
+impl EmbeddingCache {
+    pub fn new(redis: RedisService, config: &AIEmbeddingConfig) -> Self {
+        Self {
+            redis,
+            config: config.clone(),
+        }
+    }
+
+    fn key(provider: &str, model: &str, input: &str) -> String {
+        format!("ai:embedding:{}:{}:{}", provider, model, hex::encode(Sha256::digest(input)))
+    }
+
+    // 未开启缓存或 Redis 不可用时全部视为未命中
+    async fn lookup(&self, keys: &[String]) -> Vec<Option<Vec<f32>>> {
+        if !self.config.cache_enabled {
+            return vec![None; keys.len()];
+        }
+        match self.redis.query::<Vec<Option<Vec<u8>>>>(redis::cmd("MGET").arg(keys)).await {
+            Ok(values) if values.len() == keys.len() => {
+                values.iter().map(|value| value.as_deref().and_then(decode_embedding)).collect()
+            }
+            Ok(_) => vec![None; keys.len()],
+            Err(e) => {
+                log::warn!("读取向量缓存失败: {:?}", e);
+                vec![None; keys.len()]
+            }
+        }
+    }
+
+    async fn store(&self, entries: &[(&str, &[f32])]) {
+        if !self.config.cache_enabled || entries.is_empty() {
+            return;
+        }
+        let mut pipeline = redis::pipe();
+        for (key, embedding) in entries {
+            pipeline.set_ex(*key, encode_embedding(embedding), self.config.cache_ttl_secs).ignore();
+        }
+        if let Err(e) = self.redis.pipeline::<()>(&pipeline).await {
+            log::warn!("写入向量缓存失败: {:?}", e);
+        }
+    }
+
+    // 命中缓存的输入不再请求提供商，同一请求中重复的输入只计算一次
+    pub async fn embed(&self, ai_service: &AIServiceImpl, request: EmbeddingRequest) -> Result<EmbeddingResponse, AppError> {
+        let started = Instant::now();
+        let (provider, model) = ai_service.embedding_target(&request)?;
+        let keys: Vec<String> = request.input.iter().map(|input| Self::key(&provider, &model, input)).collect();
+        let mut embeddings = self.lookup(&keys).await;
+        let cached = embeddings.iter().filter(|embedding| embedding.is_some()).count();
+
+        let mut missing: HashMap<&str, usize> = HashMap::new();
+        let mut inputs = Vec::new();
+        for ((key, input), embedding) in keys.iter().zip(&request.input).zip(&embeddings) {
+            if embedding.is_none() && !missing.contains_key(key.as_str()) {
+                missing.insert(key, inputs.len());
+                inputs.push(input.clone());
+            }
+        }
+
+        let mut usage = None;
+        if !inputs.is_empty() {
+            let batch = ai_service.embed(&provider, &model, inputs).await?;
+            let entries: Vec<(&str, &[f32])> = missing
+                .iter()
+                .map(|(key, index)| (*key, batch.embeddings[*index].as_slice()))
+                .collect();
+            self.store(&entries).await;
+            for (key, embedding) in keys.iter().zip(embeddings.iter_mut()) {
+                if embedding.is_none() {
+                    *embedding = Some(batch.embeddings[missing[key.as_str()]].clone());
+                }
+            }
+            usage = batch.usage;
+        }
+
+        Ok(EmbeddingResponse {
+            embeddings: embeddings.into_iter().flatten().collect(),
+            provider,
+            model,
+            usage,
+            latency_ms: started.elapsed().as_millis() as u64,
+            cached,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ai::ChatRole;
+
+    fn request(parts: Vec<ContentPart>) -> AIRequest {
+        AIRequest {
+            messages: vec![ChatMessage {
+                role: ChatRole::User,
+                content: parts,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+            }],
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    fn text(text: &str) -> ContentPart {
+        ContentPart::Text { text: text.to_string() }
+    }
+
+    fn temperature(value: f64) -> GenerationParams {
+        GenerationParams { temperature: Some(value), ..GenerationParams::default() }
+    }
+
+    #[test]
+    fn key_is_stable_across_whitespace_and_changes_with_target() {
+        let params = GenerationParams::default();
+        let key = |provider, model, text_part| cache_key(provider, model, &params, &request(vec![text(text_part)]), CacheMode::Auto);
+
+        let base = key("openai", "gpt-4o", "describe this").unwrap();
+        assert!(base.starts_with("ai:cache:"));
+        assert_eq!(key("openai", "gpt-4o", "  describe this\n"), Some(base.clone()));
+        assert_ne!(key("openai", "gpt-4o-mini", "describe this"), Some(base.clone()));
+        assert_ne!(key("tongyi", "gpt-4o", "describe this"), Some(base.clone()));
+        assert_ne!(key("openai", "gpt-4o", "describe that"), Some(base));
+    }
+
+    #[test]
+    fn images_are_keyed_by_content() {
+        let params = GenerationParams::default();
+        let key = |data: &[u8]| {
+            cache_key("openai", "gpt-4o", &params, &request(vec![ContentPart::Image { data: data.to_vec() }]), CacheMode::Auto)
+        };
+
+        assert_eq!(key(b"png-1"), key(b"png-1"));
+        assert_ne!(key(b"png-1"), key(b"png-2"));
+    }
+
+    #[test]
+    fn image_urls_bypass_cache() {
+        let request = request(vec![text("what is this"), ContentPart::ImageUrl { url: "https://example.com/a.png".to_string() }]);
+
+        assert_eq!(cache_key("openai", "gpt-4o", &GenerationParams::default(), &request, CacheMode::Force), None);
+    }
+
+    #[test]
+    fn auto_mode_skips_positive_temperature_and_force_does_not() {
+        let request = request(vec![text("hello")]);
+        let key = |params: &GenerationParams, mode| cache_key("openai", "gpt-4o", params, &request, mode);
+
+        assert_eq!(key(&temperature(0.7), CacheMode::Auto), None);
+        assert!(key(&temperature(0.0), CacheMode::Auto).is_some());
+        assert!(key(&GenerationParams::default(), CacheMode::Auto).is_some());
+        assert!(key(&temperature(0.7), CacheMode::Force).is_some());
+        assert_ne!(key(&temperature(0.7), CacheMode::Force), key(&temperature(0.0), CacheMode::Force));
+    }
+}
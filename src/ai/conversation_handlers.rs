@@ -0,0 +1,355 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::AppError;
+use crate::models::ai::{AIRequest, ChatMessage, ChatRole, ContentPart, GenerationParams};
+use crate::models::conversation::{
+    Attachment, Conversation, CreateConversationRequest, Message, NewExchange,
+    RenameConversationRequest, SendMessageRequest, SendMessageResponse, DEFAULT_CONVERSATION_TITLE,
+};
+use crate::models::pagination::{Paginated, PaginationQuery};
+use crate::service::image_processing::sniff_image_kind;
+use crate::service::storage::Storage;
+use super::handlers::current_user_id;
+use super::history::CallRecorder;
+use super::quota::QuotaService;
+use super::service::{AIService, AIServiceImpl};
+
+const TITLE_MAX_CHARS: usize = 200;
+const MESSAGE_MAX_CHARS: usize = 32_000;
+// 与 conversations 表的列宽一致
+const PROVIDER_MAX_CHARS: usize = 50;
+const MODEL_MAX_CHARS: usize = 100;
+
+fn validate_title(title: &str) -> Result<String, AppError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(AppError::ValidationError("对话标题不能为空".to_string()));
+    }
+    if title.chars().count() > TITLE_MAX_CHARS {
+        return Err(AppError::ValidationError(format!("对话标题不能超过 {} 个字符", TITLE_MAX_CHARS)));
+    }
+    Ok(title.to_string())
+}
+
+// 空值视为未设置
+fn validate_setting(value: Option<&str>, max_chars: usize, field: &str) -> Result<Option<String>, AppError> {
+    let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+        return Ok(None);
+    };
+    if value.chars().count() > max_chars {
+        return Err(AppError::ValidationError(format!("{}不能超过 {} 个字符", field, max_chars)));
+    }
+    Ok(Some(value.to_string()))
+}
+
+// 提供商名统一为小写，且必须已启用
+fn validate_provider(ai_service: &AIServiceImpl, provider: Option<&str>) -> Result<Option<String>, AppError> {
+    let provider = validate_setting(provider, PROVIDER_MAX_CHARS, "提供商")?.map(|provider| provider.to_lowercase());
+    if let Some(provider) = provider.as_deref() {
+        if !ai_service.has_provider(provider) {
+            return Err(AppError::ValidationError(format!("AI provider not available: {}", provider)));
+        }
+    }
+    Ok(provider)
+}
+
+async fn load_conversation(
+    db: &crate::db::DbPool,
+    id: Uuid,
+    user_id: Uuid,
+) -> Result<Conversation, AppError> {
+    Conversation::find_for_user(db, id, user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("对话不存在".to_string()))
+}
+
+// 并发读取附件内容，文件丢失时跳过
+async fn load_images(storage: &dyn Storage, attachments: &[Attachment]) -> Result<HashMap<Uuid, Vec<u8>>, AppError> {
+    let objects = futures::future::try_join_all(
+        attachments.iter().map(|attachment| storage.get(&attachment.storage_key)),
+    )
+    .await?;
+
+    let mut images = HashMap::with_capacity(attachments.len());
+    for (attachment, object) in attachments.iter().zip(objects) {
+        match object {
+            Some(object) => {
+                images.insert(attachment.id, object.data);
+            }
+            None => log::warn!("对话附件文件不存在: {}", attachment.storage_key),
+        }
+    }
+    Ok(images)
+}
+
+// 图片按引用顺序在前、文本在后
+fn chat_message(role: ChatRole, attachment_ids: &[Uuid], images: &HashMap<Uuid, Vec<u8>>, text: String) -> ChatMessage {
+    let mut content: Vec<ContentPart> = attachment_ids
+        .iter()
+        .filter_map(|id| images.get(id))
+        .map(|data| ContentPart::Image { data: data.clone() })
+        .collect();
+    content.push(ContentPart::Text { text });
+    ChatMessage { role, content, tool_calls: Vec::new(), tool_call_id: None }
+}
+
+pub async fn create_conversation(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    ai_service: web::Data<AIServiceImpl>,
+    body: web::Json<CreateConversationRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let title = match body.title.as_deref() {
+        Some(title) => validate_title(title)?,
+        None => DEFAULT_CONVERSATION_TITLE.to_string(),
+    };
+
+    let settings = CreateConversationRequest {
+        title: None,
+        provider: validate_provider(&ai_service, body.provider.as_deref())?,
+        model: validate_setting(body.model.as_deref(), MODEL_MAX_CHARS, "模型名")?,
+        system_prompt: validate_setting(body.system_prompt.as_deref(), MESSAGE_MAX_CHARS, "系统提示词")?,
+    };
+
+    let conversation = Conversation::create(&db, user_id, &title, &settings).await?;
+    Ok(HttpResponse::Created().json(conversation))
+}
+
+pub async fn list_conversations(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    query: web::Query<PaginationQuery>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let (conversations, total) = Conversation::list_for_user(&db, user_id, &query).await?;
+    Ok(HttpResponse::Ok().json(Paginated::new(conversations, &query, total)))
+}
+
+pub async fn get_conversation(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let conversation = load_conversation(&db, path.into_inner(), user_id).await?;
+    Ok(HttpResponse::Ok().json(conversation))
+}
+
+pub async fn rename_conversation(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    path: web::Path<Uuid>,
+    body: web::Json<RenameConversationRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let title = validate_title(&body.title)?;
+
+    let conversation = Conversation::rename(&db, path.into_inner(), user_id, &title)
+        .await?
+        .ok_or_else(|| AppError::NotFound("对话不存在".to_string()))?;
+    Ok(HttpResponse::Ok().json(conversation))
+}
+
+pub async fn delete_conversation(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    storage: web::Data<dyn Storage>,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let keys = Conversation::delete(&db, path.into_inner(), user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("对话不存在".to_string()))?;
+
+    for key in keys {
+        if let Err(e) = storage.delete(&key).await {
+            log::warn!("删除对话附件失败 {}: {:?}", key, e);
+        }
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn list_messages(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    path: web::Path<Uuid>,
+    query: web::Query<PaginationQuery>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let conversation = load_conversation(&db, path.into_inner(), user_id).await?;
+
+    let (messages, total) = Message::list(&db, conversation.id, &query).await?;
+    Ok(HttpResponse::Ok().json(Paginated::new(messages, &query, total)))
+}
+
+// 上传图片附件，返回的 ID 可在之后的消息中反复引用
+pub async fn upload_attachment(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
+    storage: web::Data<dyn Storage>,
+    path: web::Path<Uuid>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let conversation = load_conversation(&db, path.into_inner(), user_id).await?;
+
+    let max_bytes = config.ai_conversations.attachment_max_bytes;
+    let mut image = None;
+
+    while let Some(mut field) = payload.try_next().await? {
+        let content_type = field.content_disposition();
+        let name = content_type.get_name().ok_or_else(||
+            AppError::ValidationError("Invalid form field".to_string()))?;
+
+        if name == "image" {
+            let mut bytes = web::BytesMut::new();
+            while let Some(chunk) = field.try_next().await? {
+                if bytes.len() + chunk.len() > max_bytes {
+                    return Err(AppError::ValidationError(format!("附件不能超过 {} 字节", max_bytes)));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            image = Some(bytes.freeze().to_vec());
+        }
+    }
+
+    let data = image.ok_or_else(|| AppError::ValidationError("Image is required".to_string()))?;
+    let kind = sniff_image_kind(&data)
+        .ok_or_else(|| AppError::ValidationError("不支持的图片格式，仅支持 JPEG/PNG/GIF/WebP".to_string()))?;
+
+    let key = format!("conversations/{}/{}.{}", conversation.id, Uuid::new_v4(), kind.extension());
+    let size_bytes = data.len() as i32;
+    storage.put(&key, data, kind.mime_type()).await?;
+
+    let attachment = Attachment::create(&db, conversation.id, &key, kind.mime_type(), size_bytes).await?;
+    Ok(HttpResponse::Created().json(attachment))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn send_message(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
+    storage: web::Data<dyn Storage>,
+    quota: web::Data<QuotaService>,
+    ai_service: web::Data<AIServiceImpl>,
+    path: web::Path<Uuid>,
+    body: web::Json<SendMessageRequest>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    quota.check(user_id).await?;
+    let conversation = load_conversation(&db, path.into_inner(), user_id).await?;
+    let body = body.into_inner();
+
+    let content = body.content.trim();
+    if content.is_empty() {
+        return Err(AppError::ValidationError("消息内容不能为空".to_string()));
+    }
+    if content.chars().count() > MESSAGE_MAX_CHARS {
+        return Err(AppError::ValidationError(format!("消息不能超过 {} 个字符", MESSAGE_MAX_CHARS)));
+    }
+    let max_images = config.ai_images.max_per_message;
+    if body.attachment_ids.len() > max_images {
+        return Err(AppError::ValidationError(format!("每条消息最多引用 {} 张图片", max_images)));
+    }
+    // 与创建对话时相同的校验，避免调用提供商之后才在保存时失败
+    let provider = validate_provider(&ai_service, body.provider.as_deref())?
+        .or_else(|| conversation.provider.clone());
+    let model = validate_setting(body.model.as_deref(), MODEL_MAX_CHARS, "模型名")?
+        .or_else(|| conversation.model.clone());
+
+    // 历史消息中引用的图片从存储中读取，客户端无需重复上传；本轮用到的附件一次查出
+    let previous = Message::recent(&db, conversation.id, config.ai_conversations.history_limit).await?;
+    let attachment_ids: HashSet<Uuid> = previous
+        .iter()
+        .flat_map(|message| &message.attachment_ids)
+        .chain(&body.attachment_ids)
+        .copied()
+        .collect();
+    let attachment_ids: Vec<Uuid> = attachment_ids.into_iter().collect();
+    let attachments = Attachment::find_many(&db, conversation.id, &attachment_ids).await?;
+    if body.attachment_ids.iter().any(|id| !attachments.iter().any(|attachment| attachment.id == *id)) {
+        return Err(AppError::ValidationError("附件不存在或不属于该对话".to_string()));
+    }
+    let images = load_images(storage.as_ref(), &attachments).await?;
+
+    let mut messages = Vec::with_capacity(previous.len() + 2);
+    if let Some(system) = conversation.system_prompt.clone() {
+        messages.push(ChatMessage::text(ChatRole::System, system));
+    }
+    for message in &previous {
+        messages.push(chat_message(message.chat_role(), &message.attachment_ids, &images, message.content.clone()));
+    }
+    messages.push(chat_message(ChatRole::User, &body.attachment_ids, &images, content.to_string()));
+
+    let request = AIRequest {
+        messages,
+        provider,
+        model: model.clone(),
+        params: GenerationParams::default(),
+        tools: Vec::new(),
+        response_schema: None,
+    };
+
+    let recorder = CallRecorder::new(&db, &quota, user_id, "conversation", &request, false);
+    let result = ai_service.analyze(request).await;
+    recorder.record(&result).await;
+    let response = result?;
+
+    let (user_message, assistant_message) = Message::insert_exchange(&db, conversation.id, NewExchange {
+        user_content: content,
+        attachment_ids: &body.attachment_ids,
+        assistant_content: &response.content,
+        // 记录实际处理请求的提供商和模型，可能因备用链而与请求不同
+        provider: Some(&response.provider),
+        model: response.model.as_deref().or(model.as_deref()),
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(SendMessageResponse { user_message, assistant_message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_conversation_settings() {
+        assert_eq!(validate_setting(Some(" openai "), PROVIDER_MAX_CHARS, "提供商").unwrap().as_deref(), Some("openai"));
+        assert_eq!(validate_setting(Some("  "), PROVIDER_MAX_CHARS, "提供商").unwrap(), None);
+        assert_eq!(validate_setting(None, PROVIDER_MAX_CHARS, "提供商").unwrap(), None);
+        // 按字符计数，与 VARCHAR 一致
+        assert!(validate_setting(Some(&"模".repeat(100)), MODEL_MAX_CHARS, "模型名").is_ok());
+        assert!(validate_setting(Some(&"m".repeat(101)), MODEL_MAX_CHARS, "模型名").is_err());
+        assert!(validate_setting(Some(&"p".repeat(51)), PROVIDER_MAX_CHARS, "提供商").is_err());
+    }
+
+    #[test]
+    fn validates_titles() {
+        assert_eq!(validate_title("  旅行计划 ").unwrap(), "旅行计划");
+        assert!(validate_title(" ").is_err());
+        assert!(validate_title(&"t".repeat(TITLE_MAX_CHARS + 1)).is_err());
+    }
+
+    #[test]
+    fn builds_messages_with_images_in_reference_order() {
+        let (first, second, missing) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let images = HashMap::from([(first, vec![1]), (second, vec![2])]);
+
+        let message = chat_message(ChatRole::User, &[second, missing, first], &images, "compare".to_string());
+
+        assert!(matches!(
+            message.content.as_slice(),
+            [ContentPart::Image { data: a }, ContentPart::Image { data: b }, ContentPart::Text { text }]
+                if *a == [2] && *b == [1] && text == "compare"
+        ));
+    }
+}
@@ -1,69 +1,295 @@
-use actix_web::{web, HttpResponse};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
 use actix_multipart::Multipart;
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::auth::auth_handlers::get_claims_from_request;
 use crate::errors::AppError;
-use crate::models::ai::{AIRequest, AIInput};
+use crate::config::Config;
+use crate::models::ai::{
+    AIInput, AIRequest, ChatMessage, ChatRole, ContentPart, EmbeddingRequest, GenerationParams, DEFAULT_IMAGE_PROMPT,
+};
 use crate::ai::service::AIServiceImpl;
+use super::cache::{CacheMode, EmbeddingCache, ResponseCache};
+use super::history::CallRecorder;
+use super::image_fetch::parse_image_url;
+use super::providers::AIStream;
+use super::quota::QuotaService;
 use super::service::AIService;
+use super::tools::{ToolContext, ToolRegistry};
 
+#[derive(Debug, Deserialize)]
+pub struct AIQuery {
+    #[serde(default)]
+    pub stream: bool,
+    // 流式请求不使用缓存
+    #[serde(default)]
+    pub cache: CacheMode,
+}
+
+// 以 SSE 返回增量结果；客户端断开时响应体被丢弃，上游连接随之关闭
+fn sse_response(events: AIStream) -> HttpResponse {
+    let body = events.map(|event| {
+        let frame = match event {
+            Ok(event) => format!(
+                "event: {}\ndata: {}\n\n",
+                event.name(),
+                serde_json::to_string(&event).unwrap_or_default()
+            ),
+            Err(e) => {
+                log::error!("AI流式响应出错: {:?}", e);
+                format!("event: error\ndata: {}\n\n", serde_json::json!({ "error": e.to_string() }))
+            }
+        };
+        Ok::<_, AppError>(web::Bytes::from(frame))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body)
+}
+
+pub fn current_user_id(req: &HttpRequest) -> Result<Uuid, AppError> {
+    let claims = get_claims_from_request(req)?;
+    Uuid::parse_str(&claims.sub).map_err(|_| AppError::InvalidId("无效的用户ID".into()))
+}
+
+// 调用 AI 服务并记录到调用历史
+async fn respond(
+    ai_service: &AIServiceImpl,
+    cache: &ResponseCache,
+    recorder: CallRecorder,
+    request: AIRequest,
+    query: AIQuery,
+) -> Result<HttpResponse, AppError> {
+    if query.stream {
+        if !request.tools.is_empty() {
+            return Err(AppError::ValidationError("流式请求暂不支持工具调用".to_string()));
+        }
+        if request.response_schema.is_some() {
+            return Err(AppError::ValidationError("流式请求暂不支持 response_schema".to_string()));
+        }
+        let events = match ai_service.analyze_stream(request).await {
+            Ok(events) => events,
+            Err(e) => {
+                recorder.record_error(&e).await;
+                return Err(e);
+            }
+        };
+        return Ok(sse_response(recorder.record_stream(events)));
+    }
+
+    let result = cache.analyze(ai_service, request, query.cache).await;
+    recorder.record(&result).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+// 表单文本字段的大小上限
+const PROMPT_MAX_BYTES: usize = 32 * 1024;
+const IMAGE_URL_MAX_BYTES: usize = 2048;
+const NAME_MAX_BYTES: usize = 128;
+
+// 读取表单字段，超过 max_bytes 时立即拒绝，不再继续读取
+async fn read_field(field: &mut actix_multipart::Field, name: &str, max_bytes: usize) -> Result<Vec<u8>, AppError> {
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = field.try_next().await? {
+        if bytes.len() + chunk.len() > max_bytes {
+            return Err(AppError::ValidationError(format!("字段 {} 不能超过 {} 字节", name, max_bytes)));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.to_vec())
+}
+
+// 去掉首尾空白，空字符串视为未提供；同名字段重复出现时拒绝
+async fn read_text(
+    field: &mut actix_multipart::Field,
+    name: &str,
+    max_bytes: usize,
+    target: &mut Option<String>,
+) -> Result<(), AppError> {
+    if target.is_some() {
+        return Err(AppError::ValidationError(format!("字段 {} 重复", name)));
+    }
+    let text = String::from_utf8(read_field(field, name, max_bytes).await?)
+        .map_err(|_| AppError::ValidationError(format!("字段 {} 不是有效的 UTF-8 文本", name)))?;
+    let text = text.trim();
+    if !text.is_empty() {
+        *target = Some(text.to_string());
+    }
+    Ok(())
+}
+
+// 单张上传的图片使用 AIInput，有提示词时为 ImageWithText；多张图片或图片 URL 按顺序排列，文本放在最后
+fn image_message(mut images: Vec<ContentPart>, prompt: Option<String>) -> ChatMessage {
+    if let [ContentPart::Image { data }] = images.as_mut_slice() {
+        let image = std::mem::take(data);
+        let input = match prompt {
+            Some(text) => AIInput::ImageWithText { image, text },
+            None => AIInput::Image(image),
+        };
+        return input.into_message(None);
+    }
+    images.push(ContentPart::Text { text: prompt.unwrap_or_else(|| DEFAULT_IMAGE_PROMPT.to_string()) });
+    ChatMessage { role: ChatRole::User, content: images, tool_calls: Vec::new(), tool_call_id: None }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn analyze_image(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
+    quota: web::Data<QuotaService>,
+    cache: web::Data<ResponseCache>,
     mut payload: Multipart,
+    query: web::Query<AIQuery>,
     ai_service: web::Data<AIServiceImpl>,
 ) -> Result<HttpResponse, AppError> {
-    let mut image = None;
+    let user_id = current_user_id(&req)?;
+    quota.check(user_id).await?;
+
+    let limits = &config.ai_images;
+    let mut images = Vec::new();
     let mut prompt = None;
     let mut model = None;
+    let mut provider = None;
 
     while let Some(mut field) = payload.try_next().await? {
         let content_type = field.content_disposition();
         let name = content_type.get_name().ok_or_else(|| 
-            AppError::ValidationError("Invalid form field".to_string()))?;
+            AppError::ValidationError("Invalid form field".to_string()))?.to_string();
 
-        match name {
+        if matches!(name.as_str(), "image" | "image_url") && images.len() >= limits.max_per_message {
+            return Err(AppError::ValidationError(format!(
+                "最多上传 {} 张图片", limits.max_per_message
+            )));
+        }
+        match name.as_str() {
             "image" => {
-                let mut bytes = web::BytesMut::new();
-                while let Some(chunk) = field.try_next().await? {
-                    bytes.extend_from_slice(&chunk);
-                }
-                image = Some(bytes.freeze().to_vec());
-            },
-            "prompt" => {
-                let mut text = String::new();
-                while let Some(chunk) = field.try_next().await? {
-                    text.push_str(std::str::from_utf8(&chunk)?);
-                }
-                prompt = Some(text);
+                let data = read_field(&mut field, &name, limits.max_upload_bytes).await?;
+                images.push(ContentPart::Image { data });
             },
-            "model" => {
-                let mut text = String::new();
-                while let Some(chunk) = field.try_next().await? {
-                    text.push_str(std::str::from_utf8(&chunk)?);
-                }
-                model = Some(text);
+            "image_url" => {
+                let mut url = None;
+                read_text(&mut field, &name, IMAGE_URL_MAX_BYTES, &mut url).await?;
+                let url = url.ok_or_else(|| AppError::ValidationError("image_url 不能为空".to_string()))?;
+                images.push(ContentPart::ImageUrl { url: parse_image_url(&url)?.to_string() });
             },
-            _ => {}
+            "prompt" => read_text(&mut field, &name, PROMPT_MAX_BYTES, &mut prompt).await?,
+            "model" => read_text(&mut field, &name, NAME_MAX_BYTES, &mut model).await?,
+            "provider" => read_text(&mut field, &name, NAME_MAX_BYTES, &mut provider).await?,
+            other => return Err(AppError::ValidationError(format!("未知的表单字段: {}", other))),
         }
     }
 
-    let image_data = image.ok_or_else(|| 
-        AppError::ValidationError("Image is required".to_string()))?;
-    
-    log::debug!("Received image data length: {}", image_data.len());
-    
+    if images.is_empty() {
+        return Err(AppError::ValidationError("Image is required".to_string()));
+    }
+    log::debug!("Received {} images", images.len());
+
     let request = AIRequest {
-        input: AIInput::Image(image_data),
-        prompt,
+        messages: vec![image_message(images, prompt)],
+        provider,
         model,
+        params: GenerationParams::default(),
+        tools: Vec::new(),
+        response_schema: None,
     };
 
-    let response = ai_service.analyze(request).await?;
-    Ok(HttpResponse::Ok().json(response))
+    let recorder = CallRecorder::new(&db, &quota, user_id, "image", &request, query.stream);
+    respond(&ai_service, &cache, recorder, request, query.into_inner()).await
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TextRequest {
+    #[serde(flatten)]
+    pub request: AIRequest,
+    // 按名称启用的服务端工具，由服务端执行
+    #[serde(default)]
+    pub server_tools: Vec<String>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn analyze_text(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    quota: web::Data<QuotaService>,
+    cache: web::Data<ResponseCache>,
+    tools: web::Data<ToolRegistry>,
+    body: web::Json<TextRequest>,
+    query: web::Query<AIQuery>,
+    ai_service: web::Data<AIServiceImpl>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    quota.check(user_id).await?;
+    let TextRequest { mut request, server_tools } = body.into_inner();
+    tools.enable(&mut request, &server_tools)?;
+    let recorder = CallRecorder::new(&db, &quota, user_id, "text", &request, query.stream);
+    if server_tools.is_empty() || query.stream {
+        return respond(&ai_service, &cache, recorder, request, query.into_inner()).await;
+    }
+
+    // 服务端工具的结果随时间变化，不使用缓存
+    let result = tools.run(ai_service.get_ref(), ToolContext { user_id }, request).await;
+    recorder.record(&result).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+pub async fn list_tools(req: HttpRequest, tools: web::Data<ToolRegistry>) -> Result<HttpResponse, AppError> {
+    current_user_id(&req)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "tools": tools.definitions() })))
+}
+
+pub async fn analyze_text_stream(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    quota: web::Data<QuotaService>,
+    cache: web::Data<ResponseCache>,
     request: web::Json<AIRequest>,
     ai_service: web::Data<AIServiceImpl>,
 ) -> Result<HttpResponse, AppError> {
-    let response = ai_service.analyze(request.into_inner()).await?;
-    Ok(HttpResponse::Ok().json(response))
-}
\ No newline at end of file
+    let user_id = current_user_id(&req)?;
+    quota.check(user_id).await?;
+    let request = request.into_inner();
+    let recorder = CallRecorder::new(&db, &quota, user_id, "text", &request, true);
+    let query = AIQuery { stream: true, cache: CacheMode::Off };
+    respond(&ai_service, &cache, recorder, request, query).await
+}
+
+pub async fn create_embeddings(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
+    quota: web::Data<QuotaService>,
+    cache: web::Data<EmbeddingCache>,
+    request: web::Json<EmbeddingRequest>,
+    ai_service: web::Data<AIServiceImpl>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    quota.check(user_id).await?;
+    let request = request.into_inner();
+    request.validate(config.ai_embeddings.max_inputs)?;
+
+    let recorder = CallRecorder::for_embeddings(&db, &quota, user_id, &request);
+    let result = cache.embed(&ai_service, request).await;
+    recorder.record_embeddings(&result).await;
+    Ok(HttpResponse::Ok().json(result?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn listing_tools_requires_authentication() {
+        let tools = web::Data::new(ToolRegistry::new(1));
+
+        let result = list_tools(TestRequest::get().uri("/ai/tools").to_http_request(), tools).await;
+
+        assert!(matches!(result, Err(AppError::AuthenticationError(_))));
+    }
+}
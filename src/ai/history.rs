@@ -0,0 +1,284 @@
+use std::time::Instant;
+
+use futures::StreamExt;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::ai::{AIRequest, AIResponse, EmbeddingRequest, EmbeddingResponse, StreamEvent, TokenUsage};
+use crate::models::ai_request::{AIRequestRecord, AIRequestStatus, NewAIRequestRecord};
+use super::providers::AIStream;
+use super::quota::QuotaService;
+
+struct Outcome<'a> {
+    status: AIRequestStatus,
+    provider: Option<&'a str>,
+    model: Option<&'a str>,
+    response: Option<&'a str>,
+    finish_reason: Option<&'a str>,
+    usage: Option<&'a TokenUsage>,
+    cached: bool,
+    error: Option<String>,
+}
+
+// 记录一次 AI 调用并计入用户的 token 用量；写入失败不影响请求本身，只记录错误日志
+pub struct CallRecorder {
+    pool: crate::db::DbPool,
+    quota: QuotaService,
+    user_id: Uuid,
+    endpoint: &'static str,
+    stream: bool,
+    provider: Option<String>,
+    model: Option<String>,
+    input_summary: String,
+    // 流式调用没有拿到提供商用量时，按输入文本估算计入额度
+    input_tokens_estimate: u64,
+    started: Instant,
+}
+
+// 粗略估算文本的 token 数：ASCII 字符约 4 个一个 token，其他字符（如中文）每字按一个计
+fn estimate_tokens(text: &str) -> u64 {
+    let ascii = text.chars().filter(char::is_ascii).count() as u64;
+    let other = text.chars().count() as u64 - ascii;
+    ascii.div_ceil(4) + other
+}
+
+impl CallRecorder {
+    // 在调用提供商之前创建，耗时从此刻开始计算
+    pub fn new(
+        pool: &crate::db::DbPool,
+        quota: &QuotaService,
+        user_id: Uuid,
+        endpoint: &'static str,
+        request: &AIRequest,
+        stream: bool,
+    ) -> Self {
+        Self {
+            pool: pool.clone(),
+            quota: quota.clone(),
+            user_id,
+            endpoint,
+            stream,
+            provider: request.provider.clone(),
+            model: request.model.clone(),
+            input_summary: request.summary(),
+            input_tokens_estimate: request.messages.iter().map(|message| estimate_tokens(&message.text_content())).sum(),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn for_embeddings(
+        pool: &crate::db::DbPool,
+        quota: &QuotaService,
+        user_id: Uuid,
+        request: &EmbeddingRequest,
+    ) -> Self {
+        Self {
+            pool: pool.clone(),
+            quota: quota.clone(),
+            user_id,
+            endpoint: "embeddings",
+            stream: false,
+            provider: request.provider.clone(),
+            model: request.model.clone(),
+            input_summary: request.summary(),
+            input_tokens_estimate: request.input.iter().map(|input| estimate_tokens(input)).sum(),
+            started: Instant::now(),
+        }
+    }
+
+    // 流式调用中断或提供商没有返回用量时的估算用量；没有收到任何内容时不计
+    fn estimate_usage(&self, content: &str) -> Option<TokenUsage> {
+        if content.is_empty() {
+            return None;
+        }
+        Some(TokenUsage {
+            input_tokens: Some(self.input_tokens_estimate),
+            output_tokens: Some(estimate_tokens(content)),
+            ..TokenUsage::default()
+        })
+    }
+
+    async fn save(&self, outcome: Outcome<'_>) {
+        let provider = outcome.provider.or(self.provider.as_deref());
+        let model = outcome.model.or(self.model.as_deref());
+        if outcome.cached {
+            self.quota.record_cache_hit(self.user_id, provider, model).await;
+        } else if let Some(usage) = outcome.usage {
+            self.quota.record(self.user_id, provider, model, usage).await;
+        }
+
+        let record = NewAIRequestRecord {
+            user_id: self.user_id,
+            endpoint: self.endpoint,
+            stream: self.stream,
+            provider,
+            model,
+            input_summary: &self.input_summary,
+            response: outcome.response,
+            finish_reason: outcome.finish_reason,
+            usage: outcome.usage,
+            latency_ms: self.started.elapsed().as_millis() as i64,
+            cached: outcome.cached,
+            status: outcome.status,
+            error: outcome.error.as_deref(),
+        };
+
+        if let Err(e) = AIRequestRecord::create(&self.pool, record).await {
+            log::error!("AI 调用记录写入失败: {:?}", e);
+        }
+    }
+
+    pub async fn record(&self, result: &Result<AIResponse, AppError>) {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => return self.record_error(e).await,
+        };
+        self.save(Outcome {
+            status: AIRequestStatus::Success,
+            provider: Some(&response.provider),
+            model: response.model.as_deref(),
+            response: Some(&response.content),
+            finish_reason: response.finish_reason.as_deref(),
+            usage: response.usage.as_ref(),
+            cached: response.cached,
+            error: None,
+        }).await;
+    }
+
+    // 只有全部输入都命中缓存时才记为缓存命中，否则按实际调用的用量计费
+    pub async fn record_embeddings(&self, result: &Result<EmbeddingResponse, AppError>) {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => return self.record_error(e).await,
+        };
+        self.save(Outcome {
+            status: AIRequestStatus::Success,
+            provider: Some(&response.provider),
+            model: Some(&response.model),
+            response: None,
+            finish_reason: None,
+            usage: response.usage.as_ref(),
+            cached: response.cached == response.embeddings.len(),
+            error: None,
+        }).await;
+    }
+
+    // 结构化输出修复、工具循环等多轮调用失败时，错误带有已产生的用量，照常计入额度
+    pub async fn record_error(&self, error: &AppError) {
+        self.save(Outcome {
+            status: AIRequestStatus::Error,
+            provider: None,
+            model: None,
+            response: None,
+            finish_reason: None,
+            usage: error.usage(),
+            cached: false,
+            error: Some(error.to_string()),
+        }).await;
+    }
+
+    // 流式调用在收到结束事件或出错时写入；流提前被丢弃（如客户端断开）时记为 incomplete。
+    // 提供商已经开始输出但没有返回用量时按已收到的内容估算计费，避免断开连接绕过额度
+    pub fn record_stream(self, events: AIStream) -> AIStream {
+        let recording = StreamRecording {
+            recorder: Some(self),
+            content: String::new(),
+        };
+
+        Box::pin(events.scan(recording, |recording, event| {
+            match &event {
+                Ok(StreamEvent::Delta { content }) => recording.content.push_str(content),
+                Ok(StreamEvent::Done { finish_reason, usage, provider, model }) => recording.finish(
+                    AIRequestStatus::Success,
+                    provider.clone(),
+                    model.clone(),
+                    finish_reason.clone(),
+                    usage.clone(),
+                    None,
+                ),
+                Err(e) => recording.finish(AIRequestStatus::Error, None, None, None, None, Some(e.to_string())),
+            }
+            futures::future::ready(Some(event))
+        }))
+    }
+}
+
+struct StreamRecording {
+    recorder: Option<CallRecorder>,
+    content: String,
+}
+
+impl StreamRecording {
+    fn finish(
+        &mut self,
+        status: AIRequestStatus,
+        provider: Option<String>,
+        model: Option<String>,
+        finish_reason: Option<String>,
+        usage: Option<TokenUsage>,
+        error: Option<String>,
+    ) {
+        let Some(recorder) = self.recorder.take() else {
+            return;
+        };
+        let content = std::mem::take(&mut self.content);
+        let usage = usage.or_else(|| recorder.estimate_usage(&content));
+        tokio::spawn(async move {
+            recorder.save(Outcome {
+                status,
+                provider: provider.as_deref(),
+                model: model.as_deref(),
+                response: Some(&content),
+                finish_reason: finish_reason.as_deref(),
+                usage: usage.as_ref(),
+                cached: false,
+                error,
+            }).await;
+        });
+    }
+}
+
+impl Drop for StreamRecording {
+    fn drop(&mut self) {
+        if self.recorder.is_some() {
+            self.finish(AIRequestStatus::Incomplete, None, None, None, None, Some("流式响应未正常结束".to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ai::{ChatMessage, ChatRole, GenerationParams};
+    use crate::service::redis_service::RedisService;
+
+    #[test]
+    fn estimates_ascii_and_cjk_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        assert_eq!(estimate_tokens("你好，世界"), 5);
+        assert_eq!(estimate_tokens("hi 你好"), 3);
+    }
+
+    #[tokio::test]
+    async fn estimates_usage_only_after_output_started() {
+        let pool = sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+        let quota = QuotaService::new(pool.clone(), RedisService::new("redis://localhost").unwrap(), "free");
+        let request = AIRequest {
+            messages: vec![
+                ChatMessage::text(ChatRole::System, "be brief"),
+                ChatMessage::text(ChatRole::User, "你好"),
+            ],
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        };
+        let recorder = CallRecorder::new(&pool, &quota, Uuid::new_v4(), "text", &request, true);
+
+        assert_eq!(recorder.estimate_usage(""), None);
+        let usage = recorder.estimate_usage("Hello, there").unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(4), Some(3), None));
+    }
+}
@@ -0,0 +1,38 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::json;
+
+use crate::errors::AppError;
+use crate::models::ai_request::AIRequestRecord;
+use crate::models::pagination::{Paginated, PaginationQuery};
+use super::handlers::current_user_id;
+
+pub async fn list_history(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    query: web::Query<PaginationQuery>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let (records, total) = AIRequestRecord::list_for_user(&db, user_id, &query).await?;
+    Ok(HttpResponse::Ok().json(Paginated::new(records, &query, total)))
+}
+
+pub async fn delete_history_entry(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    if !AIRequestRecord::delete_for_user(&db, path.into_inner(), user_id).await? {
+        return Err(AppError::NotFound("调用记录不存在".to_string()));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn clear_history(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let deleted = AIRequestRecord::delete_all_for_user(&db, user_id).await?;
+    Ok(HttpResponse::Ok().json(json!({ "deleted": deleted })))
+}
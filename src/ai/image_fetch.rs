@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::StreamExt;
+use reqwest::header::LOCATION;
+use reqwest::redirect::Policy;
+use reqwest::{Client, Url};
+
+use crate::config::AIImageConfig;
+use crate::errors::AppError;
+use crate::models::ai::{AIRequest, ContentPart};
+
+// 每一跳都重新解析并校验目标地址
+const MAX_REDIRECTS: usize = 3;
+
+// 解析图片 URL，只接受 http 和 https
+pub fn parse_image_url(url: &str) -> Result<Url, AppError> {
+    let url = Url::parse(url.trim()).map_err(|_| AppError::ValidationError(format!("无效的图片 URL: {}", url)))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err(AppError::ValidationError(format!("图片 URL 只支持 http 和 https: {}", url)));
+    }
+    Ok(url)
+}
+
+// 私有、回环、链路本地、运营商 NAT、保留和组播地址都不允许访问
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(ipv4);
+    }
+    let segments = ip.segments();
+    // 2002::/16 6to4 和 ::a.b.c.d IPv4 兼容地址内嵌的 IPv4 同样可能指向内网
+    let embedded = if segments[0] == 0x2002 {
+        Some((segments[1], segments[2]))
+    } else if segments[..6] == [0; 6] && !ip.is_loopback() && !ip.is_unspecified() {
+        Some((segments[6], segments[7]))
+    } else {
+        None
+    };
+    if let Some((high, low)) = embedded {
+        return is_public_ipv4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)));
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 唯一本地地址、fe80::/10 链路本地地址
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        // 2001:db8::/32 文档地址、64:ff9b::/96 NAT64 可能指向内网
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => is_public_ipv6(ip),
+    }
+}
+
+// 解析主机名，任一地址不是公网地址都拒绝，防止通过 DNS 指向内网
+async fn resolve_public(url: &Url) -> Result<Vec<SocketAddr>, AppError> {
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| AppError::ValidationError(format!("无法解析图片地址: {}", host)))?
+        .collect();
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        return Err(AppError::ValidationError(format!("不允许访问该图片地址: {}", host)));
+    }
+    Ok(addrs)
+}
+
+// 由服务端下载图片 URL，供不能自行下载的提供商使用；默认关闭
+#[derive(Clone)]
+pub struct ImageFetcher {
+    config: AIImageConfig,
+}
+
+impl ImageFetcher {
+    pub fn new(config: &AIImageConfig) -> Self {
+        Self { config: config.clone() }
+    }
+
+    // 整个下载过程（包括重定向）受超时限制
+    pub async fn fetch(&self, url: &str) -> Result<Vec<u8>, AppError> {
+        let timeout = Duration::from_secs(self.config.fetch_timeout_secs);
+        tokio::time::timeout(timeout, self.download(parse_image_url(url)?))
+            .await
+            .map_err(|_| AppError::ValidationError(format!("下载图片超时: {}", url)))?
+    }
+
+    async fn download(&self, mut url: Url) -> Result<Vec<u8>, AppError> {
+        for _ in 0..=MAX_REDIRECTS {
+            // 连接固定到已校验的地址，避免 DNS 在校验后被换成内网地址；不走系统代理
+            let addrs = resolve_public(&url).await?;
+            let client = Client::builder()
+                .redirect(Policy::none())
+                .no_proxy()
+                .resolve_to_addrs(url.host_str().unwrap_or_default(), &addrs)
+                .build()
+                .map_err(|e| AppError::InternalError(format!("创建 HTTP 客户端失败: {}", e)))?;
+
+            let response = client
+                .get(url.clone())
+                .send()
+                .await
+                .map_err(|e| AppError::ValidationError(format!("下载图片失败: {}", e.without_url())))?;
+
+            let status = response.status();
+            if status.is_redirection() {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|value| value.to_str().ok())
+                    .ok_or_else(|| AppError::ValidationError("图片 URL 重定向缺少 Location".to_string()))?;
+                let next = url
+                    .join(location)
+                    .map_err(|_| AppError::ValidationError(format!("无效的重定向地址: {}", location)))?;
+                url = parse_image_url(next.as_str())?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(AppError::ValidationError(format!("下载图片失败: HTTP {}", status.as_u16())));
+            }
+            return self.read_body(response).await;
+        }
+
+        Err(AppError::ValidationError("图片 URL 重定向次数过多".to_string()))
+    }
+
+    async fn read_body(&self, response: reqwest::Response) -> Result<Vec<u8>, AppError> {
+        let max_bytes = self.config.fetch_max_bytes;
+        let too_large = || AppError::ValidationError(format!("下载的图片不能超过 {} 字节", max_bytes));
+        if response.content_length().is_some_and(|length| length > max_bytes as u64) {
+            return Err(too_large());
+        }
+
+        let mut data = Vec::new();
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| AppError::ValidationError(format!("下载图片失败: {}", e.without_url())))?;
+            if data.len() + chunk.len() > max_bytes {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    // 把请求中的图片 URL 替换为下载的图片；fetched 在同一请求的多个提供商之间复用
+    pub async fn inline_urls(
+        &self,
+        provider: &str,
+        request: &mut AIRequest,
+        fetched: &mut HashMap<String, Vec<u8>>,
+    ) -> Result<(), AppError> {
+        for part in request.messages.iter_mut().flat_map(|message| message.content.iter_mut()) {
+            let ContentPart::ImageUrl { url } = part else {
+                continue;
+            };
+            if !self.config.fetch_enabled {
+                return Err(AppError::AIInvalidRequest(format!(
+                    "AI 提供商 {} 不支持图片 URL，请直接上传图片或开启 AI_IMAGE_FETCH_ENABLED", provider
+                )));
+            }
+            if !fetched.contains_key(url.as_str()) {
+                let data = self.fetch(url).await?;
+                fetched.insert(url.clone(), data);
+            }
+            *part = ContentPart::Image { data: fetched[url.as_str()].clone() };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetcher() -> ImageFetcher {
+        ImageFetcher::new(&AIImageConfig {
+            max_per_message: 8,
+            max_upload_bytes: 1024,
+            fetch_enabled: true,
+            fetch_max_bytes: 1024,
+            fetch_timeout_secs: 5,
+        })
+    }
+
+    #[test]
+    fn rejects_non_public_addresses() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1",
+            "0.0.0.0", "224.0.0.1", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1",
+            "2002:7f00:1::", "2002:a00:1::1", "2002:a9fe:a9fe::", "::127.0.0.1", "::10.0.0.1", "::192.168.1.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} should be rejected", ip);
+        }
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111", "2002:808:808::1", "::8.8.8.8"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_schemes() {
+        for url in ["file:///etc/passwd", "ftp://example.com/a.png", "not a url"] {
+            assert!(matches!(parse_image_url(url), Err(AppError::ValidationError(_))), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_to_fetch_from_loopback() {
+        let server = wiremock::MockServer::start().await;
+
+        let err = fetcher().fetch(&format!("{}/cat.png", server.uri())).await.unwrap_err();
+
+        assert!(matches!(err, AppError::ValidationError(_)));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+}
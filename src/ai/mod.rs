@@ -1,4 +1,16 @@
 pub mod routes;
 pub mod handlers;
 pub mod service;
-pub mod providers;
\ No newline at end of file
+pub mod providers;
+pub mod registry;
+pub mod resilience;
+pub mod admin_handlers;
+pub mod conversation_handlers;
+pub mod history;
+pub mod history_handlers;
+pub mod quota;
+pub mod usage_handlers;
+pub mod cache;
+pub mod image_fetch;
+pub mod tools;
+pub mod structured;
@@ -0,0 +1,643 @@
+use super::{
+    ensure_params_supported, error_from_request, error_from_status, header_string, parse_stream_json,
+    retry_after_seconds, sse_messages, usage_field, AIStream, Provider, VisionModels,
+};
+use crate::errors::AppError;
+use crate::models::ai::{
+    AIRequest, AIResponse, ChatMessage, ChatRole, ContentPart, StreamEvent, TokenUsage, ToolCall,
+};
+use crate::service::image_processing::{ImageKind, ImageLimits};
+use reqwest::Client;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::HashMap;
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+const DEFAULT_MODEL: &str = "claude-sonnet-4-5";
+// Claude 3 及之后的模型都支持图片输入
+const VISION_MODELS: &[&str] = &["claude-*"];
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+const API_VERSION: &str = "2023-06-01";
+const SUPPORTED_PARAMS: &[&str] = &["temperature", "top_p", "max_tokens", "stop"];
+
+// 使用提示词缓存时，缓存写入和读取的 token 不包含在 input_tokens 中
+fn parse_usage(usage: &serde_json::Value) -> TokenUsage {
+    let input_tokens = ["/input_tokens", "/cache_creation_input_tokens", "/cache_read_input_tokens"]
+        .iter()
+        .filter_map(|pointer| usage_field(usage, pointer))
+        .reduce(|total, tokens| total + tokens);
+    TokenUsage {
+        input_tokens,
+        output_tokens: usage_field(usage, "/output_tokens"),
+        image_tokens: None,
+        total_tokens: None,
+    }
+    .with_total()
+}
+
+// Messages API 支持的图片格式
+// 单图最大 5MB；长边超过 1568 像素或约 115 万像素的图片会被服务端缩小，提前缩小可减少上传体积
+const IMAGE_LIMITS: ImageLimits = ImageLimits {
+    formats: &[ImageKind::Jpeg, ImageKind::Png, ImageKind::Gif, ImageKind::WebP],
+    max_bytes: 5 * 1024 * 1024,
+    max_dimension: 1568,
+    max_pixels: 1_150_000,
+};
+
+// Anthropic Messages API (Claude 系列模型)
+pub struct AnthropicProvider {
+    client: Client,
+    api_key: String,
+    base_url: String,
+    default_model: String,
+    max_tokens: u32,
+    system_prompt: Option<String>,
+    vision_models: VisionModels,
+}
+
+impl AnthropicProvider {
+    pub fn from_provider_config(client: Client, provider_config: &HashMap<String, String>) -> Result<Self, AppError> {
+        let api_key = provider_config.get("API_KEY")
+            .ok_or_else(|| AppError::ConfigError("Anthropic API_KEY not configured".to_string()))?
+            .clone();
+
+        let max_tokens = match provider_config.get("MAX_TOKENS") {
+            Some(value) => value.parse()
+                .map_err(|_| AppError::ConfigError("Invalid Anthropic MAX_TOKENS".to_string()))?,
+            None => DEFAULT_MAX_TOKENS,
+        };
+
+        let default_model = provider_config.get("DEFAULT_MODEL")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_MODEL.to_string());
+
+        Ok(Self {
+            client,
+            api_key,
+            base_url: provider_config.get("API_ENDPOINT")
+                .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            vision_models: VisionModels::from_provider_config(provider_config, &default_model, VISION_MODELS),
+            default_model,
+            max_tokens,
+            system_prompt: provider_config.get("SYSTEM_PROMPT").cloned(),
+        })
+    }
+
+    fn image_block(&self, image_data: Vec<u8>) -> Result<serde_json::Value, AppError> {
+        let image = self.process_image(image_data)?;
+
+        Ok(json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": image.media_type,
+                "data": image.data
+            }
+        }))
+    }
+
+    // 图片块和文本块保持原有顺序；tool 消息作为 user 消息中的 tool_result 发送
+    fn message(&self, message: ChatMessage) -> Result<serde_json::Value, AppError> {
+        if message.role == ChatRole::Tool {
+            let tool_use_id = message.tool_call_id.clone().ok_or_else(||
+                AppError::ValidationError("tool 消息缺少 tool_call_id".to_string()))?;
+            return Ok(json!({
+                "role": "user",
+                "content": [{
+                    "type": "tool_result",
+                    "tool_use_id": tool_use_id,
+                    "content": message.text_content()
+                }]
+            }));
+        }
+
+        // 只调用工具时没有文本，Messages API 不接受空的文本块
+        let mut content = message.content
+            .into_iter()
+            .filter(|part| !matches!(part, ContentPart::Text { text } if text.is_empty()))
+            .map(|part| match part {
+                ContentPart::Text { text } => Ok(json!({ "type": "text", "text": text })),
+                ContentPart::Image { data } => self.image_block(data),
+                ContentPart::ImageUrl { url } => Ok(json!({
+                    "type": "image",
+                    "source": { "type": "url", "url": url }
+                })),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        content.extend(message.tool_calls.into_iter().map(|call| {
+            // input 必须是对象，无法解析的参数按空对象发送
+            let input = if call.arguments.is_object() { call.arguments } else { json!({}) };
+            json!({ "type": "tool_use", "id": call.id, "name": call.name, "input": input })
+        }));
+        Ok(json!({ "role": message.role.as_str(), "content": content }))
+    }
+
+    // Messages API 的 system 是顶层字段，所有 system 消息合并后放入；没有时使用配置的 SYSTEM_PROMPT
+    fn prepare(&self, request: AIRequest) -> Result<serde_json::Value, AppError> {
+        ensure_params_supported("Anthropic", &request.params, self.supported_params(&request))?;
+        let model = self.request_model(&request);
+        let (system, conversation): (Vec<_>, Vec<_>) = request.messages
+            .into_iter()
+            .partition(|message| message.role == ChatRole::System);
+
+        let messages = conversation
+            .into_iter()
+            .map(|message| self.message(message))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let params = request.params;
+        let mut payload = json!({
+            "model": model,
+            "max_tokens": params.max_tokens.unwrap_or(self.max_tokens),
+            "messages": messages
+        });
+        if !request.tools.is_empty() {
+            payload["tools"] = request.tools
+                .iter()
+                .map(|tool| json!({
+                    "name": tool.name,
+                    "description": tool.description,
+                    "input_schema": tool.parameters
+                }))
+                .collect();
+        }
+        if let Some(temperature) = params.temperature {
+            payload["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = params.top_p {
+            payload["top_p"] = json!(top_p);
+        }
+        if let Some(stop) = params.stop {
+            payload["stop_sequences"] = json!(stop);
+        }
+        let system = if system.is_empty() {
+            self.system_prompt.clone()
+        } else {
+            Some(system.iter().map(ChatMessage::text_content).collect::<Vec<_>>().join("\n\n"))
+        };
+        if let Some(system) = system {
+            payload["system"] = json!(system);
+        }
+        Ok(payload)
+    }
+
+    async fn send(&self, payload: &serde_json::Value) -> Result<reqwest::Response, AppError> {
+        log::debug!("Sending request to Anthropic API with model {}", payload["model"]);
+
+        let response = self.client
+            .post(self.get_endpoint(false))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", API_VERSION)
+            .header("Content-Type", "application/json")
+            .json(payload)
+            .send()
+            .await
+            .map_err(|e| error_from_request("Anthropic", e))?;
+
+        if !response.status().is_success() {
+            let status = response.status().as_u16();
+            let retry_after = retry_after_seconds(response.headers());
+            let body = response.text().await.unwrap_or_default();
+            return Err(map_error(status, retry_after, body));
+        }
+        Ok(response)
+    }
+}
+
+// Messages API 的错误体带有类型字段，优先按类型映射
+fn map_error(status: u16, retry_after: Option<u64>, body: String) -> AppError {
+    let error_type = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|value| value.pointer("/error/type").and_then(|t| t.as_str()).map(str::to_string));
+
+    match error_type.as_deref() {
+        Some("overloaded_error") => AppError::AIOverloaded {
+            message: format!("Anthropic API overloaded: {}", body),
+            retry_after,
+        },
+        Some("rate_limit_error") => AppError::AIRateLimited {
+            message: format!("Anthropic API rate limited: {}", body),
+            retry_after,
+        },
+        Some("invalid_request_error") | Some("request_too_large") | Some("not_found_error") => {
+            AppError::AIInvalidRequest(format!("Anthropic API rejected request: {}", body))
+        }
+        // 密钥失效时按提供商不可用处理，可以切换到其他提供商
+        Some("authentication_error") | Some("permission_error") => {
+            AppError::AIUnavailable(format!("Anthropic API credentials rejected: {}", body))
+        }
+        _ => error_from_status("Anthropic", status, retry_after, body),
+    }
+}
+
+// 流式响应：message_start 携带输入用量，message_delta 携带结束原因和输出用量
+#[derive(Default)]
+struct StreamState {
+    finish_reason: Option<String>,
+    usage: serde_json::Map<String, serde_json::Value>,
+    model: Option<String>,
+}
+
+impl StreamState {
+    fn merge_usage(&mut self, usage: Option<&serde_json::Value>) {
+        if let Some(usage) = usage.and_then(|usage| usage.as_object()) {
+            self.usage.extend(usage.iter().map(|(key, value)| (key.clone(), value.clone())));
+        }
+    }
+
+    fn handle(&mut self, data: &str) -> Vec<Result<StreamEvent, AppError>> {
+        let event = match parse_stream_json("Anthropic", data) {
+            Ok(event) => event,
+            Err(e) => return vec![Err(e)],
+        };
+
+        match event.get("type").and_then(|t| t.as_str()) {
+            Some("message_start") => {
+                self.merge_usage(event.pointer("/message/usage"));
+                self.model = event.pointer("/message/model").and_then(|model| model.as_str()).map(str::to_string);
+                Vec::new()
+            }
+            Some("content_block_delta") => event
+                .pointer("/delta/text")
+                .and_then(|text| text.as_str())
+                .map(|text| vec![Ok(StreamEvent::Delta { content: text.to_string() })])
+                .unwrap_or_default(),
+            Some("message_delta") => {
+                self.finish_reason = event
+                    .pointer("/delta/stop_reason")
+                    .and_then(|reason| reason.as_str())
+                    .map(str::to_string);
+                self.merge_usage(event.get("usage"));
+                Vec::new()
+            }
+            Some("message_stop") => vec![Ok(StreamEvent::Done {
+                finish_reason: self.finish_reason.take(),
+                usage: (!self.usage.is_empty())
+                    .then(|| parse_usage(&serde_json::Value::Object(std::mem::take(&mut self.usage)))),
+                provider: None,
+                model: self.model.take(),
+            })],
+            Some("error") => vec![Err(map_error(200, None, data.to_string()))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Provider for AnthropicProvider {
+    fn image_limits(&self) -> &'static ImageLimits {
+        &IMAGE_LIMITS
+    }
+
+    fn vision_models(&self) -> &VisionModels {
+        &self.vision_models
+    }
+
+    fn supported_params(&self, _request: &AIRequest) -> &'static [&'static str] {
+        SUPPORTED_PARAMS
+    }
+
+    fn get_endpoint(&self, _is_multimodal: bool) -> String {
+        format!("{}/messages", self.base_url)
+    }
+
+    fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
+        let payload = self.prepare(request)?;
+        let response = self.send(&payload).await?;
+        let request_id = header_string(response.headers(), "request-id");
+
+        let response_data = response.json::<serde_json::Value>().await
+            .map_err(|e| AppError::AIServiceError(format!("Parse response failed: {}", e)))?;
+
+        log::debug!("Anthropic API response: {:?}", response_data);
+
+        let blocks = response_data
+            .get("content")
+            .and_then(|content| content.as_array())
+            .ok_or_else(|| AppError::AIServiceError("Invalid response format".to_string()))?;
+        let block_type = |block: &serde_json::Value, expected: &str| {
+            block.get("type").and_then(|t| t.as_str()) == Some(expected)
+        };
+        let content = blocks.iter()
+            .filter(|block| block_type(block, "text"))
+            .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join("");
+        let tool_calls = blocks.iter()
+            .filter(|block| block_type(block, "tool_use"))
+            .filter_map(|block| Some(ToolCall {
+                id: block.get("id")?.as_str()?.to_string(),
+                name: block.get("name")?.as_str()?.to_string(),
+                arguments: block.get("input").cloned().unwrap_or_else(|| json!({})),
+            }))
+            .collect();
+
+        Ok(AIResponse {
+            content,
+            model: response_data.get("model").and_then(|model| model.as_str()).map(str::to_string),
+            finish_reason: response_data.get("stop_reason")
+                .and_then(|reason| reason.as_str())
+                .map(str::to_string),
+            usage: response_data.get("usage").map(parse_usage),
+            request_id: request_id.or_else(|| {
+                response_data.get("id").and_then(|id| id.as_str()).map(str::to_string)
+            }),
+            tool_calls,
+            raw_response: Some(response_data),
+            ..Default::default()
+        })
+    }
+
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError> {
+        let mut payload = self.prepare(request)?;
+        payload["stream"] = json!(true);
+        let response = self.send(&payload).await?;
+
+        let events = sse_messages("Anthropic", response)
+            .scan(StreamState::default(), |state, message| {
+                let events = match message {
+                    Ok(message) => state.handle(&message.data),
+                    Err(e) => vec![Err(e)],
+                };
+                futures::future::ready(Some(futures::stream::iter(events)))
+            })
+            .flatten();
+
+        Ok(Box::pin(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ai::{GenerationParams, ToolDefinition};
+    use crate::service::image_processing::encode_png;
+    use image::DynamicImage;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn provider_for(server: &MockServer) -> AnthropicProvider {
+        let config = HashMap::from([
+            ("API_KEY".to_string(), "test-key".to_string()),
+            ("API_ENDPOINT".to_string(), format!("{}/v1", server.uri())),
+            ("DEFAULT_MODEL".to_string(), "claude-test".to_string()),
+            ("SYSTEM_PROMPT".to_string(), "configured prompt".to_string()),
+        ]);
+        AnthropicProvider::from_provider_config(Client::new(), &config).unwrap()
+    }
+
+    fn request(messages: Vec<ChatMessage>) -> AIRequest {
+        AIRequest {
+            messages,
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    fn message_response() -> serde_json::Value {
+        json!({
+            "id": "msg_1",
+            "type": "message",
+            "model": "claude-test",
+            "content": [
+                { "type": "text", "text": "Checking " },
+                { "type": "text", "text": "the weather." },
+                { "type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": { "city": "Paris" } }
+            ],
+            "stop_reason": "tool_use",
+            "usage": { "input_tokens": 10, "cache_read_input_tokens": 90, "output_tokens": 5 }
+        })
+    }
+
+    #[tokio::test]
+    async fn maps_system_prompt_and_content_blocks() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .and(header("x-api-key", "test-key"))
+            .and(header("anthropic-version", API_VERSION))
+            .respond_with(ResponseTemplate::new(200).set_body_json(message_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let image = encode_png(&DynamicImage::new_rgb8(8, 8)).unwrap();
+        let call = ToolCall { id: "toolu_0".to_string(), name: "get_weather".to_string(), arguments: json!({ "city": "Rome" }) };
+        let mut request = request(vec![
+            ChatMessage::text(ChatRole::System, "be brief"),
+            ChatMessage::text(ChatRole::System, "answer in English"),
+            ChatMessage {
+                role: ChatRole::User,
+                content: vec![
+                    ContentPart::Text { text: "what is this?".to_string() },
+                    ContentPart::Image { data: image },
+                    ContentPart::ImageUrl { url: "https://example.com/a.png".to_string() },
+                ],
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+            },
+            ChatMessage { tool_calls: vec![call], ..ChatMessage::text(ChatRole::Assistant, "") },
+            ChatMessage { tool_call_id: Some("toolu_0".to_string()), ..ChatMessage::text(ChatRole::Tool, "{\"temp\":21}") },
+        ]);
+        request.tools = vec![ToolDefinition {
+            name: "get_weather".to_string(),
+            description: "Get the weather".to_string(),
+            parameters: json!({ "type": "object" }),
+        }];
+
+        let response = provider_for(&server).analyze(request).await.unwrap();
+
+        let body: serde_json::Value = server.received_requests().await.unwrap()[0].body_json().unwrap();
+        assert_eq!(body["model"], "claude-test");
+        assert_eq!(body["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(body["system"], "be brief\n\nanswer in English");
+        assert_eq!(body["tools"][0]["input_schema"], json!({ "type": "object" }));
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        let user = &messages[0]["content"];
+        assert_eq!(user[0], json!({ "type": "text", "text": "what is this?" }));
+        assert_eq!(user[1]["source"]["type"], "base64");
+        assert_eq!(user[1]["source"]["media_type"], "image/png");
+        assert_eq!(user[2]["source"], json!({ "type": "url", "url": "https://example.com/a.png" }));
+        // 空文本块被省略，只保留 tool_use
+        assert_eq!(messages[1]["content"], json!([
+            { "type": "tool_use", "id": "toolu_0", "name": "get_weather", "input": { "city": "Rome" } }
+        ]));
+        assert_eq!(messages[2], json!({
+            "role": "user",
+            "content": [{ "type": "tool_result", "tool_use_id": "toolu_0", "content": "{\"temp\":21}" }]
+        }));
+
+        assert_eq!(response.content, "Checking the weather.");
+        assert_eq!(response.finish_reason.as_deref(), Some("tool_use"));
+        assert_eq!(response.request_id.as_deref(), Some("msg_1"));
+        assert_eq!(response.tool_calls[0].name, "get_weather");
+        assert_eq!(response.tool_calls[0].arguments, json!({ "city": "Paris" }));
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(100), Some(5), Some(105)));
+    }
+
+    #[tokio::test]
+    async fn parses_parallel_tool_use_blocks() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "msg_2",
+                "type": "message",
+                "model": "claude-test",
+                "content": [
+                    { "type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": { "city": "Paris" } },
+                    { "type": "tool_use", "id": "toolu_2", "name": "current_time" },
+                    // 缺少 id 的块无法回应，直接忽略
+                    { "type": "tool_use", "name": "get_weather", "input": { "city": "Rome" } }
+                ],
+                "stop_reason": "tool_use",
+                "usage": { "input_tokens": 20, "output_tokens": 30 }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(request(vec![ChatMessage::text(ChatRole::User, "weather and time?")]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "");
+        assert_eq!(response.tool_calls, [
+            ToolCall { id: "toolu_1".to_string(), name: "get_weather".to_string(), arguments: json!({ "city": "Paris" }) },
+            ToolCall { id: "toolu_2".to_string(), name: "current_time".to_string(), arguments: json!({}) },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn uses_configured_system_prompt_without_system_messages() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(message_response()))
+            .mount(&server)
+            .await;
+
+        provider_for(&server)
+            .analyze(request(vec![ChatMessage::text(ChatRole::User, "hi")]))
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = server.received_requests().await.unwrap()[0].body_json().unwrap();
+        assert_eq!(body["system"], "configured prompt");
+        assert_eq!(body["messages"], json!([{ "role": "user", "content": [{ "type": "text", "text": "hi" }] }]));
+    }
+
+    #[tokio::test]
+    async fn streams_deltas_and_final_usage() {
+        let server = MockServer::start().await;
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_1","model":"claude-3-5-sonnet-20241022","usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"lo"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":7}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let body: String = events
+            .iter()
+            .map(|data| {
+                let name = serde_json::from_str::<serde_json::Value>(data).unwrap()["type"].as_str().unwrap().to_string();
+                format!("event: {}\ndata: {}\n\n", name, data)
+            })
+            .collect();
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let events: Vec<_> = provider_for(&server)
+            .analyze_stream(request(vec![ChatMessage::text(ChatRole::User, "hi")]))
+            .await
+            .unwrap()
+            .collect()
+            .await;
+
+        let body: serde_json::Value = server.received_requests().await.unwrap()[0].body_json().unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], Ok(StreamEvent::Delta { content }) if content == "Hel"));
+        assert!(matches!(&events[1], Ok(StreamEvent::Delta { content }) if content == "lo"));
+        let Ok(StreamEvent::Done { finish_reason, usage: Some(usage), model, .. }) = &events[2] else {
+            panic!("expected done event, got {:?}", events[2]);
+        };
+        assert_eq!(finish_reason.as_deref(), Some("end_turn"));
+        assert_eq!(model.as_deref(), Some("claude-3-5-sonnet-20241022"));
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(12), Some(7), Some(19)));
+    }
+
+    #[test]
+    fn parses_usage_with_prompt_cache_tokens() {
+        let plain = parse_usage(&json!({ "input_tokens": 25, "output_tokens": 10 }));
+        assert_eq!((plain.input_tokens, plain.output_tokens, plain.total_tokens), (Some(25), Some(10), Some(35)));
+
+        // 缓存写入和读取的 token 计入输入
+        let cached = parse_usage(&json!({
+            "input_tokens": 5,
+            "cache_creation_input_tokens": 100,
+            "cache_read_input_tokens": 2000,
+            "output_tokens": 50
+        }));
+        assert_eq!((cached.input_tokens, cached.output_tokens, cached.total_tokens), (Some(2105), Some(50), Some(2155)));
+
+        let output_only = parse_usage(&json!({ "output_tokens": 7 }));
+        assert_eq!((output_only.input_tokens, output_only.output_tokens, output_only.total_tokens), (None, Some(7), None));
+    }
+
+    #[test]
+    fn stream_error_event_is_mapped() {
+        let mut state = StreamState::default();
+
+        let events = state.handle(r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#);
+
+        assert!(matches!(events.as_slice(), [Err(AppError::AIOverloaded { retry_after: None, .. })]));
+    }
+
+    #[tokio::test]
+    async fn maps_error_types() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/messages"))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("retry-after", "12")
+                    .set_body_json(json!({ "type": "error", "error": { "type": "rate_limit_error", "message": "slow down" } })),
+            )
+            .mount(&server)
+            .await;
+
+        let error = provider_for(&server)
+            .analyze(request(vec![ChatMessage::text(ChatRole::User, "hi")]))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AppError::AIRateLimited { retry_after: Some(12), .. }), "{:?}", error);
+
+        let error_body = |error_type: &str| json!({ "type": "error", "error": { "type": error_type, "message": "x" } }).to_string();
+        assert!(matches!(
+            map_error(529, Some(30), error_body("overloaded_error")),
+            AppError::AIOverloaded { retry_after: Some(30), .. }
+        ));
+        assert!(matches!(map_error(400, None, error_body("invalid_request_error")), AppError::AIInvalidRequest(_)));
+        assert!(matches!(map_error(401, None, error_body("authentication_error")), AppError::AIUnavailable(_)));
+        assert!(matches!(map_error(403, None, error_body("permission_error")), AppError::AIUnavailable(_)));
+        assert!(matches!(map_error(502, None, "bad gateway".to_string()), AppError::AIUpstreamError { status: 502, .. }));
+    }
+}
@@ -1,18 +1,410 @@
+use std::pin::Pin;
+
+use std::collections::HashMap;
+
 use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::{Stream, StreamExt};
+use crate::config::model_matches;
 use crate::errors::AppError;
-use crate::models::ai::{AIRequest, AIResponse};
+use crate::models::ai::{
+    AIRequest, AIResponse, ChatMessage, EmbeddingBatch, GenerationParams, StreamEvent, ToolCall, ToolDefinition,
+};
+use crate::service::image_processing::{sniff_image_kind, ImageLimits};
+
+pub type AIStream = Pin<Box<dyn Stream<Item = Result<StreamEvent, AppError>> + Send>>;
+
+// 不带 data: 前缀的 base64 及其真实的 MIME 类型
+pub struct Base64Image {
+    pub media_type: &'static str,
+    pub data: String,
+}
+
+impl Base64Image {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
 
-pub enum ImageFormat {
-    Binary(Vec<u8>),
-    Base64(String),
-    Url(String),
+// 支持图片输入的模型；default 用于未指定模型的图片请求，allowed 中以 * 结尾的按前缀匹配
+#[derive(Debug, Clone)]
+pub struct VisionModels {
+    pub default: String,
+    pub allowed: Vec<String>,
+}
+
+impl VisionModels {
+    // VISION_MODEL 和逗号分隔的 VISION_MODELS 覆盖内置值
+    pub fn from_provider_config(provider_config: &HashMap<String, String>, default: &str, allowed: &[&str]) -> Self {
+        let allowed = match provider_config.get("VISION_MODELS") {
+            Some(models) => models
+                .split(',')
+                .map(str::trim)
+                .filter(|model| !model.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => allowed.iter().map(|model| model.to_string()).collect(),
+        };
+        Self {
+            default: provider_config.get("VISION_MODEL").cloned().unwrap_or_else(|| default.to_string()),
+            allowed,
+        }
+    }
+
+    pub fn allows(&self, model: &str) -> bool {
+        model == self.default || self.allowed.iter().any(|pattern| model_matches(pattern, model))
+    }
+}
+
+// 向量嵌入的默认模型和单次请求最多的输入条数
+#[derive(Debug, Clone)]
+pub struct EmbeddingModels {
+    pub default: String,
+    pub max_batch: usize,
+}
+
+impl EmbeddingModels {
+    // EMBEDDING_MODEL 和 EMBEDDING_BATCH_SIZE 覆盖内置值
+    pub fn from_provider_config(
+        provider_config: &HashMap<String, String>,
+        default: &str,
+        max_batch: usize,
+    ) -> Result<Self, AppError> {
+        let max_batch = match provider_config.get("EMBEDDING_BATCH_SIZE") {
+            Some(size) => size
+                .parse()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| AppError::ConfigError(format!("无效的 EMBEDDING_BATCH_SIZE: {}", size)))?,
+            None => max_batch,
+        };
+        Ok(Self {
+            default: provider_config.get("EMBEDDING_MODEL").cloned().unwrap_or_else(|| default.to_string()),
+            max_batch,
+        })
+    }
 }
 
 #[async_trait]
 pub trait Provider: Send + Sync {
-    fn process_image(&self, image_data: Vec<u8>) -> Result<ImageFormat, AppError>;
+    // 提供商接受的图片格式、大小和尺寸
+    fn image_limits(&self) -> &'static ImageLimits;
+
+    // 图片已由服务层按 image_limits 预处理，这里只识别格式并编码为 base64
+    fn process_image(&self, image_data: Vec<u8>) -> Result<Base64Image, AppError> {
+        let kind = sniff_image_kind(&image_data)
+            .ok_or_else(|| AppError::ValidationError("不支持的图片格式，仅支持 JPEG/PNG/GIF/WebP".to_string()))?;
+        Ok(Base64Image {
+            media_type: kind.mime_type(),
+            data: STANDARD.encode(image_data),
+        })
+    }
+
+    // 提供商能否自行下载 ContentPart::ImageUrl；不能时由服务端下载后以图片字节发送
+    fn supports_image_urls(&self) -> bool {
+        true
+    }
+
+    fn vision_models(&self) -> &VisionModels;
+
+    // 该请求可以使用的生成参数，其余参数在调用前拒绝
+    fn supported_params(&self, request: &AIRequest) -> &'static [&'static str];
+
+    // 请求使用的模型；未指定时含图片的请求使用默认视觉模型
+    fn request_model(&self, request: &AIRequest) -> String {
+        match &request.model {
+            Some(model) => model.clone(),
+            None if request.messages.iter().any(ChatMessage::has_images) => self.vision_models().default.clone(),
+            None => self.default_model().to_string(),
+        }
+    }
+
     fn get_endpoint(&self, is_multimodal: bool) -> String;
+    // 请求未指定模型时使用的模型，用于匹配按模型配置的生成参数
+    fn default_model(&self) -> &str;
     async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError>;
+
+    // 不支持流式输出的提供商一次性返回完整结果
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError> {
+        let response = self.analyze(request).await?;
+        let events = vec![
+            Ok(StreamEvent::Delta { content: response.content }),
+            Ok(StreamEvent::Done {
+                finish_reason: None,
+                usage: response.usage,
+                provider: None,
+                model: response.model,
+            }),
+        ];
+        Ok(Box::pin(futures::stream::iter(events)))
+    }
+
+    // 不支持向量嵌入的提供商返回 None
+    fn embedding_models(&self) -> Option<&EmbeddingModels> {
+        None
+    }
+
+    // inputs 不超过 max_batch 条，由服务层分批
+    async fn embed(&self, _model: &str, _inputs: Vec<String>) -> Result<EmbeddingBatch, AppError> {
+        Err(AppError::AIInvalidRequest("该 AI 提供商不支持向量嵌入".to_string()))
+    }
+}
+
+pub mod tongyi;
+pub mod openai;
+pub mod anthropic;
+pub mod ollama;
+
+// 提供商不支持的生成参数直接拒绝，避免被静默忽略
+pub fn ensure_params_supported(
+    provider: &str,
+    params: &GenerationParams,
+    supported: &[&str],
+) -> Result<(), AppError> {
+    let unsupported: Vec<_> = params
+        .set_fields()
+        .into_iter()
+        .filter(|field| !supported.contains(field))
+        .collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::AIInvalidRequest(format!("{} 不支持参数: {}", provider, unsupported.join(", "))))
+}
+
+// OpenAI 兼容接口和 DashScope 共用的 function 工具格式
+pub fn openai_tools(tools: &[ToolDefinition]) -> serde_json::Value {
+    tools
+        .iter()
+        .map(|tool| {
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name,
+                    "description": tool.description,
+                    "parameters": tool.parameters
+                }
+            })
+        })
+        .collect()
+}
+
+pub fn openai_tool_calls(calls: &[ToolCall]) -> serde_json::Value {
+    calls
+        .iter()
+        .map(|call| {
+            serde_json::json!({
+                "id": call.id,
+                "type": "function",
+                "function": { "name": call.name, "arguments": call.arguments_json() }
+            })
+        })
+        .collect()
 }
 
-pub mod tongyi;
\ No newline at end of file
+// 解析 message.tool_calls，arguments 为 JSON 字符串
+pub fn parse_openai_tool_calls(message: Option<&serde_json::Value>) -> Vec<ToolCall> {
+    message
+        .and_then(|message| message.get("tool_calls"))
+        .and_then(|calls| calls.as_array())
+        .map(|calls| {
+            calls
+                .iter()
+                .filter_map(|call| {
+                    let function = call.get("function")?;
+                    Some(ToolCall::from_json_arguments(
+                        call.get("id").and_then(|id| id.as_str()).unwrap_or_default().to_string(),
+                        function.get("name")?.as_str()?.to_string(),
+                        function.get("arguments").and_then(|arguments| arguments.as_str()).unwrap_or_default(),
+                    ))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// 按序号整理返回的向量；序号越界、重复或有输入没有对应向量时视为无效响应
+pub fn ordered_embeddings(
+    provider: &str,
+    items: Option<&serde_json::Value>,
+    index_field: &str,
+    count: usize,
+) -> Result<Vec<Vec<f32>>, AppError> {
+    let invalid = || AppError::AIServiceError(format!("{} 返回的向量无效", provider));
+    let items = items.and_then(|items| items.as_array()).ok_or_else(invalid)?;
+    let mut embeddings = vec![None; count];
+    for item in items {
+        let index = item.get(index_field).and_then(|index| index.as_u64()).ok_or_else(invalid)? as usize;
+        let embedding = item
+            .get("embedding")
+            .and_then(|embedding| embedding.as_array())
+            .and_then(|values| values.iter().map(|value| value.as_f64().map(|value| value as f32)).collect())
+            .ok_or_else(invalid)?;
+        match embeddings.get_mut(index) {
+            Some(slot @ None) => *slot = Some(embedding),
+            _ => return Err(invalid()),
+        }
+    }
+    embeddings.into_iter().collect::<Option<Vec<_>>>().ok_or_else(invalid)
+}
+
+// 按 HTTP 状态码把上游错误响应映射为具体的 AppError
+pub async fn error_from_response(provider: &str, response: reqwest::Response) -> AppError {
+    let status = response.status().as_u16();
+    let retry_after = retry_after_seconds(response.headers());
+    let body = response.text().await.unwrap_or_default();
+    error_from_status(provider, status, retry_after, body)
+}
+
+pub fn error_from_status(provider: &str, status: u16, retry_after: Option<u64>, body: String) -> AppError {
+    let message = format!("{} API error ({}): {}", provider, status, body);
+    match status {
+        400 | 404 | 413 | 422 => AppError::AIInvalidRequest(message),
+        429 => AppError::AIRateLimited { message, retry_after },
+        503 | 529 => AppError::AIOverloaded { message, retry_after },
+        500..=599 => AppError::AIUpstreamError { status, message },
+        _ => AppError::AIServiceError(message),
+    }
+}
+
+// 发送请求失败（未收到响应）时的错误映射
+pub fn error_from_request(provider: &str, error: reqwest::Error) -> AppError {
+    let message = format!("{} request failed: {}", provider, error);
+    if error.is_timeout() {
+        AppError::AITimeout(message)
+    } else if error.is_connect() {
+        AppError::AIUnavailable(message)
+    } else {
+        AppError::AIServiceError(message)
+    }
+}
+
+pub fn header_string(headers: &reqwest::header::HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+// 读取 usage 中的整数字段
+pub fn usage_field(usage: &serde_json::Value, pointer: &str) -> Option<u64> {
+    usage.pointer(pointer).and_then(|value| value.as_u64())
+}
+
+pub fn retry_after_seconds(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, chrono::Utc::now()))
+}
+
+// Retry-After 可以是秒数，也可以是 HTTP 日期（如 Wed, 21 Oct 2015 07:28:00 GMT）
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds);
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&chrono::Utc) - now).num_seconds().max(0) as u64)
+}
+
+// 按行读取流式响应体，兼容 \n 和 \r\n
+pub fn response_lines(
+    provider: &'static str,
+    response: reqwest::Response,
+) -> impl Stream<Item = Result<String, AppError>> + Send {
+    let body = Box::pin(response.bytes_stream());
+    futures::stream::unfold(
+        (body, Vec::new(), false),
+        move |(mut body, mut buffer, mut finished)| async move {
+            loop {
+                if let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=pos).collect();
+                    let line = String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string();
+                    return Some((Ok(line), (body, buffer, finished)));
+                }
+                if finished {
+                    if buffer.is_empty() {
+                        return None;
+                    }
+                    let line = String::from_utf8_lossy(&buffer).trim_end_matches('\r').to_string();
+                    buffer.clear();
+                    return Some((Ok(line), (body, buffer, finished)));
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        buffer.clear();
+                        return Some((Err(error_from_request(provider, e)), (body, buffer, true)));
+                    }
+                    None => finished = true,
+                }
+            }
+        },
+    )
+}
+
+pub struct SseMessage {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+// 解析 text/event-stream，每条消息以空行结束
+pub fn sse_messages(
+    provider: &'static str,
+    response: reqwest::Response,
+) -> impl Stream<Item = Result<SseMessage, AppError>> + Send {
+    let lines = Box::pin(response_lines(provider, response));
+    futures::stream::unfold(lines, |mut lines| async move {
+        let mut event = None;
+        let mut data: Vec<String> = Vec::new();
+        loop {
+            match lines.next().await {
+                Some(Ok(line)) if line.is_empty() => {
+                    if !data.is_empty() {
+                        return Some((Ok(SseMessage { event, data: data.join("\n") }), lines));
+                    }
+                    event = None;
+                }
+                Some(Ok(line)) => {
+                    if line.starts_with(':') {
+                        continue;
+                    }
+                    let (field, value) = line.split_once(':').unwrap_or((line.as_str(), ""));
+                    let value = value.strip_prefix(' ').unwrap_or(value);
+                    match field {
+                        "event" => event = Some(value.to_string()),
+                        "data" => data.push(value.to_string()),
+                        _ => {}
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), lines)),
+                None if data.is_empty() => return None,
+                None => return Some((Ok(SseMessage { event, data: data.join("\n") }), lines)),
+            }
+        }
+    })
+}
+
+// 流式事件的 data 字段均为 JSON
+pub fn parse_stream_json(provider: &str, data: &str) -> Result<serde_json::Value, AppError> {
+    serde_json::from_str(data)
+        .map_err(|e| AppError::AIServiceError(format!("{} stream parse failed: {}", provider, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        let now = chrono::Utc.with_ymd_and_hms(2015, 10, 21, 7, 27, 30).unwrap();
+
+        assert_eq!(parse_retry_after(" 120 ", now), Some(120));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now), Some(30));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now), Some(0));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
@@ -0,0 +1,475 @@
+use super::{
+    ensure_params_supported, error_from_request, error_from_response, parse_stream_json, response_lines,
+    usage_field, AIStream, Provider, VisionModels,
+};
+use crate::errors::AppError;
+use crate::models::ai::{AIRequest, AIResponse, ChatMessage, ContentPart, StreamEvent, TokenUsage};
+use reqwest::Client;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::HashMap;
+use crate::service::image_processing::{ImageKind, ImageLimits};
+
+const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+const DEFAULT_MODEL: &str = "llama3.2";
+// 多数本地视觉模型只支持 JPEG 和 PNG，并会在内部缩小大图
+const IMAGE_LIMITS: ImageLimits = ImageLimits {
+    formats: &[ImageKind::Jpeg, ImageKind::Png],
+    max_bytes: 20 * 1024 * 1024,
+    max_dimension: 2048,
+    max_pixels: 2048 * 2048,
+};
+const DEFAULT_VISION_MODEL: &str = "llava";
+const VISION_MODELS: &[&str] = &[
+    "llava*", "bakllava*", "llama3.2-vision*", "llama4*", "minicpm-v*", "qwen2.5vl*", "gemma3*",
+    "moondream*", "granite3.2-vision*",
+];
+const SUPPORTED_PARAMS: &[&str] = &["temperature", "top_p", "max_tokens", "stop", "seed"];
+
+// Ollama 本地模型服务（/api/chat），数据不出内网；多模态模型（如 llava）通过 images 字段接收图片
+pub struct OllamaProvider {
+    client: Client,
+    base_url: String,
+    default_model: String,
+    vision_models: VisionModels,
+}
+
+impl OllamaProvider {
+    // 本地服务无需密钥，未配置地址时使用默认值
+    pub fn from_provider_config(client: Client, provider_config: &HashMap<String, String>) -> Self {
+        Self {
+            client,
+            base_url: provider_config.get("API_ENDPOINT")
+                .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+            default_model: provider_config.get("DEFAULT_MODEL")
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            vision_models: VisionModels::from_provider_config(provider_config, DEFAULT_VISION_MODEL, VISION_MODELS),
+        }
+    }
+
+    // Ollama 的 content 为字符串，图片单独放在 images 字段
+    fn message(&self, message: ChatMessage) -> Result<serde_json::Value, AppError> {
+        let mut value = json!({
+            "role": message.role.as_str(),
+            "content": message.text_content()
+        });
+        let mut images = Vec::new();
+        for part in message.content {
+            match part {
+                ContentPart::Text { .. } => {}
+                ContentPart::Image { data } => images.push(self.encode_image(data)?),
+                ContentPart::ImageUrl { .. } => {
+                    return Err(AppError::AIInvalidRequest("Ollama 不支持图片 URL，请直接上传图片".to_string()));
+                }
+            }
+        }
+        if !images.is_empty() {
+            value["images"] = json!(images);
+        }
+        Ok(value)
+    }
+
+    // Ollama 要求不带 data: 前缀的纯 base64
+    fn encode_image(&self, image_data: Vec<u8>) -> Result<String, AppError> {
+        Ok(self.process_image(image_data)?.data)
+    }
+
+    fn prepare(&self, request: AIRequest) -> Result<serde_json::Value, AppError> {
+        ensure_params_supported("Ollama", &request.params, self.supported_params(&request))?;
+        if !request.tools.is_empty() {
+            return Err(AppError::AIInvalidRequest("Ollama 暂不支持工具调用".to_string()));
+        }
+        let model = self.request_model(&request);
+        let messages = request.messages
+            .into_iter()
+            .map(|message| self.message(message))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut payload = json!({
+            "model": model,
+            "messages": messages,
+            "stream": false
+        });
+        // format 直接接受 JSON Schema，按 Schema 约束生成
+        if let Some(schema) = request.response_schema {
+            payload["format"] = schema;
+        }
+
+        // 生成参数放在 options 中，max_tokens 对应 num_predict
+        let params = request.params;
+        let mut options = serde_json::Map::new();
+        if let Some(temperature) = params.temperature {
+            options.insert("temperature".to_string(), json!(temperature));
+        }
+        if let Some(top_p) = params.top_p {
+            options.insert("top_p".to_string(), json!(top_p));
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            options.insert("num_predict".to_string(), json!(max_tokens));
+        }
+        if let Some(stop) = params.stop {
+            options.insert("stop".to_string(), json!(stop));
+        }
+        if let Some(seed) = params.seed {
+            options.insert("seed".to_string(), json!(seed));
+        }
+        if !options.is_empty() {
+            payload["options"] = serde_json::Value::Object(options);
+        }
+        Ok(payload)
+    }
+
+    async fn send(&self, payload: &serde_json::Value) -> Result<reqwest::Response, AppError> {
+        log::debug!("Sending request to Ollama: {}", self.get_endpoint(false));
+
+        let response = self.client
+            .post(self.get_endpoint(false))
+            .json(payload)
+            .send()
+            .await
+            .map_err(|e| error_from_request("Ollama", e))?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response("Ollama", response).await);
+        }
+        Ok(response)
+    }
+}
+
+// 用量在响应顶层：prompt_eval_count 为输入，eval_count 为输出
+fn parse_usage(data: &serde_json::Value) -> TokenUsage {
+    TokenUsage {
+        input_tokens: usage_field(data, "/prompt_eval_count"),
+        output_tokens: usage_field(data, "/eval_count"),
+        image_tokens: None,
+        total_tokens: None,
+    }
+    .with_total()
+}
+
+// 流式响应为 NDJSON，最后一行 done 为 true 并带有用量统计
+fn parse_stream_line(line: &str) -> Vec<Result<StreamEvent, AppError>> {
+    if line.trim().is_empty() {
+        return Vec::new();
+    }
+    let chunk = match parse_stream_json("Ollama", line) {
+        Ok(chunk) => chunk,
+        Err(e) => return vec![Err(e)],
+    };
+    if let Some(error) = chunk.get("error").and_then(|error| error.as_str()) {
+        return vec![Err(AppError::AIServiceError(format!("Ollama stream error: {}", error)))];
+    }
+
+    let mut events = Vec::new();
+    if let Some(content) = chunk
+        .pointer("/message/content")
+        .and_then(|content| content.as_str())
+        .filter(|content| !content.is_empty())
+    {
+        events.push(Ok(StreamEvent::Delta { content: content.to_string() }));
+    }
+    if chunk.get("done").and_then(|done| done.as_bool()) == Some(true) {
+        events.push(Ok(StreamEvent::Done {
+            finish_reason: chunk.get("done_reason").and_then(|reason| reason.as_str()).map(str::to_string),
+            usage: Some(parse_usage(&chunk)),
+            provider: None,
+            model: chunk.get("model").and_then(|model| model.as_str()).map(str::to_string),
+        }));
+    }
+    events
+}
+
+#[async_trait]
+impl Provider for OllamaProvider {
+    fn image_limits(&self) -> &'static ImageLimits {
+        &IMAGE_LIMITS
+    }
+
+    fn supports_image_urls(&self) -> bool {
+        false
+    }
+
+    fn vision_models(&self) -> &VisionModels {
+        &self.vision_models
+    }
+
+    fn supported_params(&self, _request: &AIRequest) -> &'static [&'static str] {
+        SUPPORTED_PARAMS
+    }
+
+    fn get_endpoint(&self, _is_multimodal: bool) -> String {
+        format!("{}/api/chat", self.base_url)
+    }
+
+    fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
+        let payload = self.prepare(request)?;
+        let response = self.send(&payload).await?;
+
+        let response_data = response.json::<serde_json::Value>().await
+            .map_err(|e| AppError::AIServiceError(format!("Parse response failed: {}", e)))?;
+
+        log::debug!("Ollama response: {:?}", response_data);
+
+        let content = response_data
+            .get("message")
+            .and_then(|message| message.get("content"))
+            .and_then(|content| content.as_str())
+            .ok_or_else(|| AppError::AIServiceError("Invalid response format".to_string()))?
+            .to_string();
+
+        Ok(AIResponse {
+            content,
+            model: response_data.get("model").and_then(|model| model.as_str()).map(str::to_string),
+            finish_reason: response_data.get("done_reason")
+                .and_then(|reason| reason.as_str())
+                .map(str::to_string),
+            usage: Some(parse_usage(&response_data)),
+            raw_response: Some(response_data),
+            ..Default::default()
+        })
+    }
+
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError> {
+        let mut payload = self.prepare(request)?;
+        payload["stream"] = json!(true);
+        let response = self.send(&payload).await?;
+
+        let events = response_lines("Ollama", response).flat_map(|line| {
+            let events = match line {
+                Ok(line) => parse_stream_line(&line),
+                Err(e) => vec![Err(e)],
+            };
+            futures::stream::iter(events)
+        });
+
+        Ok(Box::pin(events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ai::{AIInput, ChatRole, GenerationParams};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use image::{DynamicImage, ImageFormat};
+    use std::io::Cursor;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn encoded_image(format: ImageFormat) -> Vec<u8> {
+        let mut buffer = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(8, 8).write_to(&mut buffer, format).unwrap();
+        buffer.into_inner()
+    }
+
+    fn provider_for(server: &MockServer) -> OllamaProvider {
+        let config = HashMap::from([
+            ("API_ENDPOINT".to_string(), format!("{}/", server.uri())),
+            ("DEFAULT_MODEL".to_string(), "llama-test".to_string()),
+        ]);
+        OllamaProvider::from_provider_config(Client::new(), &config)
+    }
+
+    fn request(messages: Vec<ChatMessage>) -> AIRequest {
+        AIRequest {
+            messages,
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    fn chat_response(content: &str) -> serde_json::Value {
+        json!({
+            "model": "llama-test",
+            "created_at": "2024-01-01T00:00:00Z",
+            "message": { "role": "assistant", "content": content },
+            "done": true,
+            "prompt_eval_count": 12,
+            "eval_count": 4
+        })
+    }
+
+    #[tokio::test]
+    async fn sends_text_with_system_prompt() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({
+                "model": "llama-test",
+                "stream": false,
+                "messages": [
+                    { "role": "system", "content": "be brief" },
+                    { "role": "user", "content": "hello" }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(chat_response("hi")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(request(vec![
+                ChatMessage::text(ChatRole::System, "be brief"),
+                ChatMessage::text(ChatRole::User, "hello"),
+            ]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "hi");
+        assert_eq!(response.model.as_deref(), Some("llama-test"));
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(12), Some(4), Some(16)));
+    }
+
+    #[tokio::test]
+    async fn sends_response_schema_as_format() {
+        let schema = json!({ "type": "object", "properties": { "answer": { "type": "integer" } } });
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({ "format": schema })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(chat_response(r#"{"answer":42}"#)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        provider_for(&server)
+            .analyze(AIRequest {
+                response_schema: Some(schema.clone()),
+                ..request(vec![ChatMessage::text(ChatRole::User, "6 times 7?")])
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sends_images_as_plain_base64() {
+        let server = MockServer::start().await;
+        let image = encoded_image(ImageFormat::Jpeg);
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({
+                "model": "llava",
+                "messages": [{
+                    "role": "user",
+                    "content": "describe",
+                    "images": [STANDARD.encode(&image)]
+                }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(chat_response("a photo")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(AIRequest {
+                model: Some("llava".to_string()),
+                ..request(vec![AIInput::ImageWithText { image, text: "describe".to_string() }.into_message(None)])
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "a photo");
+    }
+
+    #[tokio::test]
+    async fn sends_generation_params_as_options() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({
+                "options": { "temperature": 0.5, "num_predict": 128, "seed": 1 }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(chat_response("ok")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(AIRequest {
+                params: GenerationParams {
+                    temperature: Some(0.5),
+                    max_tokens: Some(128),
+                    seed: Some(1),
+                    ..Default::default()
+                },
+                ..request(vec![ChatMessage::text(ChatRole::User, "hello")])
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "ok");
+    }
+
+    #[tokio::test]
+    async fn maps_missing_model_to_invalid_request() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .respond_with(
+                ResponseTemplate::new(404).set_body_json(json!({ "error": "model 'nope' not found" })),
+            )
+            .mount(&server)
+            .await;
+
+        let result = provider_for(&server)
+            .analyze(AIRequest {
+                model: Some("nope".to_string()),
+                ..request(vec![ChatMessage::text(ChatRole::User, "hello")])
+            })
+            .await;
+
+        assert!(matches!(result, Err(AppError::AIInvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn streams_ndjson_chunks() {
+        let server = MockServer::start().await;
+        let body = [
+            json!({ "message": { "role": "assistant", "content": "Hi" }, "done": false }),
+            json!({ "message": { "role": "assistant", "content": " there" }, "done": false }),
+            json!({
+                "message": { "role": "assistant", "content": "" },
+                "done": true,
+                "done_reason": "stop",
+                "prompt_eval_count": 12,
+                "eval_count": 4
+            }),
+        ]
+        .map(|chunk| format!("{}\n", chunk))
+        .concat();
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .and(body_partial_json(json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/x-ndjson"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let events: Vec<StreamEvent> = provider_for(&server)
+            .analyze_stream(request(vec![ChatMessage::text(ChatRole::User, "hello")]))
+            .await
+            .unwrap()
+            .map(|event| event.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[1], StreamEvent::Delta { content } if content == " there"));
+        assert!(matches!(
+            &events[2],
+            StreamEvent::Done { finish_reason: Some(reason), .. } if reason == "stop"
+        ));
+    }
+}
@@ -0,0 +1,928 @@
+use super::{
+    ensure_params_supported, error_from_request, error_from_response, header_string, openai_tool_calls,
+    openai_tools, ordered_embeddings, parse_openai_tool_calls, parse_stream_json, sse_messages, usage_field, AIStream,
+    EmbeddingModels, Provider, VisionModels,
+};
+use crate::errors::AppError;
+use crate::models::ai::{AIRequest, AIResponse, ChatMessage, ContentPart, EmbeddingBatch, StreamEvent, TokenUsage};
+use crate::service::image_processing::{ImageKind, ImageLimits};
+use reqwest::Client;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde_json::json;
+use std::collections::HashMap;
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_MODEL: &str = "gpt-4o-mini";
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+// OpenAI 单次最多 2048 条输入
+const EMBEDDING_BATCH_SIZE: usize = 2048;
+// 高精度模式下图片会被缩放到 2048x2048 以内，提前缩小可减少上传体积
+const IMAGE_LIMITS: ImageLimits = ImageLimits {
+    formats: &[ImageKind::Jpeg, ImageKind::Png, ImageKind::Gif, ImageKind::WebP],
+    max_bytes: 20 * 1024 * 1024,
+    max_dimension: 2048,
+    max_pixels: 2048 * 2048,
+};
+// llama.cpp server 的 OpenAI 兼容接口，忽略 model 字段
+const LLAMA_CPP_BASE_URL: &str = "http://localhost:8080/v1";
+const LLAMA_CPP_MODEL: &str = "default";
+// llama.cpp server 需以 --embeddings 启动，一批输入受其 batch 大小限制
+const LLAMA_CPP_EMBEDDING_BATCH_SIZE: usize = 32;
+// 支持图片输入的 OpenAI 模型；兼容服务通过 VISION_MODELS 配置
+const VISION_MODELS: &[&str] = &["gpt-4o*", "chatgpt-4o*", "gpt-4.1*", "gpt-4-turbo*", "gpt-5*", "o1*", "o3*", "o4*"];
+const SUPPORTED_PARAMS: &[&str] = &["temperature", "top_p", "max_tokens", "stop", "seed"];
+// 推理模型不接受 max_tokens，输出上限改用 max_completion_tokens
+const MAX_COMPLETION_TOKENS_MODELS: &[&str] = &["o1", "o3", "o4", "gpt-5"];
+
+fn max_tokens_field(model: &str) -> &'static str {
+    let uses_completion_tokens = MAX_COMPLETION_TOKENS_MODELS
+        .iter()
+        .any(|family| model == *family || model.starts_with(&format!("{}-", family)));
+    if uses_completion_tokens {
+        "max_completion_tokens"
+    } else {
+        "max_tokens"
+    }
+}
+
+// 结构化输出使用的 response_format；DeepSeek 等兼容服务只接受 json_object，
+// 此时 Schema 只通过 structured::add_instruction 写入的提示词传达
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseFormat {
+    JsonSchema,
+    JsonObject,
+}
+
+impl ResponseFormat {
+    // RESPONSE_FORMAT 为 json_schema（默认）或 json_object
+    fn from_provider_config(provider_config: &HashMap<String, String>) -> Result<Self, AppError> {
+        match provider_config.get("RESPONSE_FORMAT").map(String::as_str) {
+            None | Some("json_schema") => Ok(ResponseFormat::JsonSchema),
+            Some("json_object") => Ok(ResponseFormat::JsonObject),
+            Some(other) => Err(AppError::ConfigError(format!("无效的 RESPONSE_FORMAT: {}", other))),
+        }
+    }
+}
+
+// OpenAI Chat Completions 接口，也适用于 vLLM、LM Studio、DeepSeek 等兼容服务
+pub struct OpenAIProvider {
+    client: Client,
+    api_key: Option<String>,
+    organization: Option<String>,
+    base_url: String,
+    default_model: String,
+    // llama.cpp server 通常离线运行，不下载图片 URL
+    image_urls: bool,
+    response_format: ResponseFormat,
+    vision_models: VisionModels,
+    embedding_models: EmbeddingModels,
+}
+
+impl OpenAIProvider {
+    pub fn from_provider_config(client: Client, provider_config: &HashMap<String, String>) -> Result<Self, AppError> {
+        Self::with_defaults(
+            client,
+            provider_config,
+            DEFAULT_BASE_URL,
+            DEFAULT_MODEL,
+            VISION_MODELS,
+            EmbeddingModels::from_provider_config(provider_config, DEFAULT_EMBEDDING_MODEL, EMBEDDING_BATCH_SIZE)?,
+        )
+    }
+
+    // llama.cpp server 本地运行，未配置地址时使用默认值
+    pub fn llama_cpp(client: Client, provider_config: &HashMap<String, String>) -> Result<Self, AppError> {
+        let embedding_models =
+            EmbeddingModels::from_provider_config(provider_config, LLAMA_CPP_MODEL, LLAMA_CPP_EMBEDDING_BATCH_SIZE)?;
+        // 是否支持图片取决于加载的模型，不按模型名限制
+        Ok(Self {
+            image_urls: false,
+            ..Self::with_defaults(client, provider_config, LLAMA_CPP_BASE_URL, LLAMA_CPP_MODEL, &["*"], embedding_models)?
+        })
+    }
+
+    // 兼容服务通常不校验 API_KEY，因此允许为空
+    fn with_defaults(
+        client: Client,
+        provider_config: &HashMap<String, String>,
+        base_url: &str,
+        model: &str,
+        vision_models: &[&str],
+        embedding_models: EmbeddingModels,
+    ) -> Result<Self, AppError> {
+        let default_model = provider_config.get("DEFAULT_MODEL")
+            .cloned()
+            .unwrap_or_else(|| model.to_string());
+        Ok(Self {
+            client,
+            api_key: provider_config.get("API_KEY").cloned(),
+            organization: provider_config.get("ORGANIZATION").cloned(),
+            base_url: provider_config.get("API_ENDPOINT")
+                .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| base_url.to_string()),
+            vision_models: VisionModels::from_provider_config(provider_config, &default_model, vision_models),
+            embedding_models,
+            default_model,
+            image_urls: true,
+            response_format: ResponseFormat::from_provider_config(provider_config)?,
+        })
+    }
+
+    // 纯文本消息直接使用字符串，带图片时使用 content parts
+    fn message(&self, message: ChatMessage) -> Result<serde_json::Value, AppError> {
+        let mut value = if message.has_images() {
+            let content = message.content
+                .into_iter()
+                .map(|part| self.content_part(part))
+                .collect::<Result<Vec<_>, _>>()?;
+            json!({ "role": message.role.as_str(), "content": content })
+        } else {
+            json!({ "role": message.role.as_str(), "content": message.text_content() })
+        };
+        if !message.tool_calls.is_empty() {
+            value["tool_calls"] = openai_tool_calls(&message.tool_calls);
+            // 只有工具调用时 content 为 null
+            if value["content"] == json!("") {
+                value["content"] = serde_json::Value::Null;
+            }
+        }
+        if let Some(tool_call_id) = message.tool_call_id {
+            value["tool_call_id"] = json!(tool_call_id);
+        }
+        Ok(value)
+    }
+
+    fn content_part(&self, part: ContentPart) -> Result<serde_json::Value, AppError> {
+        Ok(match part {
+            ContentPart::Text { text } => json!({ "type": "text", "text": text }),
+            ContentPart::Image { data } => json!({ "type": "image_url", "image_url": { "url": self.image_url(data)? } }),
+            ContentPart::ImageUrl { url } => json!({ "type": "image_url", "image_url": { "url": url } }),
+        })
+    }
+
+    fn image_url(&self, image_data: Vec<u8>) -> Result<String, AppError> {
+        Ok(self.process_image(image_data)?.data_url())
+    }
+
+    fn prepare(&self, request: AIRequest) -> Result<serde_json::Value, AppError> {
+        ensure_params_supported("OpenAI", &request.params, self.supported_params(&request))?;
+        let model = self.request_model(&request);
+        let messages = request.messages
+            .into_iter()
+            .map(|message| self.message(message))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut payload = json!({
+            "model": model,
+            "messages": messages
+        });
+        if !request.tools.is_empty() {
+            payload["tools"] = openai_tools(&request.tools);
+        }
+        if let Some(schema) = request.response_schema {
+            payload["response_format"] = match self.response_format {
+                ResponseFormat::JsonSchema => json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema }
+                }),
+                ResponseFormat::JsonObject => json!({ "type": "json_object" }),
+            };
+        }
+        let params = request.params;
+        if let Some(temperature) = params.temperature {
+            payload["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = params.top_p {
+            payload["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            payload[max_tokens_field(&model)] = json!(max_tokens);
+        }
+        if let Some(stop) = params.stop {
+            payload["stop"] = json!(stop);
+        }
+        if let Some(seed) = params.seed {
+            payload["seed"] = json!(seed);
+        }
+        Ok(payload)
+    }
+
+    async fn send(&self, endpoint: String, payload: &serde_json::Value) -> Result<reqwest::Response, AppError> {
+        log::debug!("Sending request to OpenAI-compatible API: {}", endpoint);
+
+        let mut builder = self.client
+            .post(endpoint)
+            .header("Content-Type", "application/json")
+            .json(payload);
+        if let Some(ref api_key) = self.api_key {
+            builder = builder.header("Authorization", format!("Bearer {}", api_key));
+        }
+        if let Some(ref organization) = self.organization {
+            builder = builder.header("OpenAI-Organization", organization);
+        }
+
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| error_from_request("OpenAI", e))?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response("OpenAI", response).await);
+        }
+        Ok(response)
+    }
+}
+
+fn parse_usage(usage: &serde_json::Value) -> TokenUsage {
+    TokenUsage {
+        input_tokens: usage_field(usage, "/prompt_tokens"),
+        output_tokens: usage_field(usage, "/completion_tokens"),
+        image_tokens: None,
+        total_tokens: usage_field(usage, "/total_tokens"),
+    }
+    .with_total()
+}
+
+// 流式响应中 finish_reason 与 usage 分别出现在不同的 chunk，收到 [DONE] 时一并返回
+#[derive(Default)]
+struct StreamState {
+    finish_reason: Option<String>,
+    usage: Option<TokenUsage>,
+    model: Option<String>,
+}
+
+impl StreamState {
+    fn handle(&mut self, data: &str) -> Vec<Result<StreamEvent, AppError>> {
+        if data.trim() == "[DONE]" {
+            return vec![Ok(StreamEvent::Done {
+                finish_reason: self.finish_reason.take(),
+                usage: self.usage.take(),
+                provider: None,
+                model: self.model.take(),
+            })];
+        }
+
+        let chunk = match parse_stream_json("OpenAI", data) {
+            Ok(chunk) => chunk,
+            Err(e) => return vec![Err(e)],
+        };
+        if let Some(error) = chunk.get("error") {
+            return vec![Err(AppError::AIServiceError(format!("OpenAI stream error: {}", error)))];
+        }
+        if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+            self.usage = Some(parse_usage(usage));
+        }
+        if let Some(model) = chunk.get("model").and_then(|model| model.as_str()) {
+            self.model = Some(model.to_string());
+        }
+
+        let choice = chunk.get("choices").and_then(|choices| choices.get(0));
+        if let Some(reason) = choice
+            .and_then(|choice| choice.get("finish_reason"))
+            .and_then(|reason| reason.as_str())
+        {
+            self.finish_reason = Some(reason.to_string());
+        }
+
+        choice
+            .and_then(|choice| choice.get("delta"))
+            .and_then(|delta| delta.get("content"))
+            .and_then(|content| content.as_str())
+            .filter(|content| !content.is_empty())
+            .map(|content| vec![Ok(StreamEvent::Delta { content: content.to_string() })])
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Provider for OpenAIProvider {
+    fn image_limits(&self) -> &'static ImageLimits {
+        &IMAGE_LIMITS
+    }
+
+    fn supports_image_urls(&self) -> bool {
+        self.image_urls
+    }
+
+    fn vision_models(&self) -> &VisionModels {
+        &self.vision_models
+    }
+
+    fn supported_params(&self, _request: &AIRequest) -> &'static [&'static str] {
+        SUPPORTED_PARAMS
+    }
+
+    fn get_endpoint(&self, _is_multimodal: bool) -> String {
+        format!("{}/chat/completions", self.base_url)
+    }
+
+    fn default_model(&self) -> &str {
+        &self.default_model
+    }
+
+    async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
+        let payload = self.prepare(request)?;
+        let response = self.send(self.get_endpoint(false), &payload).await?;
+        let request_id = header_string(response.headers(), "x-request-id");
+
+        let response_data = response.json::<serde_json::Value>().await
+            .map_err(|e| AppError::AIServiceError(format!("Parse response failed: {}", e)))?;
+
+        log::debug!("OpenAI-compatible API response: {:?}", response_data);
+
+        let choice = response_data.get("choices").and_then(|choices| choices.get(0));
+        let message = choice
+            .and_then(|choice| choice.get("message"))
+            .ok_or_else(|| AppError::AIServiceError("Invalid response format".to_string()))?;
+        let tool_calls = parse_openai_tool_calls(Some(message));
+        // 只调用工具时 content 为 null
+        let content = match message.get("content").and_then(|content| content.as_str()) {
+            Some(content) => content.to_string(),
+            None if !tool_calls.is_empty() => String::new(),
+            None => return Err(AppError::AIServiceError("Invalid response format".to_string())),
+        };
+
+        Ok(AIResponse {
+            content,
+            model: response_data.get("model").or(payload.get("model"))
+                .and_then(|model| model.as_str())
+                .map(str::to_string),
+            finish_reason: choice
+                .and_then(|choice| choice.get("finish_reason"))
+                .and_then(|reason| reason.as_str())
+                .map(str::to_string),
+            usage: response_data.get("usage").map(parse_usage),
+            // 兼容服务不一定返回 x-request-id，此时使用响应体中的 id
+            request_id: request_id.or_else(|| {
+                response_data.get("id").and_then(|id| id.as_str()).map(str::to_string)
+            }),
+            tool_calls,
+            raw_response: Some(response_data),
+            ..Default::default()
+        })
+    }
+
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError> {
+        let mut payload = self.prepare(request)?;
+        payload["stream"] = json!(true);
+        payload["stream_options"] = json!({ "include_usage": true });
+        let response = self.send(self.get_endpoint(false), &payload).await?;
+
+        let events = sse_messages("OpenAI", response)
+            .scan(StreamState::default(), |state, message| {
+                let events = match message {
+                    Ok(message) => state.handle(&message.data),
+                    Err(e) => vec![Err(e)],
+                };
+                futures::future::ready(Some(futures::stream::iter(events)))
+            })
+            .flatten();
+
+        Ok(Box::pin(events))
+    }
+
+    fn embedding_models(&self) -> Option<&EmbeddingModels> {
+        Some(&self.embedding_models)
+    }
+
+    async fn embed(&self, model: &str, inputs: Vec<String>) -> Result<EmbeddingBatch, AppError> {
+        let count = inputs.len();
+        let payload = json!({ "model": model, "input": inputs, "encoding_format": "float" });
+        let response = self.send(format!("{}/embeddings", self.base_url), &payload).await?;
+        let response_data = response.json::<serde_json::Value>().await
+            .map_err(|e| AppError::AIServiceError(format!("Parse response failed: {}", e)))?;
+
+        Ok(EmbeddingBatch {
+            embeddings: ordered_embeddings("OpenAI", response_data.get("data"), "index", count)?,
+            usage: response_data.get("usage").map(parse_usage),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ai::{AIInput, ChatRole, GenerationParams, ToolCall, ToolDefinition};
+    use crate::service::image_processing::encode_png;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use image::DynamicImage;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn png_image(width: u32, height: u32) -> Vec<u8> {
+        encode_png(&DynamicImage::new_rgb8(width, height)).unwrap()
+    }
+
+    fn provider_for(server: &MockServer) -> OpenAIProvider {
+        let config = HashMap::from([
+            ("API_KEY".to_string(), "test-key".to_string()),
+            ("API_ENDPOINT".to_string(), format!("{}/v1", server.uri())),
+            ("ORGANIZATION".to_string(), "org-test".to_string()),
+            ("DEFAULT_MODEL".to_string(), "gpt-test".to_string()),
+        ]);
+        OpenAIProvider::from_provider_config(Client::new(), &config).unwrap()
+    }
+
+    fn request(messages: Vec<ChatMessage>) -> AIRequest {
+        AIRequest {
+            messages,
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    fn completion(content: &str) -> serde_json::Value {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 3, "total_tokens": 8 }
+        })
+    }
+
+    #[tokio::test]
+    async fn sends_text_request_with_default_model() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("Authorization", "Bearer test-key"))
+            .and(header("OpenAI-Organization", "org-test"))
+            .and(body_partial_json(json!({
+                "model": "gpt-test",
+                "messages": [{ "role": "user", "content": "hello" }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion("hi there")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(request(vec![ChatMessage::text(ChatRole::User, "hello")]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "hi there");
+        assert_eq!(response.finish_reason.as_deref(), Some("stop"));
+        assert_eq!(response.request_id.as_deref(), Some("chatcmpl-1"));
+        assert_eq!(response.model.as_deref(), Some("gpt-test"));
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(5), Some(3), Some(8)));
+        assert!(response.raw_response.is_some());
+    }
+
+    #[tokio::test]
+    async fn uses_max_completion_tokens_for_reasoning_models() {
+        let server = MockServer::start().await;
+        let provider = provider_for(&server);
+        let reasoning_request = |model: &str| AIRequest {
+            model: Some(model.to_string()),
+            params: GenerationParams { max_tokens: Some(64), ..GenerationParams::default() },
+            ..request(vec![ChatMessage::text(ChatRole::User, "hello")])
+        };
+
+        for model in ["o1", "o3-mini", "o4-mini-2025-04-16", "gpt-5", "gpt-5-nano"] {
+            let payload = provider.prepare(reasoning_request(model)).unwrap();
+            assert_eq!(payload["max_completion_tokens"], json!(64), "{}", model);
+            assert!(payload.get("max_tokens").is_none(), "{}", model);
+        }
+        for model in ["gpt-4o", "gpt-4.1-mini", "o1x", "gpt-50"] {
+            let payload = provider.prepare(reasoning_request(model)).unwrap();
+            assert_eq!(payload["max_tokens"], json!(64), "{}", model);
+            assert!(payload.get("max_completion_tokens").is_none(), "{}", model);
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_image_as_data_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({ "model": "gpt-4o" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion("a cat")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let image = png_image(4, 4);
+
+        let response = provider_for(&server)
+            .analyze(AIRequest {
+                model: Some("gpt-4o".to_string()),
+                ..request(vec![AIInput::Image(image.clone()).into_message(Some("what is this?".to_string()))])
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.content, "a cat");
+
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        let content = &body["messages"][0]["content"];
+        assert_eq!(
+            content[0]["image_url"]["url"],
+            format!("data:image/png;base64,{}", STANDARD.encode(&image))
+        );
+        assert_eq!(content[1], json!({ "type": "text", "text": "what is this?" }));
+    }
+
+    #[tokio::test]
+    async fn uses_vision_model_for_images_without_model() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({ "model": "gpt-4o" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion("a cat")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let config = HashMap::from([
+            ("API_ENDPOINT".to_string(), format!("{}/v1", server.uri())),
+            ("DEFAULT_MODEL".to_string(), "gpt-test".to_string()),
+            ("VISION_MODEL".to_string(), "gpt-4o".to_string()),
+            ("VISION_MODELS".to_string(), "gpt-4o-*, gpt-4.1".to_string()),
+        ]);
+        let provider = OpenAIProvider::from_provider_config(Client::new(), &config).unwrap();
+        assert!(provider.vision_models().allows("gpt-4o"));
+        assert!(provider.vision_models().allows("gpt-4o-2024-08-06"));
+        assert!(provider.vision_models().allows("gpt-4.1"));
+        assert!(!provider.vision_models().allows("gpt-test"));
+
+        provider
+            .analyze(request(vec![AIInput::Image(png_image(4, 4)).into_message(None)]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_unrecognized_image_data() {
+        let server = MockServer::start().await;
+
+        let err = provider_for(&server)
+            .analyze(request(vec![AIInput::Image(b"not an image at all".to_vec()).into_message(None)]))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, AppError::ValidationError(_)));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_roles_and_part_order() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({
+                "messages": [
+                    { "role": "system", "content": "be brief" },
+                    {
+                        "role": "user",
+                        "content": [
+                            { "type": "text", "text": "compare" },
+                            { "type": "image_url", "image_url": { "url": "https://example.com/a.png" } }
+                        ]
+                    },
+                    { "role": "assistant", "content": "calling a tool" },
+                    { "role": "tool", "tool_call_id": "call_1", "content": "42" }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion("done")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(request(vec![
+                ChatMessage::text(ChatRole::System, "be brief"),
+                ChatMessage {
+                    role: ChatRole::User,
+                    content: vec![
+                        ContentPart::Text { text: "compare".to_string() },
+                        ContentPart::ImageUrl { url: "https://example.com/a.png".to_string() },
+                    ],
+                    tool_calls: Vec::new(),
+                    tool_call_id: None,
+                },
+                ChatMessage::text(ChatRole::Assistant, "calling a tool"),
+                ChatMessage { tool_call_id: Some("call_1".to_string()), ..ChatMessage::text(ChatRole::Tool, "42") },
+            ]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "done");
+    }
+
+    #[tokio::test]
+    async fn sends_tools_and_parses_tool_calls() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({
+                "tools": [{
+                    "type": "function",
+                    "function": {
+                        "name": "get_weather",
+                        "description": "look up the weather",
+                        "parameters": { "type": "object", "properties": { "city": { "type": "string" } } }
+                    }
+                }],
+                "messages": [
+                    { "role": "user", "content": "weather?" },
+                    {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_0",
+                            "type": "function",
+                            "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                        }]
+                    },
+                    { "role": "tool", "tool_call_id": "call_0", "content": "sunny" }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_1",
+                            "type": "function",
+                            "function": { "name": "get_weather", "arguments": "{\"city\":\"Berlin\"}" }
+                        }]
+                    },
+                    "finish_reason": "tool_calls"
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(AIRequest {
+                tools: vec![ToolDefinition {
+                    name: "get_weather".to_string(),
+                    description: "look up the weather".to_string(),
+                    parameters: json!({ "type": "object", "properties": { "city": { "type": "string" } } }),
+                }],
+                ..request(vec![
+                    ChatMessage::text(ChatRole::User, "weather?"),
+                    ChatMessage {
+                        role: ChatRole::Assistant,
+                        content: Vec::new(),
+                        tool_calls: vec![ToolCall::from_json_arguments("call_0".to_string(), "get_weather".to_string(), r#"{"city":"Paris"}"#)],
+                        tool_call_id: None,
+                    },
+                    ChatMessage { tool_call_id: Some("call_0".to_string()), ..ChatMessage::text(ChatRole::Tool, "sunny") },
+                ])
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "");
+        assert_eq!(response.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].id, "call_1");
+        assert_eq!(response.tool_calls[0].arguments, json!({ "city": "Berlin" }));
+    }
+
+    #[tokio::test]
+    async fn sends_response_schema_as_json_schema_format() {
+        let schema = json!({ "type": "object", "properties": { "name": { "type": "string" } }, "required": ["name"] });
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({
+                "response_format": {
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema }
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion(r#"{"name":"Ada"}"#)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(AIRequest {
+                response_schema: Some(schema.clone()),
+                ..request(vec![ChatMessage::text(ChatRole::User, "who wrote the first program?")])
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, r#"{"name":"Ada"}"#);
+    }
+
+    #[test]
+    fn json_object_format_omits_schema() {
+        let config = |format: &str| HashMap::from([("RESPONSE_FORMAT".to_string(), format.to_string())]);
+        let provider = OpenAIProvider::from_provider_config(Client::new(), &config("json_object")).unwrap();
+
+        let payload = provider
+            .prepare(AIRequest {
+                response_schema: Some(json!({ "type": "object" })),
+                ..request(vec![ChatMessage::text(ChatRole::User, "who wrote the first program?")])
+            })
+            .unwrap();
+
+        assert_eq!(payload["response_format"], json!({ "type": "json_object" }));
+        assert!(matches!(
+            OpenAIProvider::from_provider_config(Client::new(), &config("xml")),
+            Err(AppError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn embeds_inputs_in_request_order() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .and(header("Authorization", "Bearer test-key"))
+            .and(body_partial_json(json!({ "model": "text-embedding-3-small", "input": ["first", "second"] })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "object": "list",
+                "data": [
+                    { "object": "embedding", "index": 1, "embedding": [0.5, -0.25] },
+                    { "object": "embedding", "index": 0, "embedding": [1.0, 0.0] }
+                ],
+                "model": "text-embedding-3-small",
+                "usage": { "prompt_tokens": 4, "total_tokens": 4 }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let provider = provider_for(&server);
+        let models = provider.embedding_models().unwrap();
+        assert_eq!((models.default.as_str(), models.max_batch), ("text-embedding-3-small", 2048));
+
+        let batch = provider
+            .embed("text-embedding-3-small", vec!["first".to_string(), "second".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(batch.embeddings, vec![vec![1.0, 0.0], vec![0.5, -0.25]]);
+        assert_eq!(batch.usage.unwrap().input_tokens, Some(4));
+    }
+
+    #[tokio::test]
+    async fn rejects_embeddings_missing_an_input() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": [{ "index": 0, "embedding": [1.0] }]
+            })))
+            .mount(&server)
+            .await;
+
+        let err = provider_for(&server)
+            .embed("text-embedding-3-small", vec!["first".to_string(), "second".to_string()])
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, AppError::AIServiceError(_)));
+    }
+
+    #[tokio::test]
+    async fn maps_generation_params() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({
+                "temperature": 0.2,
+                "max_tokens": 64,
+                "stop": ["END"],
+                "seed": 7
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion("ok")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let provider = provider_for(&server);
+        let params = GenerationParams {
+            temperature: Some(0.2),
+            max_tokens: Some(64),
+            stop: Some(vec!["END".to_string()]),
+            seed: Some(7),
+            ..Default::default()
+        };
+        let response = provider
+            .analyze(AIRequest {
+                params,
+                ..request(vec![ChatMessage::text(ChatRole::User, "hello")])
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.content, "ok");
+
+        // 通义千问专有参数不会被静默忽略
+        let result = provider
+            .analyze(AIRequest {
+                params: GenerationParams { enable_search: Some(true), ..Default::default() },
+                ..request(vec![ChatMessage::text(ChatRole::User, "hello")])
+            })
+            .await;
+        assert!(matches!(result, Err(AppError::AIInvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn llama_cpp_server_needs_no_credentials() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({
+                "model": "default",
+                "messages": [
+                    { "role": "system", "content": "answer in English" },
+                    { "role": "user", "content": "hello" }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(completion("local answer")))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let config = HashMap::from([
+            ("API_ENDPOINT".to_string(), format!("{}/v1", server.uri())),
+        ]);
+        let provider = OpenAIProvider::llama_cpp(Client::new(), &config).unwrap();
+        let response = provider
+            .analyze(request(vec![
+                ChatMessage::text(ChatRole::System, "answer in English"),
+                ChatMessage::text(ChatRole::User, "hello"),
+            ]))
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "local answer");
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn surfaces_api_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("bad request"))
+            .mount(&server)
+            .await;
+
+        let result = provider_for(&server)
+            .analyze(request(vec![ChatMessage::text(ChatRole::User, "hello")]))
+            .await;
+
+        assert!(matches!(result, Err(AppError::AIInvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn streams_deltas_with_usage() {
+        let server = MockServer::start().await;
+        let body = [
+            r#"data: {"model":"gpt-4o-2024-08-06","choices":[{"index":0,"delta":{"role":"assistant","content":"Hel"},"finish_reason":null}]}"#,
+            r#"data: {"choices":[{"index":0,"delta":{"content":"lo"},"finish_reason":"stop"}]}"#,
+            r#"data: {"choices":[],"usage":{"prompt_tokens":3,"completion_tokens":2,"total_tokens":5}}"#,
+            "data: [DONE]",
+        ]
+        .map(|line| format!("{}\n\n", line))
+        .concat();
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let events: Vec<StreamEvent> = provider_for(&server)
+            .analyze_stream(request(vec![ChatMessage::text(ChatRole::User, "hello")]))
+            .await
+            .unwrap()
+            .map(|event| event.unwrap())
+            .collect()
+            .await;
+
+        assert!(matches!(&events[0], StreamEvent::Delta { content } if content == "Hel"));
+        assert!(matches!(&events[1], StreamEvent::Delta { content } if content == "lo"));
+        match &events[2] {
+            StreamEvent::Done { finish_reason, usage, model, .. } => {
+                assert_eq!(finish_reason.as_deref(), Some("stop"));
+                assert_eq!(model.as_deref(), Some("gpt-4o-2024-08-06"));
+                assert_eq!(usage.as_ref().unwrap().total_tokens, Some(5));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert_eq!(events.len(), 3);
+    }
+}
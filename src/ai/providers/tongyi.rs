@@ -1,78 +1,235 @@
-use super::{Provider, ImageFormat};
-use crate::config::Config;
+use super::{
+    ensure_params_supported, error_from_request, error_from_response, openai_tool_calls, openai_tools,
+    ordered_embeddings, parse_openai_tool_calls, parse_stream_json, sse_messages, usage_field, AIStream,
+    EmbeddingModels, Provider, VisionModels,
+};
 use crate::errors::AppError;
-use crate::models::ai::{AIRequest, AIResponse, AIInput};
+use crate::models::ai::{AIRequest, AIResponse, ChatMessage, ContentPart, EmbeddingBatch, StreamEvent, TokenUsage};
 use reqwest::Client;
 use async_trait::async_trait;
+use futures::StreamExt;
 use serde_json::json;
-use base64::engine::general_purpose::STANDARD;
-use base64::Engine;
+use std::collections::HashMap;
+use crate::service::image_processing::{ImageKind, ImageLimits};
+
+const DEFAULT_BASE_URL: &str = "https://dashscope.aliyuncs.com/api/v1";
+const DEFAULT_MODEL: &str = "qwen-turbo";
+const DEFAULT_VISION_MODEL: &str = "qwen-vl-max";
+const VISION_MODELS: &[&str] = &["qwen-vl-*", "qwen2-vl-*", "qwen2.5-vl-*", "qwen3-vl-*", "qvq-*"];
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-v3";
+// text-embedding-v3 单次最多 10 条，v1 和 v2 为 25 条
+const EMBEDDING_BATCH_SIZE: usize = 10;
+// base64 编码后不超过 10MB；通义千问 VL 单图最多约 1280 万像素
+const IMAGE_LIMITS: ImageLimits = ImageLimits {
+    formats: &[ImageKind::Jpeg, ImageKind::Png, ImageKind::WebP],
+    max_bytes: 7 * 1024 * 1024,
+    max_dimension: 8192,
+    max_pixels: 12_845_056,
+};
+const TEXT_PARAMS: &[&str] = &[
+    "temperature", "top_p", "max_tokens", "stop", "seed", "enable_search", "result_format",
+];
+// 多模态接口不支持联网搜索
+const MULTIMODAL_PARAMS: &[&str] = &["temperature", "top_p", "max_tokens", "stop", "seed", "result_format"];
 
 pub struct TongyiProvider {
     client: Client,
     api_key: String,
     text_endpoint: String,
     multimodal_endpoint: String,
+    embedding_endpoint: String,
+    vision_models: VisionModels,
+    embedding_models: EmbeddingModels,
 }
 
 impl TongyiProvider {
-    pub fn new(config: &Config) -> Result<Self, AppError> {
-        let provider_config = config.ai_providers.get_provider_config("tongyi")
-            .ok_or_else(|| AppError::ConfigError("Tongyi provider not configured".to_string()))?;
-
+    pub fn from_provider_config(client: Client, provider_config: &HashMap<String, String>) -> Result<Self, AppError> {
         let api_key = provider_config.get("API_KEY")
             .ok_or_else(|| AppError::ConfigError("Tongyi API_KEY not configured".to_string()))?
             .clone();
 
+        // API_ENDPOINT 为 DashScope 基础地址，如国际站 https://dashscope-intl.aliyuncs.com/api/v1
+        let base_url = provider_config.get("API_ENDPOINT")
+            .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+
         Ok(Self {
-            client: Client::new(),
+            client,
             api_key,
-            text_endpoint: "https://dashscope.aliyuncs.com/api/v1/services/aigc/text-generation/generation".to_string(),
-            multimodal_endpoint: "https://dashscope.aliyuncs.com/api/v1/services/aigc/multimodal-generation/generation".to_string(),
+            text_endpoint: format!("{}/services/aigc/text-generation/generation", base_url),
+            multimodal_endpoint: format!("{}/services/aigc/multimodal-generation/generation", base_url),
+            embedding_endpoint: format!("{}/services/embeddings/text-embedding/text-embedding", base_url),
+            vision_models: VisionModels::from_provider_config(provider_config, DEFAULT_VISION_MODEL, VISION_MODELS),
+            embedding_models: EmbeddingModels::from_provider_config(
+                provider_config,
+                DEFAULT_EMBEDDING_MODEL,
+                EMBEDDING_BATCH_SIZE,
+            )?,
         })
     }
 
-    fn build_text_payload(&self, text: &str, model: &str) -> serde_json::Value {
-        json!({
-            "model": model,
-            "input": {
-                "messages": [
-                    {
-                        "role": "user",
-                        "content": text
-                    }
-                ]
+    // 文本接口的 content 为字符串，多模态接口为 [{image}, {text}] 列表
+    // tool 消息除 tool_call_id 外还带上对应的函数名，tool_names 由之前的 assistant 消息得到
+    fn message(
+        &self,
+        message: ChatMessage,
+        multimodal: bool,
+        tool_names: &HashMap<String, String>,
+    ) -> Result<serde_json::Value, AppError> {
+        if !multimodal {
+            let mut value = json!({ "role": message.role.as_str(), "content": message.text_content() });
+            if !message.tool_calls.is_empty() {
+                value["tool_calls"] = openai_tool_calls(&message.tool_calls);
             }
-        })
+            if let Some(tool_call_id) = message.tool_call_id {
+                if let Some(name) = tool_names.get(&tool_call_id) {
+                    value["name"] = json!(name);
+                }
+                value["tool_call_id"] = json!(tool_call_id);
+            }
+            return Ok(value);
+        }
+
+        let content = message.content
+            .into_iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => Ok(json!({ "text": text })),
+                ContentPart::Image { data } => self.image_content(data).map(|image| json!({ "image": image })),
+                // DashScope 支持直接传入公网图片地址
+                ContentPart::ImageUrl { url } => Ok(json!({ "image": url })),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(json!({ "role": message.role.as_str(), "content": content }))
+    }
+
+    fn image_content(&self, image_data: Vec<u8>) -> Result<String, AppError> {
+        Ok(self.process_image(image_data)?.data_url())
     }
 
-    fn build_image_payload(&self, image: String, text: String, model: &str) -> serde_json::Value {
-        json!({
+    // 返回请求体和接口地址，任意一条消息包含图片时使用多模态接口
+    fn prepare(&self, request: AIRequest) -> Result<(serde_json::Value, String), AppError> {
+        let multimodal = request.messages.iter().any(ChatMessage::has_images);
+        ensure_params_supported("Tongyi", &request.params, self.supported_params(&request))?;
+
+        let model = self.request_model(&request);
+
+        let tool_names: HashMap<String, String> = request.messages
+            .iter()
+            .flat_map(|message| &message.tool_calls)
+            .map(|call| (call.id.clone(), call.name.clone()))
+            .collect();
+        let messages = request.messages
+            .into_iter()
+            .map(|message| self.message(message, multimodal, &tool_names))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut payload = json!({
             "model": model,
             "input": {
-                "messages": [
-                    {
-                        "role": "user",
-                        "content": [
-                            { "image": image },
-                            { "text": text }
-                        ]
-                    }
-                ]
+                "messages": messages
             }
-        })
+        });
+        // GenerationParams 的字段名与 DashScope parameters 一致
+        let mut parameters = serde_json::to_value(&request.params)
+            .map_err(|e| AppError::InternalError(format!("序列化生成参数失败: {}", e)))?;
+        // 工具调用只支持文本接口，结果以 message 格式返回
+        if !request.tools.is_empty() {
+            if multimodal {
+                return Err(AppError::AIInvalidRequest("通义千问多模态接口不支持工具调用".to_string()));
+            }
+            if request.params.result_format.as_deref() == Some("text") {
+                return Err(AppError::AIInvalidRequest("工具调用需要 result_format 为 message".to_string()));
+            }
+            parameters["tools"] = openai_tools(&request.tools);
+            parameters["result_format"] = json!("message");
+        }
+        // JSON 模式只支持文本接口，不接受 Schema，Schema 由服务层写入提示词
+        if request.response_schema.is_some() && !multimodal {
+            parameters["response_format"] = json!({ "type": "json_object" });
+        }
+        if parameters.as_object().is_some_and(|parameters| !parameters.is_empty()) {
+            payload["parameters"] = parameters;
+        }
+        Ok((payload, self.get_endpoint(multimodal)))
+    }
+
+    async fn send(&self, payload: &serde_json::Value, endpoint: String, stream: bool) -> Result<reqwest::Response, AppError> {
+        log::debug!("Sending request to Tongyi API: {:?}", payload);
+
+        let mut builder = self.client
+            .post(endpoint)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(payload);
+        if stream {
+            builder = builder.header("X-DashScope-SSE", "enable");
+        }
+
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| error_from_request("Tongyi", e))?;
+
+        if !response.status().is_success() {
+            return Err(error_from_response("Tongyi", response).await);
+        }
+        Ok(response)
+    }
+}
+
+// 文本接口默认返回 output.text；多模态接口以及 result_format 为 message 时
+// 返回 output.choices[0].message.content，其中多模态为 [{text}] 列表
+fn extract_text(response_data: &serde_json::Value) -> Option<&str> {
+    let output = response_data.get("output")?;
+    if let Some(text) = output.get("text").and_then(|text| text.as_str()) {
+        return Some(text);
+    }
+    let content = output.pointer("/choices/0/message/content")?;
+    content
+        .as_str()
+        .or_else(|| content.pointer("/0/text").and_then(|text| text.as_str()))
+}
+
+fn extract_finish_reason(response_data: &serde_json::Value) -> Option<String> {
+    let output = response_data.get("output")?;
+    let reason = output
+        .get("finish_reason")
+        .or_else(|| output.pointer("/choices/0/finish_reason"));
+    // 未结束时 DashScope 返回字符串 "null"
+    reason
+        .and_then(|reason| reason.as_str())
+        .filter(|reason| !reason.is_empty() && *reason != "null")
+        .map(str::to_string)
+}
+
+// 多模态模型在 usage 或 input_tokens_details 中返回图片 token
+fn parse_usage(usage: &serde_json::Value) -> TokenUsage {
+    TokenUsage {
+        input_tokens: usage_field(usage, "/input_tokens"),
+        output_tokens: usage_field(usage, "/output_tokens"),
+        image_tokens: usage_field(usage, "/image_tokens")
+            .or_else(|| usage_field(usage, "/input_tokens_details/image_tokens")),
+        total_tokens: usage_field(usage, "/total_tokens"),
     }
+    .with_total()
 }
 
 #[async_trait]
 impl Provider for TongyiProvider {
-    fn process_image(&self, image_data: Vec<u8>) -> Result<ImageFormat, AppError> {
-        log::debug!("Processing image with size: {} bytes", image_data.len());
-        if image_data.len() < 100 {
-            return Err(AppError::ValidationError("Invalid image data".to_string()));
+    fn image_limits(&self) -> &'static ImageLimits {
+        &IMAGE_LIMITS
+    }
+
+    fn vision_models(&self) -> &VisionModels {
+        &self.vision_models
+    }
+
+    fn supported_params(&self, request: &AIRequest) -> &'static [&'static str] {
+        if request.messages.iter().any(ChatMessage::has_images) {
+            MULTIMODAL_PARAMS
+        } else {
+            TEXT_PARAMS
         }
-        let base64 = STANDARD.encode(image_data);
-        Ok(ImageFormat::Base64(format!("data:image/jpeg;base64,{}", base64)))
     }
 
     fn get_endpoint(&self, is_multimodal: bool) -> String {
@@ -83,86 +240,335 @@ impl Provider for TongyiProvider {
         }
     }
 
-    async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
-        // 保存输入类型
-        let is_text = matches!(&request.input, AIInput::Text(_));
-
-        let (_model, payload, endpoint) = match request.input {
-            AIInput::Text(text) => {
-                let model = request.model.unwrap_or_else(|| "qwen-turbo".to_string());
-                let payload = self.build_text_payload(&text, &model);
-                (model, payload, self.get_endpoint(false))
-            },
-            AIInput::Image(image_data) => {
-                let model = String::from("qwen-vl-max");
-                let processed_image = self.process_image(image_data)?;
-                let image_content = match processed_image {
-                    ImageFormat::Base64(base64) => base64,
-                    _ => return Err(AppError::AIServiceError("Unsupported image format".to_string())),
-                };
-                let text = request.prompt.unwrap_or_else(|| "请分析这张图片".to_string());
-                let payload = self.build_image_payload(image_content, text, &model);
-                (model, payload, self.get_endpoint(true))
-            },
-            AIInput::ImageWithText { image, text } => {
-                let model = String::from("qwen-vl-max");
-                let processed_image = self.process_image(image)?;
-                let image_content = match processed_image {
-                    ImageFormat::Base64(base64) => base64,
-                    _ => return Err(AppError::AIServiceError("Unsupported image format".to_string())),
-                };
-                let prompt = request.prompt.unwrap_or_else(|| text);
-                let payload = self.build_image_payload(image_content, prompt, &model);
-                (model, payload, self.get_endpoint(true))
-            }
-        };
-
-        log::debug!("Sending request to Tongyi API: {:?}", payload);
-
-        let response = self.client
-            .post(endpoint)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .header("Content-Type", "application/json")
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| AppError::AIServiceError(format!("Request failed: {}", e)))?;
+    fn default_model(&self) -> &str {
+        DEFAULT_MODEL
+    }
 
-        if !response.status().is_success() {
-            let error = response.text().await.unwrap_or_default();
-            return Err(AppError::AIServiceError(format!("API error: {}", error)));
-        }
+    async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
+        let (payload, endpoint) = self.prepare(request)?;
+        let response = self.send(&payload, endpoint, false).await?;
 
         let response_data = response.json::<serde_json::Value>().await
             .map_err(|e| AppError::AIServiceError(format!("Parse response failed: {}", e)))?;
 
         log::debug!("Tongyi API response: {:?}", response_data);
 
-        let content = if is_text {
-            response_data
-                .get("output")
-                .and_then(|output| output.get("text"))
-                .and_then(|text| text.as_str())
-                .ok_or_else(|| AppError::AIServiceError("Invalid response format".to_string()))?
-                .to_string()
-        } else {
-            response_data
-                .get("output")
-                .and_then(|output| output.get("choices"))
-                .and_then(|choices| choices.get(0))
-                .and_then(|choice| choice.get("message"))
-                .and_then(|message| message.get("content"))
-                .and_then(|content| content.get(0))
-                .and_then(|first| first.get("text"))
-                .and_then(|text| text.as_str())
-                .ok_or_else(|| AppError::AIServiceError("Invalid response format".to_string()))?
-                .to_string()
+        let tool_calls = parse_openai_tool_calls(response_data.pointer("/output/choices/0/message"));
+        let content = match extract_text(&response_data) {
+            Some(content) => content.to_string(),
+            None if !tool_calls.is_empty() => String::new(),
+            None => return Err(AppError::AIServiceError("Invalid response format".to_string())),
         };
 
         Ok(AIResponse {
             content,
-            confidence: None,
+            model: payload.get("model").and_then(|model| model.as_str()).map(str::to_string),
+            finish_reason: extract_finish_reason(&response_data),
+            usage: response_data.get("usage").map(parse_usage),
+            request_id: response_data.get("request_id")
+                .and_then(|id| id.as_str())
+                .map(str::to_string),
+            tool_calls,
             raw_response: Some(response_data),
+            ..Default::default()
         })
     }
-}
\ No newline at end of file
+
+    // DashScope SSE：incremental_output 使每个事件只包含新增内容
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError> {
+        let (mut payload, endpoint) = self.prepare(request)?;
+        payload["parameters"]["incremental_output"] = json!(true);
+        let response = self.send(&payload, endpoint, true).await?;
+
+        let events = sse_messages("Tongyi", response).flat_map(|message| {
+            let parsed = message.and_then(|message| {
+                let is_error = message.event.as_deref() == Some("error");
+                parse_stream_json("Tongyi", &message.data).map(|data| (is_error, data))
+            });
+            let events = match parsed {
+                Ok((is_error, data)) => {
+                    let mut events = Vec::new();
+                    // 出错时 DashScope 发送 event:error，data 中带有 code 和 message
+                    if is_error || data.get("code").and_then(|code| code.as_str()).is_some() {
+                        let code = data.get("code").and_then(|code| code.as_str()).unwrap_or("unknown");
+                        events.push(Err(AppError::AIServiceError(format!("Tongyi stream error {}: {}", code, data))));
+                    } else {
+                        if let Some(text) = extract_text(&data).filter(|text| !text.is_empty()) {
+                            events.push(Ok(StreamEvent::Delta { content: text.to_string() }));
+                        }
+                        if let Some(finish_reason) = extract_finish_reason(&data) {
+                            events.push(Ok(StreamEvent::Done {
+                                finish_reason: Some(finish_reason),
+                                usage: data.get("usage").map(parse_usage),
+                                provider: None,
+                                model: None,
+                            }));
+                        }
+                    }
+                    events
+                }
+                Err(e) => vec![Err(e)],
+            };
+            futures::stream::iter(events)
+        });
+
+        Ok(Box::pin(events))
+    }
+
+    fn embedding_models(&self) -> Option<&EmbeddingModels> {
+        Some(&self.embedding_models)
+    }
+
+    async fn embed(&self, model: &str, inputs: Vec<String>) -> Result<EmbeddingBatch, AppError> {
+        let count = inputs.len();
+        let payload = json!({ "model": model, "input": { "texts": inputs } });
+        let response = self.send(&payload, self.embedding_endpoint.clone(), false).await?;
+        let response_data = response.json::<serde_json::Value>().await
+            .map_err(|e| AppError::AIServiceError(format!("Parse response failed: {}", e)))?;
+
+        // 向量嵌入只返回 total_tokens，全部来自输入
+        let tokens = usage_field(&response_data, "/usage/total_tokens");
+        Ok(EmbeddingBatch {
+            embeddings: ordered_embeddings("Tongyi", response_data.pointer("/output/embeddings"), "text_index", count)?,
+            usage: tokens.map(|tokens| TokenUsage {
+                input_tokens: Some(tokens),
+                total_tokens: Some(tokens),
+                ..Default::default()
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ai::{ChatRole, GenerationParams, ToolCall, ToolDefinition};
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const TEXT_PATH: &str = "/api/v1/services/aigc/text-generation/generation";
+
+    fn provider_for(server: &MockServer) -> TongyiProvider {
+        let config = HashMap::from([
+            ("API_KEY".to_string(), "test-key".to_string()),
+            ("API_ENDPOINT".to_string(), format!("{}/api/v1", server.uri())),
+        ]);
+        TongyiProvider::from_provider_config(Client::new(), &config).unwrap()
+    }
+
+    fn request(text: &str) -> AIRequest {
+        AIRequest {
+            messages: vec![ChatMessage::text(ChatRole::User, text)],
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    // DashScope SSE 的消息格式：id、event、HTTP 状态注释行和 data
+    fn sse_body(events: &[(&str, &str)]) -> String {
+        events
+            .iter()
+            .enumerate()
+            .map(|(index, (event, data))| {
+                let status = if *event == "error" { 400 } else { 200 };
+                format!("id:{}\nevent:{}\n:HTTP_STATUS/{}\ndata:{}\n\n", index + 1, event, status, data)
+            })
+            .collect()
+    }
+
+    async fn stream_events(server: &MockServer, body: String) -> Vec<Result<StreamEvent, AppError>> {
+        Mock::given(method("POST"))
+            .and(path(TEXT_PATH))
+            .and(header("X-DashScope-SSE", "enable"))
+            .and(body_partial_json(json!({ "parameters": { "incremental_output": true } })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .expect(1)
+            .mount(server)
+            .await;
+
+        provider_for(server).analyze_stream(request("hi")).await.unwrap().collect().await
+    }
+
+    #[test]
+    fn parses_text_and_vision_usage() {
+        let text = parse_usage(&json!({ "input_tokens": 13, "output_tokens": 40, "total_tokens": 53 }));
+        assert_eq!(
+            (text.input_tokens, text.output_tokens, text.image_tokens, text.total_tokens),
+            (Some(13), Some(40), None, Some(53))
+        );
+
+        // 多模态接口的图片 token 在 input_tokens_details 中，未返回 total_tokens 时自行求和
+        let vision = parse_usage(&json!({
+            "input_tokens": 152,
+            "output_tokens": 106,
+            "input_tokens_details": { "text_tokens": 24, "image_tokens": 128 }
+        }));
+        assert_eq!(
+            (vision.input_tokens, vision.output_tokens, vision.image_tokens, vision.total_tokens),
+            (Some(152), Some(106), Some(128), Some(258))
+        );
+
+        let qwen_vl = parse_usage(&json!({ "input_tokens": 30, "output_tokens": 5, "image_tokens": 20 }));
+        assert_eq!((qwen_vl.image_tokens, qwen_vl.total_tokens), (Some(20), Some(35)));
+    }
+
+    #[tokio::test]
+    async fn streams_incremental_output() {
+        let server = MockServer::start().await;
+        let body = sse_body(&[
+            ("result", r#"{"output":{"finish_reason":"null","text":"Hel"},"usage":{"input_tokens":8,"output_tokens":1,"total_tokens":9},"request_id":"r1"}"#),
+            ("result", r#"{"output":{"finish_reason":"null","text":""},"usage":{"input_tokens":8,"output_tokens":1,"total_tokens":9},"request_id":"r1"}"#),
+            ("result", r#"{"output":{"finish_reason":"null","text":"lo"},"usage":{"input_tokens":8,"output_tokens":2,"total_tokens":10},"request_id":"r1"}"#),
+            ("result", r#"{"output":{"finish_reason":"stop","text":"!"},"usage":{"input_tokens":8,"output_tokens":3,"total_tokens":11},"request_id":"r1"}"#),
+        ]);
+
+        let events = stream_events(&server, body).await;
+
+        let deltas: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                Ok(StreamEvent::Delta { content }) => Some(content.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, ["Hel", "lo", "!"]);
+        assert_eq!(events.len(), 4);
+        let Ok(StreamEvent::Done { finish_reason, usage: Some(usage), .. }) = &events[3] else {
+            panic!("expected done event, got {:?}", events[3]);
+        };
+        assert_eq!(finish_reason.as_deref(), Some("stop"));
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(8), Some(3), Some(11)));
+    }
+
+    #[tokio::test]
+    async fn stream_error_event_becomes_error() {
+        let server = MockServer::start().await;
+        let body = sse_body(&[
+            ("result", r#"{"output":{"finish_reason":"null","text":"Hel"},"request_id":"r1"}"#),
+            ("error", r#"{"code":"DataInspectionFailed","message":"Output data may contain inappropriate content.","request_id":"r1"}"#),
+        ]);
+
+        let events = stream_events(&server, body).await;
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], Ok(StreamEvent::Delta { content }) if content == "Hel"));
+        let Err(AppError::AIServiceError(message)) = &events[1] else {
+            panic!("expected error, got {:?}", events[1]);
+        };
+        assert!(message.contains("DataInspectionFailed"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn sends_tools_and_parses_tool_calls() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(TEXT_PATH))
+            .and(body_partial_json(json!({
+                "input": {
+                    "messages": [
+                        { "role": "user", "content": "weather?" },
+                        {
+                            "role": "assistant",
+                            "content": "",
+                            "tool_calls": [{
+                                "id": "call_0",
+                                "type": "function",
+                                "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                            }]
+                        },
+                        { "role": "tool", "content": "sunny", "name": "get_weather", "tool_call_id": "call_0" }
+                    ]
+                },
+                "parameters": {
+                    "result_format": "message",
+                    "tools": [{
+                        "type": "function",
+                        "function": { "name": "get_weather", "parameters": { "type": "object", "properties": {} } }
+                    }]
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "output": {
+                    "choices": [{
+                        "finish_reason": "tool_calls",
+                        "message": {
+                            "role": "assistant",
+                            "content": "",
+                            "tool_calls": [{
+                                "id": "call_1",
+                                "type": "function",
+                                "function": { "name": "get_weather", "arguments": "{\"city\":\"Berlin\"}" }
+                            }]
+                        }
+                    }]
+                },
+                "usage": { "input_tokens": 40, "output_tokens": 12, "total_tokens": 52 },
+                "request_id": "r2"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let response = provider_for(&server)
+            .analyze(AIRequest {
+                messages: vec![
+                    ChatMessage::text(ChatRole::User, "weather?"),
+                    ChatMessage {
+                        role: ChatRole::Assistant,
+                        content: Vec::new(),
+                        tool_calls: vec![ToolCall::from_json_arguments("call_0".to_string(), "get_weather".to_string(), r#"{"city":"Paris"}"#)],
+                        tool_call_id: None,
+                    },
+                    ChatMessage { tool_call_id: Some("call_0".to_string()), ..ChatMessage::text(ChatRole::Tool, "sunny") },
+                ],
+                tools: vec![ToolDefinition {
+                    name: "get_weather".to_string(),
+                    description: String::new(),
+                    parameters: json!({ "type": "object", "properties": {} }),
+                }],
+                ..request("")
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(response.content, "");
+        assert_eq!(response.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(response.tool_calls.len(), 1);
+        assert_eq!(response.tool_calls[0].id, "call_1");
+        assert_eq!(response.tool_calls[0].name, "get_weather");
+        assert_eq!(response.tool_calls[0].arguments, json!({ "city": "Berlin" }));
+    }
+
+    #[tokio::test]
+    async fn parses_embeddings_by_text_index() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/services/embeddings/text-embedding/text-embedding"))
+            .and(body_partial_json(json!({ "model": "text-embedding-v3", "input": { "texts": ["a", "b"] } })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "output": {
+                    "embeddings": [
+                        { "text_index": 1, "embedding": [0.3, 0.4] },
+                        { "text_index": 0, "embedding": [0.1, 0.2] }
+                    ]
+                },
+                "usage": { "total_tokens": 6 },
+                "request_id": "r3"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let batch = provider_for(&server)
+            .embed("text-embedding-v3", vec!["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(batch.embeddings, [vec![0.1, 0.2], vec![0.3, 0.4]]);
+        let usage = batch.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(6), None, Some(6)));
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::ai::TokenUsage;
+use crate::models::ai_quota::{ModelUsage, NewUsage, QuotaLimits};
+use crate::service::redis_service::RedisService;
+
+// 计数器键包含日期，过期时间只需覆盖当前周期
+const DAILY_COUNTER_TTL_SECS: u64 = 2 * 24 * 3600;
+const MONTHLY_COUNTER_TTL_SECS: u64 = 32 * 24 * 3600;
+
+// 计数器存在时累加；不存在时用 ARGV[2]（已包含本次用量的数据库统计）初始化，未传时返回 nil。
+// 判断和写入在同一个脚本里完成，初始化和累加之间不会丢失其他请求的用量
+const INCREMENT_OR_INIT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return redis.call('INCRBY', KEYS[1], ARGV[1])
+end
+if ARGV[2] == nil then
+    return nil
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+return tonumber(ARGV[2])
+"#;
+
+#[derive(Debug, Serialize)]
+pub struct QuotaWindow {
+    pub used: i64,
+    // None 表示不限
+    pub limit: Option<i64>,
+    pub remaining: Option<i64>,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl QuotaWindow {
+    fn new(used: i64, limit: Option<i64>, resets_at: DateTime<Utc>) -> Self {
+        Self {
+            used,
+            limit,
+            remaining: limit.map(|limit| (limit - used).max(0)),
+            resets_at,
+        }
+    }
+
+    fn exhausted(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    fn retry_after(&self) -> u64 {
+        (self.resets_at - Utc::now()).num_seconds().max(1) as u64
+    }
+
+    fn message(&self) -> String {
+        format!(
+            "已使用 {} / {} tokens，将于 {} 重置",
+            self.used,
+            self.limit.unwrap_or_default(),
+            self.resets_at.to_rfc3339(),
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaStatus {
+    pub plan: String,
+    pub daily: QuotaWindow,
+    pub monthly: QuotaWindow,
+}
+
+// 周期按 UTC 计算
+struct Periods {
+    today: NaiveDate,
+    month_start: NaiveDate,
+}
+
+impl Periods {
+    fn now() -> Self {
+        Self::on(Utc::now().date_naive())
+    }
+
+    fn on(today: NaiveDate) -> Self {
+        Self {
+            today,
+            month_start: today.with_day(1).unwrap_or(today),
+        }
+    }
+
+    fn day_resets_at(&self) -> DateTime<Utc> {
+        start_of(self.today.succ_opt().unwrap_or(self.today))
+    }
+
+    fn month_resets_at(&self) -> DateTime<Utc> {
+        start_of(self.month_start.checked_add_months(Months::new(1)).unwrap_or(self.month_start))
+    }
+}
+
+fn start_of(day: NaiveDate) -> DateTime<Utc> {
+    day.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc()
+}
+
+fn daily_key(user_id: Uuid, day: NaiveDate) -> String {
+    format!("ai:tokens:{}:{}", user_id, day.format("%Y-%m-%d"))
+}
+
+fn monthly_key(user_id: Uuid, month_start: NaiveDate) -> String {
+    format!("ai:tokens:{}:{}", user_id, month_start.format("%Y-%m"))
+}
+
+// 按用户计量 token 用量并执行每日、每月额度；Postgres 保存用量，Redis 计数器用于快速检查
+#[derive(Clone)]
+pub struct QuotaService {
+    pool: crate::db::DbPool,
+    redis: RedisService,
+    default_plan: String,
+    increment: redis::Script,
+}
+
+impl QuotaService {
+    pub fn new(pool: crate::db::DbPool, redis: RedisService, default_plan: &str) -> Self {
+        Self {
+            pool,
+            redis,
+            default_plan: default_plan.to_string(),
+            increment: redis::Script::new(INCREMENT_OR_INIT),
+        }
+    }
+
+    // 读取计数器；计数器不存在或 Redis 不可用时从数据库统计。
+    // 计数器只在记录用量时初始化：检查时读到的统计可能不含正在写入的用量，用它初始化会漏计
+    async fn used(&self, key: &str, user_id: Uuid, since: NaiveDate) -> Result<i64, AppError> {
+        match self.redis.query::<Option<i64>>(redis::cmd("GET").arg(key)).await {
+            Ok(Some(used)) => Ok(used),
+            Ok(None) => ModelUsage::tokens_since(&self.pool, user_id, since).await,
+            Err(e) => {
+                log::warn!("读取 AI 用量计数器失败，改为查询数据库: {:?}", e);
+                ModelUsage::tokens_since(&self.pool, user_id, since).await
+            }
+        }
+    }
+
+    // 累加计数器；计数器不存在时用数据库统计（已包含本次用量）初始化。
+    // 多个请求同时初始化时可能多计一次调用的用量，但不会漏计
+    async fn increment(&self, key: &str, ttl: u64, tokens: i64, user_id: Uuid, since: NaiveDate) -> Result<(), AppError> {
+        let mut invocation = self.increment.key(key);
+        invocation.arg(tokens);
+        if self.redis.invoke::<Option<i64>>(&invocation).await?.is_some() {
+            return Ok(());
+        }
+        let used = ModelUsage::tokens_since(&self.pool, user_id, since).await?;
+        invocation.arg(used).arg(ttl);
+        self.redis.invoke::<Option<i64>>(&invocation).await?;
+        Ok(())
+    }
+
+    pub async fn status(&self, user_id: Uuid) -> Result<QuotaStatus, AppError> {
+        let limits = QuotaLimits::for_user(&self.pool, user_id, &self.default_plan).await?;
+        let periods = Periods::now();
+
+        let daily_used = self.used(&daily_key(user_id, periods.today), user_id, periods.today).await?;
+        let monthly_used = self.used(&monthly_key(user_id, periods.month_start), user_id, periods.month_start).await?;
+
+        Ok(QuotaStatus {
+            plan: limits.plan,
+            daily: QuotaWindow::new(daily_used, limits.daily_token_limit, periods.day_resets_at()),
+            monthly: QuotaWindow::new(monthly_used, limits.monthly_token_limit, periods.month_resets_at()),
+        })
+    }
+
+    // 在调用提供商之前检查；额度用完之前发起的最后一次调用可能略微超出额度
+    pub async fn check(&self, user_id: Uuid) -> Result<(), AppError> {
+        let status = self.status(user_id).await?;
+        if status.monthly.exhausted() {
+            return Err(AppError::AIMonthlyQuotaExceeded {
+                message: status.monthly.message(),
+                retry_after: status.monthly.retry_after(),
+            });
+        }
+        if status.daily.exhausted() {
+            return Err(AppError::AIDailyQuotaExceeded {
+                message: status.daily.message(),
+                retry_after: status.daily.retry_after(),
+            });
+        }
+        Ok(())
+    }
+
+    // 缓存命中只计请求数，不消耗额度
+    pub async fn record_cache_hit(&self, user_id: Uuid, provider: Option<&str>, model: Option<&str>) {
+        let saved = ModelUsage::add(&self.pool, NewUsage {
+            user_id,
+            day: Periods::now().today,
+            provider,
+            model,
+            input_tokens: 0,
+            output_tokens: 0,
+            total_tokens: 0,
+            cached: true,
+        }).await;
+        if let Err(e) = saved {
+            log::error!("AI 用量写入失败: {:?}", e);
+        }
+    }
+
+    // 记录提供商返回的用量；失败只记录日志，不影响已完成的调用
+    pub async fn record(&self, user_id: Uuid, provider: Option<&str>, model: Option<&str>, usage: &TokenUsage) {
+        let usage = usage.clone().with_total();
+        let input_tokens = usage.input_tokens.unwrap_or_default() as i64;
+        let output_tokens = usage.output_tokens.unwrap_or_default() as i64;
+        let total_tokens = usage.total_tokens.map(|tokens| tokens as i64).unwrap_or(input_tokens + output_tokens);
+        let periods = Periods::now();
+
+        let saved = ModelUsage::add(&self.pool, NewUsage {
+            user_id,
+            day: periods.today,
+            provider,
+            model,
+            input_tokens,
+            output_tokens,
+            total_tokens,
+            cached: false,
+        }).await;
+        if let Err(e) = saved {
+            log::error!("AI 用量写入失败: {:?}", e);
+            return;
+        }
+
+        let counters = [
+            (daily_key(user_id, periods.today), DAILY_COUNTER_TTL_SECS, periods.today),
+            (monthly_key(user_id, periods.month_start), MONTHLY_COUNTER_TTL_SECS, periods.month_start),
+        ];
+        for (key, ttl, since) in counters {
+            if let Err(e) = self.increment(&key, ttl, total_tokens, user_id, since).await {
+                log::warn!("更新 AI 用量计数器失败: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periods_reset_at_next_utc_day_and_month() {
+        let periods = Periods::on(NaiveDate::from_ymd_opt(2024, 12, 31).unwrap());
+
+        assert_eq!(periods.month_start, NaiveDate::from_ymd_opt(2024, 12, 1).unwrap());
+        assert_eq!(periods.day_resets_at().to_rfc3339(), "2025-01-01T00:00:00+00:00");
+        assert_eq!(periods.month_resets_at().to_rfc3339(), "2025-01-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn counter_keys_are_per_user_and_period() {
+        let user_id = Uuid::nil();
+        let periods = Periods::on(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap());
+
+        assert_eq!(daily_key(user_id, periods.today), format!("ai:tokens:{}:2024-02-29", user_id));
+        assert_eq!(monthly_key(user_id, periods.month_start), format!("ai:tokens:{}:2024-02", user_id));
+    }
+
+    #[test]
+    fn window_remaining_is_clamped_and_unlimited_never_exhausts() {
+        let resets_at = Utc::now() + chrono::Duration::hours(1);
+
+        let over = QuotaWindow::new(1200, Some(1000), resets_at);
+        assert_eq!(over.remaining, Some(0));
+        assert!(over.exhausted());
+        assert!(over.retry_after() > 3500 && over.retry_after() <= 3600);
+
+        let unlimited = QuotaWindow::new(1_000_000, None, resets_at);
+        assert_eq!(unlimited.remaining, None);
+        assert!(!unlimited.exhausted());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::config::{Config, ModelRoute};
+use crate::errors::AppError;
+use crate::models::ai::AIRequest;
+
+use super::resilience::{CircuitBreaker, CircuitSnapshot};
+use super::providers::{
+    anthropic::AnthropicProvider, ollama::OllamaProvider, openai::OpenAIProvider,
+    tongyi::TongyiProvider, Provider,
+};
+
+pub struct RegisteredProvider {
+    pub provider: Arc<dyn Provider>,
+    pub breaker: CircuitBreaker,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProviderStatus {
+    pub name: String,
+    pub default: bool,
+    pub fallback_position: Option<usize>,
+    pub circuit: CircuitSnapshot,
+}
+
+// 启动时根据配置创建全部提供商，所有提供商共享同一个 HTTP 连接池
+pub struct ProviderRegistry {
+    providers: HashMap<String, RegisteredProvider>,
+    default_provider: String,
+    routes: Vec<ModelRoute>,
+    fallback_chain: Vec<String>,
+}
+
+impl ProviderRegistry {
+    pub fn from_config(config: &Config) -> Result<Self, AppError> {
+        // 不设置总超时，否则会截断较长的流式响应；请求超时由服务层按调用类型控制
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(config.ai_resilience.connect_timeout_secs))
+            .build()
+            .map_err(|e| AppError::ConfigError(format!("HTTP 客户端初始化失败: {}", e)))?;
+        let empty = HashMap::new();
+
+        let mut names: Vec<&str> = config.ai_providers.configured_providers().collect();
+        // 本地提供商无需任何配置即可作为默认提供商
+        if !names.contains(&config.ai_default_provider.as_str()) {
+            names.push(&config.ai_default_provider);
+        }
+
+        let mut providers = HashMap::new();
+        for name in names {
+            let provider_config = config.ai_providers.get_provider_config(name).unwrap_or(&empty);
+            match build_provider(name, client.clone(), provider_config) {
+                Ok(provider) => {
+                    log::info!("AI 提供商已加载: {}", name);
+                    providers.insert(name.to_string(), RegisteredProvider {
+                        provider,
+                        breaker: CircuitBreaker::new(&config.ai_resilience),
+                    });
+                }
+                Err(e) => log::warn!("AI 提供商 {} 未启用: {:?}", name, e),
+            }
+        }
+
+        for name in &config.ai_resilience.fallback_chain {
+            if !providers.contains_key(name) {
+                log::warn!("备用 AI 提供商 {} 未启用，将被跳过", name);
+            }
+        }
+
+        Ok(Self {
+            providers,
+            default_provider: config.ai_default_provider.clone(),
+            routes: config.ai_model_routes.clone(),
+            fallback_chain: config.ai_resilience.fallback_chain.clone(),
+        })
+    }
+
+    // 依次尝试的提供商：主提供商在前，其后为备用链；显式指定提供商时不切换
+    pub fn candidates(&self, request: &AIRequest) -> Result<Vec<(&str, &RegisteredProvider)>, AppError> {
+        let primary = self.resolve(request.provider.as_deref(), request.model.as_deref())?;
+        let mut candidates = vec![primary];
+        if request.provider.is_none() {
+            for name in &self.fallback_chain {
+                if let Some(entry) = self.get(name) {
+                    if !candidates.iter().any(|(existing, _)| *existing == entry.0) {
+                        candidates.push(entry);
+                    }
+                }
+            }
+        }
+        Ok(candidates)
+    }
+
+    // 选择顺序：请求显式指定的提供商 > 模型名路由规则 > 默认提供商
+    pub fn resolve(&self, provider: Option<&str>, model: Option<&str>) -> Result<(&str, &RegisteredProvider), AppError> {
+        if let Some(name) = provider {
+            let name = name.to_lowercase();
+            return self.get(&name).ok_or_else(|| {
+                AppError::ValidationError(format!("AI provider not available: {}", name))
+            });
+        }
+
+        if let Some(model) = model {
+            if let Some(route) = self.routes.iter().find(|route| route.matches(model)) {
+                return self.get(&route.provider).ok_or_else(|| {
+                    AppError::ValidationError(format!(
+                        "AI provider {} for model {} not available",
+                        route.provider, model
+                    ))
+                });
+            }
+        }
+
+        self.get(&self.default_provider).ok_or_else(|| {
+            AppError::ConfigError(format!("Default AI provider not configured: {}", self.default_provider))
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<(&str, &RegisteredProvider)> {
+        self.providers
+            .get_key_value(name)
+            .map(|(name, entry)| (name.as_str(), entry))
+    }
+
+    pub fn status(&self) -> Vec<ProviderStatus> {
+        let mut status: Vec<ProviderStatus> = self
+            .providers
+            .iter()
+            .map(|(name, entry)| ProviderStatus {
+                name: name.clone(),
+                default: *name == self.default_provider,
+                fallback_position: self.fallback_chain.iter().position(|fallback| fallback == name),
+                circuit: entry.breaker.snapshot(),
+            })
+            .collect();
+        status.sort_by(|a, b| a.name.cmp(&b.name));
+        status
+    }
+
+    // 手动关闭熔断器，返回 false 表示提供商不存在
+    pub fn reset_circuit(&self, name: &str) -> bool {
+        match self.providers.get(name) {
+            Some(entry) => {
+                entry.breaker.reset();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// 测试用：按名称构建并注册给定的提供商，第一个为默认提供商，备用链取自 resilience
+#[cfg(test)]
+pub fn registry_for(
+    providers: &[(&str, HashMap<String, String>)],
+    resilience: &crate::config::AIResilienceConfig,
+) -> Result<ProviderRegistry, AppError> {
+    let mut registered = HashMap::new();
+    for (name, provider_config) in providers {
+        registered.insert(name.to_string(), RegisteredProvider {
+            provider: build_provider(name, Client::new(), provider_config)?,
+            breaker: CircuitBreaker::new(resilience),
+        });
+    }
+    Ok(ProviderRegistry {
+        providers: registered,
+        default_provider: providers.first().map(|(name, _)| name.to_string()).unwrap_or_default(),
+        routes: Vec::new(),
+        fallback_chain: resilience.fallback_chain.clone(),
+    })
+}
+
+fn build_provider(
+    name: &str,
+    client: Client,
+    provider_config: &HashMap<String, String>,
+) -> Result<Arc<dyn Provider>, AppError> {
+    Ok(match name {
+        "tongyi" => Arc::new(TongyiProvider::from_provider_config(client, provider_config)?),
+        "openai" => Arc::new(OpenAIProvider::from_provider_config(client, provider_config)?),
+        "anthropic" => Arc::new(AnthropicProvider::from_provider_config(client, provider_config)?),
+        "ollama" => Arc::new(OllamaProvider::from_provider_config(client, provider_config)),
+        "llamacpp" => Arc::new(OpenAIProvider::llama_cpp(client, provider_config)?),
+        other => return Err(AppError::ConfigError(format!("Unknown AI provider: {}", other))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AIResilienceConfig;
+    use crate::models::ai::{ChatMessage, ChatRole, GenerationParams};
+
+    fn registry(providers: &[&str], fallback_chain: &[&str]) -> ProviderRegistry {
+        let resilience = AIResilienceConfig {
+            max_retries: 0,
+            retry_base_delay_ms: 0,
+            retry_max_delay_ms: 0,
+            max_retry_after_secs: 0,
+            request_timeout_secs: 1,
+            connect_timeout_secs: 1,
+            stream_idle_timeout_secs: 1,
+            fallback_chain: Vec::new(),
+            circuit_failure_threshold: 1,
+            circuit_open_secs: 1,
+        };
+        // 路由只看注册名，具体实现不影响
+        let providers = providers
+            .iter()
+            .map(|name| {
+                let provider = build_provider("ollama", Client::new(), &HashMap::new()).unwrap();
+                (name.to_string(), RegisteredProvider { provider, breaker: CircuitBreaker::new(&resilience) })
+            })
+            .collect();
+        ProviderRegistry {
+            providers,
+            default_provider: "tongyi".to_string(),
+            routes: ModelRoute::parse_list("gpt-*=openai,qwen-*=tongyi,claude-*=anthropic").unwrap(),
+            fallback_chain: fallback_chain.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    fn request(provider: Option<&str>, model: Option<&str>) -> AIRequest {
+        AIRequest {
+            messages: vec![ChatMessage::text(ChatRole::User, "hi")],
+            provider: provider.map(str::to_string),
+            model: model.map(str::to_string),
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    fn resolved(registry: &ProviderRegistry, provider: Option<&str>, model: Option<&str>) -> Result<String, AppError> {
+        registry.resolve(provider, model).map(|(name, _)| name.to_string())
+    }
+
+    #[test]
+    fn routes_by_provider_then_model_then_default() {
+        let registry = registry(&["tongyi", "openai", "anthropic", "ollama"], &[]);
+
+        assert_eq!(resolved(&registry, None, Some("gpt-4o")).unwrap(), "openai");
+        assert_eq!(resolved(&registry, None, Some("claude-sonnet-4-5")).unwrap(), "anthropic");
+        assert_eq!(resolved(&registry, None, Some("qwen-plus")).unwrap(), "tongyi");
+        assert_eq!(resolved(&registry, None, Some("llama3")).unwrap(), "tongyi");
+        assert_eq!(resolved(&registry, None, None).unwrap(), "tongyi");
+        // 显式指定的提供商优先于模型路由，且不区分大小写
+        assert_eq!(resolved(&registry, Some("Ollama"), Some("gpt-4o")).unwrap(), "ollama");
+    }
+
+    #[test]
+    fn rejects_unavailable_providers() {
+        let registry = registry(&["openai"], &[]);
+
+        assert!(matches!(resolved(&registry, Some("anthropic"), None), Err(AppError::ValidationError(_))));
+        assert!(matches!(resolved(&registry, None, Some("claude-sonnet-4-5")), Err(AppError::ValidationError(_))));
+        assert!(matches!(resolved(&registry, None, None), Err(AppError::ConfigError(_))));
+    }
+
+    #[test]
+    fn candidates_follow_fallback_chain() {
+        let registry = registry(&["tongyi", "openai", "ollama"], &["openai", "anthropic", "tongyi", "ollama"]);
+        let names = |request: AIRequest| -> Vec<String> {
+            registry.candidates(&request).unwrap().into_iter().map(|(name, _)| name.to_string()).collect()
+        };
+
+        // 未启用的 anthropic 被跳过，主提供商不重复出现
+        assert_eq!(names(request(None, Some("gpt-4o"))), ["openai", "tongyi", "ollama"]);
+        assert_eq!(names(request(None, None)), ["tongyi", "openai", "ollama"]);
+        // 显式指定提供商时不切换
+        assert_eq!(names(request(Some("ollama"), None)), ["ollama"]);
+    }
+}
@@ -0,0 +1,293 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::Serialize;
+
+use crate::config::AIResilienceConfig;
+use crate::errors::AppError;
+
+// 只有瞬时错误才值得重试或切换提供商；请求本身有问题时重试没有意义
+pub fn is_transient(error: &AppError) -> bool {
+    matches!(
+        error,
+        AppError::AIRateLimited { .. }
+            | AppError::AIOverloaded { .. }
+            | AppError::AIUpstreamError { .. }
+            | AppError::AITimeout(_)
+            | AppError::AIUnavailable(_)
+    )
+}
+
+fn retry_after(error: &AppError) -> Option<u64> {
+    match error {
+        AppError::AIRateLimited { retry_after, .. } | AppError::AIOverloaded { retry_after, .. } => *retry_after,
+        _ => None,
+    }
+}
+
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_delay_ms: u64,
+    max_delay_ms: u64,
+    max_retry_after_secs: u64,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &AIResilienceConfig) -> Self {
+        Self {
+            max_retries: config.max_retries,
+            base_delay_ms: config.retry_base_delay_ms,
+            max_delay_ms: config.retry_max_delay_ms,
+            max_retry_after_secs: config.max_retry_after_secs,
+        }
+    }
+
+    // 第 attempt 次失败（从 0 开始）后的等待时间，None 表示不再重试
+    pub fn delay_for(&self, attempt: u32, error: &AppError) -> Option<Duration> {
+        if attempt >= self.max_retries || !is_transient(error) {
+            return None;
+        }
+
+        if let Some(seconds) = retry_after(error) {
+            return (seconds <= self.max_retry_after_secs).then(|| Duration::from_secs(seconds));
+        }
+
+        // 指数退避，在 [cap/2, cap] 内随机抖动，避免多个请求同时重试
+        let cap = self
+            .base_delay_ms
+            .saturating_mul(1u64 << attempt.min(16))
+            .min(self.max_delay_ms);
+        let delay = cap / 2 + rand::thread_rng().gen_range(0..=cap - cap / 2);
+        Some(Duration::from_millis(delay))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitSnapshot {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub opened_at: Option<DateTime<Utc>>,
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<DateTime<Utc>>,
+    // 半开状态下正在进行的探测请求的开始时间；探测请求被取消时不会回报结果，超时后允许新的探测
+    probe_started_at: Option<DateTime<Utc>>,
+}
+
+// 每个提供商一个熔断器：连续失败达到阈值后熔断，冷却期结束后放行一个探测请求
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: chrono::Duration,
+    probe_timeout: chrono::Duration,
+    inner: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &AIResilienceConfig) -> Self {
+        Self {
+            failure_threshold: config.circuit_failure_threshold,
+            open_duration: chrono::Duration::seconds(config.circuit_open_secs as i64),
+            // 每次尝试最长 request_timeout_secs，超过后探测请求必然已结束或被取消
+            probe_timeout: chrono::Duration::seconds(config.request_timeout_secs as i64),
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_started_at: None,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn allow_request(&self) -> bool {
+        let mut inner = self.lock();
+        let now = Utc::now();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                let cooled_down = inner
+                    .opened_at
+                    .map(|opened_at| now >= opened_at + self.open_duration)
+                    .unwrap_or(true);
+                if cooled_down {
+                    inner.state = CircuitState::HalfOpen;
+                    inner.probe_started_at = Some(now);
+                }
+                cooled_down
+            }
+            CircuitState::HalfOpen => {
+                let probe_pending = inner
+                    .probe_started_at
+                    .is_some_and(|started_at| now < started_at + self.probe_timeout);
+                if !probe_pending {
+                    inner.probe_started_at = Some(now);
+                }
+                !probe_pending
+            }
+        }
+    }
+
+    // 熔断期间剩余的秒数，用于 Retry-After
+    pub fn retry_after(&self) -> Option<u64> {
+        let inner = self.lock();
+        let opened_at = inner.opened_at?;
+        let remaining = (opened_at + self.open_duration - Utc::now()).num_seconds();
+        Some(remaining.max(1) as u64)
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.lock();
+        inner.state = CircuitState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probe_started_at = None;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.lock();
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        inner.probe_started_at = None;
+        let should_open = inner.state == CircuitState::HalfOpen
+            || inner.consecutive_failures >= self.failure_threshold;
+        if should_open {
+            if inner.state != CircuitState::Open {
+                log::warn!("AI 提供商连续失败 {} 次，熔断", inner.consecutive_failures);
+            }
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Utc::now());
+        }
+    }
+
+    pub fn reset(&self) {
+        self.record_success();
+    }
+
+    pub fn snapshot(&self) -> CircuitSnapshot {
+        let inner = self.lock();
+        CircuitSnapshot {
+            state: inner.state,
+            consecutive_failures: inner.consecutive_failures,
+            opened_at: inner.opened_at,
+            retry_at: match inner.state {
+                CircuitState::Open => inner.opened_at.map(|opened_at| opened_at + self.open_duration),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(circuit_open_secs: u64) -> AIResilienceConfig {
+        AIResilienceConfig {
+            max_retries: 2,
+            retry_base_delay_ms: 100,
+            retry_max_delay_ms: 300,
+            max_retry_after_secs: 10,
+            request_timeout_secs: 60,
+            connect_timeout_secs: 10,
+            stream_idle_timeout_secs: 60,
+            fallback_chain: Vec::new(),
+            circuit_failure_threshold: 3,
+            circuit_open_secs,
+        }
+    }
+
+    fn upstream_error() -> AppError {
+        AppError::AIUpstreamError { status: 500, message: "boom".to_string() }
+    }
+
+    #[test]
+    fn backoff_grows_within_jitter_and_cap() {
+        let policy = RetryPolicy::new(&config(30));
+
+        for _ in 0..50 {
+            let first = policy.delay_for(0, &upstream_error()).unwrap().as_millis();
+            let second = policy.delay_for(1, &upstream_error()).unwrap().as_millis();
+            assert!((50..=100).contains(&first), "{}", first);
+            assert!((100..=200).contains(&second), "{}", second);
+        }
+        assert!(policy.delay_for(2, &upstream_error()).is_none());
+        assert!(policy.delay_for(0, &AppError::AIInvalidRequest("bad".to_string())).is_none());
+    }
+
+    #[test]
+    fn honors_retry_after_up_to_limit() {
+        let policy = RetryPolicy::new(&config(30));
+        let limited = |retry_after| AppError::AIRateLimited { message: String::new(), retry_after: Some(retry_after) };
+
+        assert_eq!(policy.delay_for(0, &limited(3)), Some(Duration::from_secs(3)));
+        assert_eq!(policy.delay_for(0, &limited(11)), None);
+    }
+
+    #[test]
+    fn opens_after_threshold_and_closes_after_probe() {
+        let breaker = CircuitBreaker::new(&config(0));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.snapshot().state, CircuitState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.snapshot().state, CircuitState::Open);
+
+        // 冷却结束后只放行一个探测请求
+        assert!(breaker.allow_request());
+        assert_eq!(breaker.snapshot().state, CircuitState::HalfOpen);
+        assert!(!breaker.allow_request());
+
+        breaker.record_success();
+        let snapshot = breaker.snapshot();
+        assert_eq!((snapshot.state, snapshot.consecutive_failures), (CircuitState::Closed, 0));
+        assert!(breaker.allow_request());
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(&config(30));
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(!breaker.allow_request());
+        assert!(breaker.retry_after().is_some_and(|seconds| seconds <= 30));
+
+        breaker.lock().opened_at = Some(Utc::now() - chrono::Duration::seconds(31));
+        assert!(breaker.allow_request());
+        breaker.record_failure();
+        assert_eq!(breaker.snapshot().state, CircuitState::Open);
+        assert!(!breaker.allow_request());
+    }
+
+    #[test]
+    fn abandoned_probe_expires() {
+        let breaker = CircuitBreaker::new(&config(0));
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        assert!(breaker.allow_request());
+        assert!(!breaker.allow_request());
+
+        // 探测请求被取消后没有回报结果，超过 probe_timeout 后放行新的探测
+        breaker.lock().probe_started_at = Some(Utc::now() - chrono::Duration::seconds(61));
+        assert!(breaker.allow_request());
+        assert!(!breaker.allow_request());
+    }
+}
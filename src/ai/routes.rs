@@ -1,11 +1,26 @@
 use actix_web::web;
-use super::handlers;
+use super::{conversation_handlers, handlers, history_handlers, usage_handlers};
 
 // ai/routes.rs
 pub fn ai_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/ai")
-            .route("/text", web::post().to(handlers::analyze_text)) 
+            .route("/text", web::post().to(handlers::analyze_text))
+            .route("/text/stream", web::post().to(handlers::analyze_text_stream))
             .route("/image", web::post().to(handlers::analyze_image))
+            .route("/tools", web::get().to(handlers::list_tools))
+            .route("/embeddings", web::post().to(handlers::create_embeddings))
+            .route("/usage", web::get().to(usage_handlers::my_usage))
+            .route("/history", web::get().to(history_handlers::list_history))
+            .route("/history", web::delete().to(history_handlers::clear_history))
+            .route("/history/{id}", web::delete().to(history_handlers::delete_history_entry))
+            .route("/conversations", web::post().to(conversation_handlers::create_conversation))
+            .route("/conversations", web::get().to(conversation_handlers::list_conversations))
+            .route("/conversations/{id}", web::get().to(conversation_handlers::get_conversation))
+            .route("/conversations/{id}", web::patch().to(conversation_handlers::rename_conversation))
+            .route("/conversations/{id}", web::delete().to(conversation_handlers::delete_conversation))
+            .route("/conversations/{id}/messages", web::get().to(conversation_handlers::list_messages))
+            .route("/conversations/{id}/messages", web::post().to(conversation_handlers::send_message))
+            .route("/conversations/{id}/attachments", web::post().to(conversation_handlers::upload_attachment))
     );
 }
\ No newline at end of file
@@ -1,32 +1,830 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::errors::AppError;
-use crate::models::ai::{AIRequest, AIResponse};
-use crate::config::Config;
+use crate::models::ai::{
+    AIRequest, AIResponse, ChatMessage, ContentPart, EmbeddingBatch, EmbeddingRequest, GenerationParams, StreamEvent,
+    TokenUsage,
+};
+use crate::config::{AIGenerationConfig, Config};
+use crate::service::image_processing::{prepare_image, ImageLimits};
+use actix_web::web;
 use async_trait::async_trait;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
 
-use super::providers::{self, Provider};
+use super::image_fetch::ImageFetcher;
+use super::providers::{ensure_params_supported, AIStream, Provider};
+use super::registry::{ProviderRegistry, ProviderStatus, RegisteredProvider};
+use super::resilience::{is_transient, RetryPolicy};
+use super::structured;
 
 #[async_trait]
 pub trait AIService: Send + Sync {
     async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError>;
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError>;
+}
+
+// 请求在该提供商上使用的模型，含图片时必须是提供商允许的视觉模型
+fn target_model(provider: &dyn Provider, request: &AIRequest) -> Result<String, AppError> {
+    let model = provider.request_model(request);
+    let has_images = request.messages.iter().any(ChatMessage::has_images);
+    if has_images && !provider.vision_models().allows(&model) {
+        return Err(AppError::ValidationError(format!(
+            "模型 {} 不支持图片输入，可用的模型: {}",
+            model,
+            provider.vision_models().allowed.join(", ")
+        )));
+    }
+    Ok(model)
+}
+
+// 按提供商限制预处理后的图片，键为限制和原图的 SHA-256
+type PreparedImages = HashMap<(ImageLimits, [u8; 32]), Vec<u8>>;
+
+// 按提供商的限制预处理请求中的图片。解码、缩放和重新编码是 CPU 密集操作，放到阻塞线程池；
+// 结果在重试和限制相同的备用提供商之间复用，同一张图片只处理一次
+async fn prepare_images(
+    request: &mut AIRequest,
+    limits: &'static ImageLimits,
+    prepared: &mut PreparedImages,
+) -> Result<(), AppError> {
+    let mut keys = Vec::new();
+    let mut pending = HashMap::new();
+    for part in request.messages.iter().flat_map(|message| &message.content) {
+        if let ContentPart::Image { data } = part {
+            let key = (*limits, Sha256::digest(data).into());
+            if !prepared.contains_key(&key) {
+                pending.entry(key).or_insert_with(|| data.clone());
+            }
+            keys.push(key);
+        }
+    }
+
+    if !pending.is_empty() {
+        let processed = web::block(move || -> Result<Vec<_>, AppError> {
+            pending
+                .into_iter()
+                .map(|(key, data)| Ok((key, prepare_image(data, limits)?.data)))
+                .collect()
+        })
+        .await
+        .map_err(|e| AppError::InternalError(format!("图片处理任务失败: {}", e)))??;
+        prepared.extend(processed);
+    }
+
+    let parts = request.messages.iter_mut().flat_map(|message| message.content.iter_mut());
+    for (part, key) in parts.filter(|part| matches!(part, ContentPart::Image { .. })).zip(keys) {
+        *part = ContentPart::Image { data: prepared[&key].clone() };
+    }
+    Ok(())
+}
+
+// 流式响应超过 idle 没有新事件时以超时错误结束
+fn with_idle_timeout(stream: AIStream, idle: Duration) -> AIStream {
+    Box::pin(futures::stream::unfold(Some(stream), move |stream| async move {
+        let mut stream = stream?;
+        match tokio::time::timeout(idle, stream.next()).await {
+            Ok(Some(event)) => Some((event, Some(stream))),
+            Ok(None) => None,
+            Err(_) => Some((
+                Err(AppError::AITimeout(format!("流式响应超过 {} 秒没有新数据", idle.as_secs()))),
+                None,
+            )),
+        }
+    }))
+}
+
+// 在结束事件上补齐实际应答的提供商，以及上游未返回模型名时请求使用的模型
+fn with_origin(stream: AIStream, provider: String, model: String) -> AIStream {
+    Box::pin(stream.map(move |event| match event {
+        Ok(StreamEvent::Done { finish_reason, usage, model: upstream_model, .. }) => Ok(StreamEvent::Done {
+            finish_reason,
+            usage,
+            provider: Some(provider.clone()),
+            model: upstream_model.or_else(|| Some(model.clone())),
+        }),
+        other => other,
+    }))
 }
 
 #[derive(Clone)]
 pub struct AIServiceImpl {
-    config: Arc<Config>
+    registry: Arc<ProviderRegistry>,
+    retry_policy: Arc<RetryPolicy>,
+    generation: Arc<AIGenerationConfig>,
+    include_raw_response: bool,
+    max_images_per_message: usize,
+    image_fetcher: ImageFetcher,
+    json_max_repairs: usize,
+    request_timeout: Duration,
+    stream_idle_timeout: Duration,
 }
 
 impl AIServiceImpl {
-    pub fn new(config: Config) -> Self {
-        Self { config: config.into() }
+    pub fn new(config: &Config) -> Result<Self, AppError> {
+        Ok(Self {
+            registry: Arc::new(ProviderRegistry::from_config(config)?),
+            retry_policy: Arc::new(RetryPolicy::new(&config.ai_resilience)),
+            generation: Arc::new(config.ai_generation.clone()),
+            include_raw_response: config.ai_include_raw_response,
+            max_images_per_message: config.ai_images.max_per_message,
+            image_fetcher: ImageFetcher::new(&config.ai_images),
+            json_max_repairs: config.ai_json_max_repairs,
+            request_timeout: Duration::from_secs(config.ai_resilience.request_timeout_secs),
+            stream_idle_timeout: Duration::from_secs(config.ai_resilience.stream_idle_timeout_secs),
+        })
+    }
+
+    pub fn provider_status(&self) -> Vec<ProviderStatus> {
+        self.registry.status()
+    }
+
+    pub fn has_provider(&self, name: &str) -> bool {
+        self.registry.get(name).is_some()
+    }
+
+    pub fn reset_circuit(&self, provider: &str) -> bool {
+        self.registry.reset_circuit(provider)
+    }
+
+    // 合并该模型的默认参数，并把 max_tokens 限制在配置的上限内
+    fn resolve_params(&self, model: &str, params: GenerationParams) -> Result<GenerationParams, AppError> {
+        let mut params = match self.generation.defaults_for(model) {
+            Some(defaults) => params.or(defaults),
+            None => params,
+        };
+        if let Some(cap) = self.generation.max_tokens_for(model) {
+            params.max_tokens = Some(params.max_tokens.map_or(cap, |max_tokens| max_tokens.min(cap)));
+        }
+        params.validate()?;
+        Ok(params)
+    }
+
+    // 确定请求在该提供商上使用的模型和参数，并检查提供商是否接受这些参数
+    fn prepare_for(&self, name: &str, provider: &dyn Provider, mut request: AIRequest) -> Result<AIRequest, AppError> {
+        let model = target_model(provider, &request)?;
+        request.params = self.resolve_params(&model, request.params)?;
+        ensure_params_supported(name, &request.params, provider.supported_params(&request))?;
+        request.model = Some(model);
+        Ok(request)
+    }
+
+    // 在 prepare_for 的基础上下载提供商不能直接接收的图片 URL，并按其限制预处理图片
+    async fn prepare_attempt(
+        &self,
+        name: &str,
+        provider: &dyn Provider,
+        request: AIRequest,
+        fetched_images: &mut HashMap<String, Vec<u8>>,
+        prepared_images: &mut PreparedImages,
+    ) -> Result<AIRequest, AppError> {
+        let mut request = self.prepare_for(name, provider, request)?;
+        if !provider.supports_image_urls() {
+            self.image_fetcher.inline_urls(name, &mut request, fetched_images).await?;
+        }
+        prepare_images(&mut request, provider.image_limits(), prepared_images).await?;
+        Ok(request)
+    }
+
+    fn validate_images(&self, request: &AIRequest) -> Result<(), AppError> {
+        let too_many = request.messages.iter().any(|message| {
+            message.content.iter().filter(|part| !matches!(part, ContentPart::Text { .. })).count()
+                > self.max_images_per_message
+        });
+        if too_many {
+            return Err(AppError::ValidationError(format!(
+                "单条消息最多包含 {} 张图片", self.max_images_per_message
+            )));
+        }
+        Ok(())
+    }
+
+    // 请求将发往的主提供商、模型及合并默认值后的参数
+    pub fn primary_target(&self, request: &AIRequest) -> Result<(String, String, GenerationParams), AppError> {
+        let (name, entry) = self
+            .registry
+            .candidates(request)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| AppError::AIServiceError("No AI provider available".to_string()))?;
+        let model = target_model(entry.provider.as_ref(), request)?;
+        let params = self.resolve_params(&model, request.params.clone())?;
+        Ok((name.to_string(), model, params))
+    }
+
+    // 在单个提供商上按退避策略重试瞬时错误，每次尝试受 request_timeout 限制
+    async fn call_with_retry<R, T, F, Fut>(
+        &self,
+        name: &str,
+        entry: &RegisteredProvider,
+        request: R,
+        call: &F,
+    ) -> Result<T, AppError>
+    where
+        R: Clone,
+        F: Fn(Arc<dyn Provider>, R) -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let result = tokio::time::timeout(self.request_timeout, call(entry.provider.clone(), request.clone()))
+                .await
+                .unwrap_or_else(|_| Err(AppError::AITimeout(format!(
+                    "AI 提供商 {} 超过 {} 秒未响应", name, self.request_timeout.as_secs()
+                ))));
+            match result {
+                Ok(response) => {
+                    entry.breaker.record_success();
+                    return Ok(response);
+                }
+                Err(e) if is_transient(&e) => {
+                    entry.breaker.record_failure();
+                    let delay = match self.retry_policy.delay_for(attempt, &e) {
+                        Some(delay) if entry.breaker.allow_request() => delay,
+                        _ => return Err(e),
+                    };
+                    log::warn!(
+                        "AI 提供商 {} 第 {} 次请求失败，{:?} 后重试: {:?}",
+                        name, attempt + 1, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    // 请求本身无效不代表提供商故障
+                    entry.breaker.record_success();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // 依次尝试主提供商和备用链，流式请求只在建立连接阶段重试和切换
+    // 成功时同时返回实际使用的提供商名
+    async fn run<T, F, Fut>(&self, request: AIRequest, call: F) -> Result<(String, T), AppError>
+    where
+        F: Fn(Arc<dyn Provider>, AIRequest) -> Fut,
+        Fut: Future<Output = Result<T, AppError>>,
+    {
+        self.validate_images(&request)?;
+        let candidates = self.registry.candidates(&request)?;
+        let mut last_error = None;
+        let mut fetched_images = HashMap::new();
+        let mut prepared_images = PreparedImages::new();
+
+        for (index, (name, entry)) in candidates.into_iter().enumerate() {
+            if !entry.breaker.allow_request() {
+                log::warn!("AI 提供商 {} 已熔断，跳过", name);
+                last_error = Some(AppError::AIOverloaded {
+                    message: format!("AI provider {} circuit open", name),
+                    retry_after: entry.breaker.retry_after(),
+                });
+                continue;
+            }
+
+            let mut attempt_request = request.clone();
+            if index > 0 {
+                // 模型名只对主提供商有效，备用提供商使用各自的默认模型
+                attempt_request.model = None;
+                log::warn!("切换到备用 AI 提供商 {}", name);
+            }
+            let attempt_request = match self
+                .prepare_attempt(name, entry.provider.as_ref(), attempt_request, &mut fetched_images, &mut prepared_images)
+                .await
+            {
+                Ok(attempt_request) => attempt_request,
+                Err(e) if index == 0 => return Err(e),
+                Err(e) => {
+                    // 备用提供商无法处理该请求时跳过，保留之前的错误
+                    log::warn!("备用 AI 提供商 {} 无法处理该请求，跳过: {:?}", name, e);
+                    last_error.get_or_insert(e);
+                    continue;
+                }
+            };
+            log::debug!("Routing AI request to provider {}", name);
+
+            match self.call_with_retry(name, entry, attempt_request, &call).await {
+                Ok(response) => return Ok((name.to_string(), response)),
+                Err(e) if is_transient(&e) => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| AppError::AIServiceError("No AI provider available".to_string())))
+    }
+
+    // 向量嵌入使用的提供商和模型
+    pub fn embedding_target(&self, request: &EmbeddingRequest) -> Result<(String, String), AppError> {
+        let (name, entry) = self.registry.resolve(request.provider.as_deref(), request.model.as_deref())?;
+        let models = entry
+            .provider
+            .embedding_models()
+            .ok_or_else(|| AppError::ValidationError(format!("AI 提供商 {} 不支持向量嵌入", name)))?;
+        let model = request.model.clone().unwrap_or_else(|| models.default.clone());
+        Ok((name.to_string(), model))
+    }
+
+    // 按提供商的单次上限分批调用，每批按退避策略重试；
+    // 不同模型的向量不能混用，因此不切换备用提供商
+    pub async fn embed(&self, provider: &str, model: &str, inputs: Vec<String>) -> Result<EmbeddingBatch, AppError> {
+        let (name, entry) = self
+            .registry
+            .get(provider)
+            .ok_or_else(|| AppError::ValidationError(format!("AI provider not available: {}", provider)))?;
+        let max_batch = entry.provider.embedding_models().map_or(1, |models| models.max_batch);
+        let mut result = EmbeddingBatch::default();
+
+        for batch in inputs.chunks(max_batch) {
+            if !entry.breaker.allow_request() {
+                return Err(AppError::AIOverloaded {
+                    message: format!("AI provider {} circuit open", name),
+                    retry_after: entry.breaker.retry_after(),
+                });
+            }
+            let embed = |provider: Arc<dyn Provider>, batch: Vec<String>| {
+                let model = model.to_string();
+                async move { provider.embed(&model, batch).await }
+            };
+            let embedded = self.call_with_retry(name, entry, batch.to_vec(), &embed).await?;
+            if embedded.embeddings.len() != batch.len() {
+                return Err(AppError::AIServiceError(format!("{} 返回的向量数量与输入不一致", name)));
+            }
+            result.embeddings.extend(embedded.embeddings);
+            result.usage = TokenUsage::accumulate(result.usage, embedded.usage);
+        }
+        Ok(result)
+    }
+
+    // 校验失败时带上错误重新请求，最多修复 json_max_repairs 次；用量和耗时为所有尝试之和，
+    // 最终失败时已产生的用量随错误返回
+    async fn analyze_json(&self, mut request: AIRequest, schema: &serde_json::Value) -> Result<AIResponse, AppError> {
+        let started = Instant::now();
+        let validator = structured::compile(schema)?;
+        structured::add_instruction(&mut request, schema);
+        let mut usage = None;
+        let mut errors = Vec::new();
+
+        for attempt in 0..=self.json_max_repairs {
+            let mut response = self
+                .analyze_once(request.clone())
+                .await
+                .map_err(|e| e.with_usage(usage.clone()))?;
+            usage = TokenUsage::accumulate(usage, response.usage.take());
+            // 工具调用不是最终回答，由工具循环执行后再次请求
+            let result = match response.tool_calls.is_empty() {
+                true => structured::parse_output(&response.content, &validator).map(Some),
+                false => Ok(None),
+            };
+            match result {
+                Ok(data) => {
+                    response.data = data;
+                    response.usage = usage;
+                    response.latency_ms = started.elapsed().as_millis() as u64;
+                    return Ok(response);
+                }
+                Err(e) => {
+                    log::warn!("第 {} 次结构化输出未通过校验: {:?}", attempt + 1, e);
+                    structured::add_repair_prompt(&mut request, response.content, &e);
+                    errors = e;
+                }
+            }
+        }
+
+        Err(AppError::AIServiceError(format!(
+            "模型输出经过 {} 次修复仍不符合 JSON Schema: {}",
+            self.json_max_repairs,
+            errors.join("; ")
+        ))
+        .with_usage(usage))
+    }
+
+    async fn analyze_once(&self, request: AIRequest) -> Result<AIResponse, AppError> {
+        let started = Instant::now();
+        let (provider, mut response) = self
+            .run(request, |provider, request| async move { provider.analyze(request).await })
+            .await?;
+
+        response.provider = provider;
+        response.latency_ms = started.elapsed().as_millis() as u64;
+        if !self.include_raw_response {
+            response.raw_response = None;
+        }
+        Ok(response)
     }
 }
 
 #[async_trait]
 impl AIService for AIServiceImpl {
     async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
-        let provider = providers::tongyi::TongyiProvider::new(&self.config)?;
-        provider.analyze(request).await
+        match request.response_schema.clone() {
+            Some(schema) => self.analyze_json(request, &schema).await,
+            None => self.analyze_once(request).await,
+        }
+    }
+
+    async fn analyze_stream(&self, request: AIRequest) -> Result<AIStream, AppError> {
+        let (provider, (model, stream)) = self
+            .run(request, |provider, request| async move {
+                let model = request.model.clone().unwrap_or_default();
+                provider.analyze_stream(request).await.map(|stream| (model, stream))
+            })
+            .await?;
+        Ok(with_idle_timeout(with_origin(stream, provider, model), self.stream_idle_timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::registry::registry_for;
+    use crate::models::ai::{AIInput, ChatRole};
+    use crate::service::image_processing::{decode_image, encode_png, sniff_image_kind, ImageKind};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use image::{DynamicImage, ImageFormat};
+    use crate::config::{AIImageConfig, AIResilienceConfig};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    // 第一个提供商为主提供商，其余依次作为备用链
+    fn service_with(providers: &[(&str, HashMap<String, String>)]) -> AIServiceImpl {
+        let resilience = AIResilienceConfig {
+            max_retries: 0,
+            retry_base_delay_ms: 0,
+            retry_max_delay_ms: 0,
+            max_retry_after_secs: 0,
+            request_timeout_secs: 5,
+            connect_timeout_secs: 5,
+            stream_idle_timeout_secs: 5,
+            fallback_chain: providers.iter().skip(1).map(|(name, _)| name.to_string()).collect(),
+            circuit_failure_threshold: 5,
+            circuit_open_secs: 1,
+        };
+        let images = AIImageConfig {
+            max_per_message: 4,
+            max_upload_bytes: 1024,
+            fetch_enabled: false,
+            fetch_max_bytes: 1024,
+            fetch_timeout_secs: 5,
+        };
+        AIServiceImpl {
+            registry: Arc::new(registry_for(providers, &resilience).unwrap()),
+            retry_policy: Arc::new(RetryPolicy::new(&resilience)),
+            generation: Arc::new(AIGenerationConfig { defaults: Vec::new(), max_tokens: Vec::new() }),
+            include_raw_response: false,
+            max_images_per_message: images.max_per_message,
+            image_fetcher: ImageFetcher::new(&images),
+            json_max_repairs: 0,
+            request_timeout: Duration::from_secs(resilience.request_timeout_secs),
+            stream_idle_timeout: Duration::from_secs(resilience.stream_idle_timeout_secs),
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn embeds_in_batches_of_provider_limit() {
+        let server = MockServer::start().await;
+        // 每条输入返回 [序号]，按批内 text_index 排列
+        Mock::given(method("POST"))
+            .and(path("/api/v1/services/embeddings/text-embedding/text-embedding"))
+            .respond_with(|request: &Request| {
+                let body: serde_json::Value = request.body_json().unwrap();
+                let embeddings: Vec<serde_json::Value> = body["input"]["texts"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, text)| {
+                        let value: f32 = text.as_str().unwrap().parse().unwrap();
+                        serde_json::json!({ "text_index": index, "embedding": [value] })
+                    })
+                    .collect();
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "output": { "embeddings": embeddings },
+                    "usage": { "total_tokens": embeddings.len() }
+                }))
+            })
+            .expect(3)
+            .mount(&server)
+            .await;
+        let config = HashMap::from([
+            ("API_KEY".to_string(), "test-key".to_string()),
+            ("API_ENDPOINT".to_string(), format!("{}/api/v1", server.uri())),
+            ("EMBEDDING_BATCH_SIZE".to_string(), "2".to_string()),
+        ]);
+        let service = service_with(&[("tongyi", config)]);
+        let inputs: Vec<String> = (0..5).map(|index| index.to_string()).collect();
+
+        let batch = service.embed("tongyi", "text-embedding-v3", inputs).await.unwrap();
+
+        let sizes: Vec<usize> = server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| request.body_json::<serde_json::Value>().unwrap()["input"]["texts"].as_array().unwrap().len())
+            .collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        assert_eq!(batch.embeddings, [vec![0.0], vec![1.0], vec![2.0], vec![3.0], vec![4.0]]);
+        assert_eq!(batch.usage.unwrap().total_tokens, Some(5));
+    }
+
+    #[tokio::test]
+    async fn failed_repairs_return_usage_of_every_attempt() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/services/aigc/text-generation/generation"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "output": { "text": "{}", "finish_reason": "stop" },
+                "usage": { "input_tokens": 10, "output_tokens": 2, "total_tokens": 12 }
+            })))
+            .expect(3)
+            .mount(&server)
+            .await;
+        let config = HashMap::from([
+            ("API_KEY".to_string(), "test-key".to_string()),
+            ("API_ENDPOINT".to_string(), format!("{}/api/v1", server.uri())),
+        ]);
+        let service = AIServiceImpl { json_max_repairs: 2, ..service_with(&[("tongyi", config)]) };
+        // 任何值都不满足的 Schema
+        let schema = serde_json::json!({ "not": {} });
+        let request = AIRequest {
+            messages: vec![ChatMessage::text(ChatRole::User, "hi")],
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: Some(schema),
+        };
+
+        let error = service.analyze(request).await.unwrap_err();
+
+        assert!(matches!(&error, AppError::AIFailedWithUsage { error, .. } if matches!(**error, AppError::AIServiceError(_))));
+        let usage = error.usage().unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens, usage.total_tokens), (Some(30), Some(6), Some(36)));
+    }
+
+    #[tokio::test]
+    async fn skips_fallback_that_rejects_params() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/services/aigc/text-generation/generation"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = |endpoint: String| HashMap::from([
+            ("API_KEY".to_string(), "test-key".to_string()),
+            ("API_ENDPOINT".to_string(), endpoint),
+        ]);
+        let service = service_with(&[
+            ("tongyi", config(format!("{}/api/v1", server.uri()))),
+            ("openai", config(format!("{}/v1", server.uri()))),
+        ]);
+        // enable_search 只有通义千问支持，备用的 OpenAI 被跳过而不是以 400 结束整条链
+        let request = AIRequest {
+            messages: vec![ChatMessage::text(ChatRole::User, "hi")],
+            provider: None,
+            model: None,
+            params: GenerationParams { enable_search: Some(true), ..GenerationParams::default() },
+            tools: Vec::new(),
+            response_schema: None,
+        };
+
+        let error = service.analyze(request).await.unwrap_err();
+
+        assert!(matches!(error, AppError::AIOverloaded { .. }), "got {:?}", error);
+    }
+
+    fn image_request(messages: Vec<ChatMessage>) -> AIRequest {
+        AIRequest {
+            messages,
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: Vec::new(),
+            response_schema: None,
+        }
+    }
+
+    fn encoded_image(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut buffer, format).unwrap();
+        buffer.into_inner()
+    }
+
+    async fn received_body(server: &MockServer) -> serde_json::Value {
+        let requests = server.received_requests().await.unwrap();
+        serde_json::from_slice(&requests[0].body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn downscales_images_to_provider_limit() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": "a banner" }, "finish_reason": "stop" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = HashMap::from([("API_ENDPOINT".to_string(), format!("{}/v1", server.uri()))]);
+        let service = service_with(&[("openai", config)]);
+        let banner = encode_png(&DynamicImage::new_rgb8(4096, 1024)).unwrap();
+
+        service.analyze(image_request(vec![AIInput::Image(banner).into_message(None)])).await.unwrap();
+
+        let body = received_body(&server).await;
+        let url = body["messages"][0]["content"][0]["image_url"]["url"].as_str().unwrap();
+        let data = STANDARD.decode(url.strip_prefix("data:image/jpeg;base64,").unwrap()).unwrap();
+        let image = decode_image(&data, sniff_image_kind(&data).unwrap(), 4096).unwrap();
+        assert_eq!((image.width(), image.height()), (2048, 512));
+    }
+
+    #[tokio::test]
+    async fn converts_formats_the_provider_does_not_accept() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/chat"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "message": { "role": "assistant", "content": "a gif" },
+                "done": true
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = HashMap::from([("API_ENDPOINT".to_string(), server.uri())]);
+        let service = service_with(&[("ollama", config)]);
+        let gif = encoded_image(DynamicImage::new_rgb8(8, 8), ImageFormat::Gif);
+
+        service.analyze(image_request(vec![AIInput::Image(gif).into_message(None)])).await.unwrap();
+
+        let body = received_body(&server).await;
+        let data = STANDARD.decode(body["messages"][0]["images"][0].as_str().unwrap()).unwrap();
+        assert_eq!(sniff_image_kind(&data), Some(ImageKind::Jpeg));
+    }
+
+    fn color(rgb: [u8; 3]) -> Vec<u8> {
+        encode_png(&DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb(rgb)))).unwrap()
+    }
+
+    // 两张上传图片和两个 URL 交替出现
+    fn mixed_message() -> ChatMessage {
+        ChatMessage {
+            role: ChatRole::User,
+            content: vec![
+                ContentPart::Image { data: color([255, 0, 0]) },
+                ContentPart::ImageUrl { url: "https://example.com/a.png".to_string() },
+                ContentPart::Image { data: color([0, 0, 255]) },
+                ContentPart::ImageUrl { url: "https://example.com/b.png".to_string() },
+                ContentPart::Text { text: "compare".to_string() },
+            ],
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    fn red_channel(data: &[u8]) -> u8 {
+        decode_image(data, sniff_image_kind(data).unwrap(), 64).unwrap().to_rgb8().get_pixel(4, 4)[0]
+    }
+
+    #[tokio::test]
+    async fn sends_every_image_and_url_in_order() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "choices": [{ "message": { "role": "assistant", "content": "different" }, "finish_reason": "stop" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = HashMap::from([("API_ENDPOINT".to_string(), format!("{}/v1", server.uri()))]);
+        let service = service_with(&[("openai", config)]);
+
+        service.analyze(image_request(vec![mixed_message()])).await.unwrap();
+
+        let body = received_body(&server).await;
+        let parts = body["messages"][0]["content"].as_array().unwrap();
+        let urls: Vec<&str> = parts[..4].iter().map(|part| part["image_url"]["url"].as_str().unwrap()).collect();
+        let decoded = |url: &str| STANDARD.decode(url.strip_prefix("data:image/png;base64,").unwrap()).unwrap();
+        assert!(red_channel(&decoded(urls[0])) > 200);
+        assert_eq!(urls[1], "https://example.com/a.png");
+        assert!(red_channel(&decoded(urls[2])) < 50);
+        assert_eq!(urls[3], "https://example.com/b.png");
+        assert_eq!(parts[4]["text"], "compare");
+    }
+
+    #[tokio::test]
+    async fn passes_image_urls_unchanged_to_capable_providers() {
+        for name in ["openai", "tongyi", "anthropic"] {
+            let config = HashMap::from([("API_KEY".to_string(), "test-key".to_string())]);
+            let service = service_with(&[(name, config)]);
+            let (name, entry) = service.registry.get(name).unwrap();
+
+            let request = service
+                .prepare_attempt(name, entry.provider.as_ref(), image_request(vec![mixed_message()]), &mut HashMap::new(), &mut PreparedImages::new())
+                .await
+                .unwrap();
+
+            let urls: Vec<&str> = request.messages[0].content.iter()
+                .filter_map(|part| match part {
+                    ContentPart::ImageUrl { url } => Some(url.as_str()),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(urls, ["https://example.com/a.png", "https://example.com/b.png"], "{}", name);
+            assert!(matches!(request.messages[0].content[2], ContentPart::Image { .. }), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn inlines_image_urls_for_providers_without_url_support() {
+        for name in ["ollama", "llamacpp"] {
+            let mut service = service_with(&[(name, HashMap::new())]);
+            service.image_fetcher = ImageFetcher::new(&AIImageConfig {
+                max_per_message: 4,
+                max_upload_bytes: 1024,
+                fetch_enabled: true,
+                fetch_max_bytes: 1024,
+                fetch_timeout_secs: 5,
+            });
+            let (name, entry) = service.registry.get(name).unwrap();
+            // 已下载的图片在重试和备用提供商之间复用，不再访问网络
+            let mut fetched = HashMap::from([
+                ("https://example.com/a.png".to_string(), color([255, 255, 255])),
+                ("https://example.com/b.png".to_string(), color([0, 0, 0])),
+            ]);
+
+            let request = service
+                .prepare_attempt(name, entry.provider.as_ref(), image_request(vec![mixed_message()]), &mut fetched, &mut PreparedImages::new())
+                .await
+                .unwrap();
+
+            let reds: Vec<u8> = request.messages[0].content[..4].iter()
+                .map(|part| match part {
+                    ContentPart::Image { data } => red_channel(data),
+                    other => panic!("{}: expected inlined image, got {:?}", name, other),
+                })
+                .collect();
+            assert!(reds[0] > 200 && reds[1] > 200 && reds[2] < 50 && reds[3] < 50, "{}: {:?}", name, reds);
+            assert!(matches!(&request.messages[0].content[4], ContentPart::Text { text } if text == "compare"));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_image_urls_when_fetch_is_disabled() {
+        let server = MockServer::start().await;
+        let config = HashMap::from([("API_ENDPOINT".to_string(), server.uri())]);
+        let service = service_with(&[("ollama", config)]);
+
+        let error = service.analyze(image_request(vec![mixed_message()])).await.unwrap_err();
+
+        assert!(matches!(error, AppError::AIInvalidRequest(_)), "got {:?}", error);
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn idle_stream_ends_with_timeout() {
+        let delta = Ok(StreamEvent::Delta { content: "hi".to_string() });
+        let stalled: AIStream = Box::pin(futures::stream::iter(vec![delta]).chain(futures::stream::pending()));
+
+        let events: Vec<_> = with_idle_timeout(stalled, Duration::from_millis(50)).collect().await;
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], Ok(StreamEvent::Delta { content }) if content == "hi"));
+        assert!(matches!(&events[1], Err(AppError::AITimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn done_event_carries_answering_provider_and_model() {
+        let done = |model: Option<&str>| {
+            Ok(StreamEvent::Done {
+                finish_reason: Some("stop".to_string()),
+                usage: None,
+                provider: None,
+                model: model.map(str::to_string),
+            })
+        };
+        let streams: [AIStream; 2] = [
+            Box::pin(futures::stream::iter(vec![Ok(StreamEvent::Delta { content: "hi".to_string() }), done(None)])),
+            Box::pin(futures::stream::iter(vec![done(Some("gpt-4o-2024-08-06"))])),
+        ];
+        let mut origins = Vec::new();
+        for stream in streams {
+            let events: Vec<_> = with_origin(stream, "openai".to_string(), "gpt-4o".to_string()).collect().await;
+            match events.last() {
+                Some(Ok(StreamEvent::Done { provider, model, .. })) => origins.push((provider.clone(), model.clone())),
+                other => panic!("expected done event, got {:?}", other),
+            }
+        }
+
+        assert_eq!(origins, [
+            (Some("openai".to_string()), Some("gpt-4o".to_string())),
+            (Some("openai".to_string()), Some("gpt-4o-2024-08-06".to_string())),
+        ]);
+    }
+}
@@ -0,0 +1,103 @@
+use jsonschema::Validator;
+use serde_json::Value;
+
+use crate::errors::AppError;
+use crate::models::ai::{AIRequest, ChatMessage, ChatRole, ContentPart};
+
+// 修复提示中最多列出的校验错误条数
+const MAX_REPORTED_ERRORS: usize = 10;
+
+pub fn compile(schema: &Value) -> Result<Validator, AppError> {
+    jsonschema::validator_for(schema)
+        .map_err(|e| AppError::ValidationError(format!("无效的 response_schema: {}", e)))
+}
+
+// 把输出要求写入 system 提示词；Anthropic 没有 JSON 模式，只能依靠提示词，通义千问的 JSON 模式也要求提示词中出现 JSON
+pub fn add_instruction(request: &mut AIRequest, schema: &Value) {
+    let instruction = format!(
+        "只输出一个符合以下 JSON Schema 的 JSON 值，不要使用 Markdown 代码块，也不要输出其他内容。\nJSON Schema: {}",
+        schema
+    );
+    match request.messages.first_mut() {
+        Some(message) if message.role == ChatRole::System => {
+            message.content.push(ContentPart::Text { text: instruction })
+        }
+        _ => request.messages.insert(0, ChatMessage::text(ChatRole::System, instruction)),
+    }
+}
+
+// 模型有时仍会用 ```json 代码块包住输出
+fn strip_code_fence(text: &str) -> &str {
+    let Some(inner) = text.strip_prefix("```").and_then(|inner| inner.strip_suffix("```")) else {
+        return text;
+    };
+    match inner.split_once('\n') {
+        Some((language, body)) if language.chars().all(|c| c.is_ascii_alphanumeric()) => body.trim(),
+        _ => inner.trim(),
+    }
+}
+
+// 解析并校验模型输出，失败时返回交给模型修复的错误列表
+pub fn parse_output(content: &str, validator: &Validator) -> Result<Value, Vec<String>> {
+    let value: Value = serde_json::from_str(strip_code_fence(content.trim()))
+        .map_err(|e| vec![format!("不是有效的 JSON: {}", e)])?;
+    let errors: Vec<String> = validator
+        .iter_errors(&value)
+        .take(MAX_REPORTED_ERRORS)
+        .map(|error| match error.instance_path.as_str() {
+            "" => error.to_string(),
+            path => format!("{}: {}", path, error),
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(value)
+    } else {
+        Err(errors)
+    }
+}
+
+// 把上一次的输出和校验错误追加到对话中，让模型修正
+pub fn add_repair_prompt(request: &mut AIRequest, output: String, errors: &[String]) {
+    if !output.trim().is_empty() {
+        request.messages.push(ChatMessage::text(ChatRole::Assistant, output));
+    }
+    request.messages.push(ChatMessage::text(
+        ChatRole::User,
+        format!(
+            "上面的输出不符合要求的 JSON Schema：\n- {}\n请只输出修正后的 JSON。",
+            errors.join("\n- ")
+        ),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn validator() -> Validator {
+        compile(&json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer", "minimum": 0 } },
+            "required": ["name", "age"]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_fenced_output() {
+        let output = "```json\n{\"name\": \"Ada\", \"age\": 36}\n```";
+
+        assert_eq!(parse_output(output, &validator()).unwrap(), json!({ "name": "Ada", "age": 36 }));
+    }
+
+    #[test]
+    fn reports_schema_violations_with_paths() {
+        let errors = parse_output(r#"{"name": "Ada", "age": -1}"#, &validator()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("/age: "), "{}", errors[0]);
+
+        let errors = parse_output("Ada is 36", &validator()).unwrap_err();
+        assert!(errors[0].starts_with("不是有效的 JSON"));
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::ai::{
+    AIRequest, AIResponse, ChatMessage, ChatRole, ContentPart, TokenUsage, ToolCall, ToolDefinition,
+};
+use super::quota::QuotaService;
+use super::service::AIService;
+
+// 单个工具的执行时间上限，超时按执行失败反馈给模型
+const TOOL_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_TOOLS_PER_REQUEST: usize = 64;
+
+// 执行工具时的调用方
+#[derive(Debug, Clone, Copy)]
+pub struct ToolContext {
+    pub user_id: Uuid,
+}
+
+// 服务端工具：definition 发给模型，模型调用时执行 call，返回值序列化后作为 tool 消息发回模型
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn definition(&self) -> ToolDefinition;
+
+    async fn call(&self, context: ToolContext, arguments: serde_json::Value) -> Result<serde_json::Value, AppError>;
+}
+
+// 通过 register_fn 注册的异步函数
+struct FnTool<F> {
+    definition: ToolDefinition,
+    function: F,
+}
+
+#[async_trait]
+impl<F, Fut> Tool for FnTool<F>
+where
+    F: Fn(ToolContext, serde_json::Value) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<serde_json::Value, AppError>> + Send,
+{
+    fn definition(&self) -> ToolDefinition {
+        self.definition.clone()
+    }
+
+    async fn call(&self, context: ToolContext, arguments: serde_json::Value) -> Result<serde_json::Value, AppError> {
+        (self.function)(context, arguments).await
+    }
+}
+
+// 查询调用者自己的 AI 额度
+struct UsageTool {
+    quota: QuotaService,
+}
+
+#[async_trait]
+impl Tool for UsageTool {
+    fn definition(&self) -> ToolDefinition {
+        ToolDefinition {
+            name: "get_ai_usage".to_string(),
+            description: "查询当前用户今日和本月的 AI token 用量、额度上限和重置时间".to_string(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        }
+    }
+
+    async fn call(&self, context: ToolContext, _arguments: serde_json::Value) -> Result<serde_json::Value, AppError> {
+        let status = self.quota.status(context.user_id).await?;
+        serde_json::to_value(status).map_err(|e| AppError::InternalError(format!("序列化额度失败: {}", e)))
+    }
+}
+
+// 服务端工具注册表和执行循环；启动时注册，请求通过 server_tools 按名称启用
+#[derive(Clone)]
+pub struct ToolRegistry {
+    tools: HashMap<String, Arc<dyn Tool>>,
+    max_steps: usize,
+}
+
+impl ToolRegistry {
+    pub fn new(max_steps: usize) -> Self {
+        Self {
+            tools: HashMap::new(),
+            max_steps,
+        }
+    }
+
+    // 内置工具：服务器当前时间和调用者的 AI 用量
+    pub fn with_builtin_tools(max_steps: usize, quota: QuotaService) -> Result<Self, AppError> {
+        let mut registry = Self::new(max_steps);
+        registry.register_fn(
+            ToolDefinition {
+                name: "current_time".to_string(),
+                description: "获取服务器当前的 UTC 时间".to_string(),
+                parameters: json!({ "type": "object", "properties": {} }),
+            },
+            |_, _| async {
+                let now = Utc::now();
+                Ok(json!({ "utc": now.to_rfc3339(), "unix": now.timestamp() }))
+            },
+        )?;
+        registry.register(UsageTool { quota })?;
+        Ok(registry)
+    }
+
+    pub fn register(&mut self, tool: impl Tool + 'static) -> Result<(), AppError> {
+        let definition = tool.definition();
+        definition.validate().map_err(|e| AppError::ConfigError(e.to_string()))?;
+        if self.tools.contains_key(&definition.name) {
+            return Err(AppError::ConfigError(format!("工具 {} 重复注册", definition.name)));
+        }
+        self.tools.insert(definition.name, Arc::new(tool));
+        Ok(())
+    }
+
+    pub fn register_fn<F, Fut>(&mut self, definition: ToolDefinition, function: F) -> Result<(), AppError>
+    where
+        F: Fn(ToolContext, serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<serde_json::Value, AppError>> + Send + 'static,
+    {
+        self.register(FnTool { definition, function })
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        let mut definitions: Vec<_> = self.tools.values().map(|tool| tool.definition()).collect();
+        definitions.sort_by(|a, b| a.name.cmp(&b.name));
+        definitions
+    }
+
+    // 把启用的服务端工具加入请求并校验全部工具；客户端工具不能与服务端工具同名
+    pub fn enable(&self, request: &mut AIRequest, server_tools: &[String]) -> Result<(), AppError> {
+        if let Some(tool) = request.tools.iter().find(|tool| self.tools.contains_key(&tool.name)) {
+            return Err(AppError::ValidationError(format!(
+                "工具 {} 与服务端工具同名，请通过 server_tools 启用", tool.name
+            )));
+        }
+        for name in server_tools {
+            let tool = self.tools.get(name)
+                .ok_or_else(|| AppError::ValidationError(format!("未知的服务端工具: {}", name)))?;
+            if !request.tools.iter().any(|enabled| enabled.name == *name) {
+                request.tools.push(tool.definition());
+            }
+        }
+
+        if request.tools.len() > MAX_TOOLS_PER_REQUEST {
+            return Err(AppError::ValidationError(format!("每个请求最多 {} 个工具", MAX_TOOLS_PER_REQUEST)));
+        }
+        let mut names = HashSet::new();
+        for tool in &request.tools {
+            tool.validate()?;
+            if !names.insert(tool.name.as_str()) {
+                return Err(AppError::ValidationError(format!("工具 {} 重复", tool.name)));
+            }
+        }
+        Ok(())
+    }
+
+    // 模型只调用服务端工具时执行并把结果追加为 tool 消息，直到模型给出最终回答；
+    // 出现客户端工具调用时直接返回，由客户端执行后再次请求。用量和耗时为所有轮次之和，
+    // 失败时已产生的用量随错误返回
+    pub async fn run(
+        &self,
+        ai_service: &dyn AIService,
+        context: ToolContext,
+        mut request: AIRequest,
+    ) -> Result<AIResponse, AppError> {
+        let started = Instant::now();
+        let server_tools: HashSet<String> = request.tools
+            .iter()
+            .filter(|tool| self.tools.contains_key(&tool.name))
+            .map(|tool| tool.name.clone())
+            .collect();
+        let mut usage = None;
+
+        for step in 0..self.max_steps {
+            let mut response = ai_service
+                .analyze(request.clone())
+                .await
+                .map_err(|e| e.with_usage(usage.clone()))?;
+            usage = TokenUsage::accumulate(usage, response.usage.take());
+
+            let server_only = response.tool_calls.iter().all(|call| server_tools.contains(&call.name));
+            if response.tool_calls.is_empty() || !server_only {
+                response.usage = usage;
+                response.latency_ms = started.elapsed().as_millis() as u64;
+                return Ok(response);
+            }
+
+            log::debug!("第 {} 轮执行 {} 个工具调用", step + 1, response.tool_calls.len());
+            let results = futures::future::join_all(
+                response.tool_calls.iter().map(|call| self.execute(context, call)),
+            )
+            .await;
+
+            let content = match response.content.is_empty() {
+                true => Vec::new(),
+                false => vec![ContentPart::Text { text: response.content }],
+            };
+            request.messages.push(ChatMessage {
+                role: ChatRole::Assistant,
+                content,
+                tool_calls: response.tool_calls.clone(),
+                tool_call_id: None,
+            });
+            for (call, result) in response.tool_calls.into_iter().zip(results) {
+                request.messages.push(ChatMessage {
+                    tool_call_id: Some(call.id),
+                    ..ChatMessage::text(ChatRole::Tool, result)
+                });
+            }
+        }
+
+        Err(AppError::AIInvalidRequest(format!("工具调用超过 {} 轮仍未得到最终回答", self.max_steps)).with_usage(usage))
+    }
+
+    // 执行失败时把错误作为结果交给模型，由模型决定如何继续
+    async fn execute(&self, context: ToolContext, call: &ToolCall) -> String {
+        let result = match (self.tools.get(&call.name), &call.arguments) {
+            (_, serde_json::Value::String(_)) => Err(AppError::ValidationError("参数不是有效的 JSON".to_string())),
+            (Some(tool), arguments) => tokio::time::timeout(TOOL_CALL_TIMEOUT, tool.call(context, arguments.clone()))
+                .await
+                .unwrap_or_else(|_| Err(AppError::ValidationError("工具执行超时".to_string()))),
+            (None, _) => Err(AppError::ValidationError(format!("未知的工具: {}", call.name))),
+        };
+
+        match result {
+            Ok(value) => value.to_string(),
+            Err(e) => {
+                log::warn!("工具 {} 执行失败: {:?}", call.name, e);
+                // 只把参数错误原样告诉模型，其他错误不暴露内部细节
+                let message = match e {
+                    AppError::ValidationError(message) => message,
+                    _ => "工具执行失败".to_string(),
+                };
+                json!({ "error": message }).to_string()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::models::ai::GenerationParams;
+    use crate::ai::providers::AIStream;
+
+    // 按顺序返回预设响应，并记录每一轮收到的请求
+    struct ScriptedService {
+        responses: Mutex<VecDeque<AIResponse>>,
+        requests: Mutex<Vec<AIRequest>>,
+    }
+
+    impl ScriptedService {
+        fn new(responses: Vec<AIResponse>) -> Self {
+            Self {
+                responses: Mutex::new(responses.into()),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl AIService for ScriptedService {
+        async fn analyze(&self, request: AIRequest) -> Result<AIResponse, AppError> {
+            self.requests.lock().unwrap().push(request);
+            self.responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| AppError::AIServiceError("no scripted response".to_string()))
+        }
+
+        async fn analyze_stream(&self, _request: AIRequest) -> Result<AIStream, AppError> {
+            Err(AppError::AIServiceError("not scripted".to_string()))
+        }
+    }
+
+    fn definition(name: &str) -> ToolDefinition {
+        ToolDefinition {
+            name: name.to_string(),
+            description: String::new(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        }
+    }
+
+    fn registry(max_steps: usize) -> ToolRegistry {
+        let mut registry = ToolRegistry::new(max_steps);
+        registry
+            .register_fn(definition("add"), |_, arguments| async move {
+                let sum = arguments["a"].as_i64().unwrap_or_default() + arguments["b"].as_i64().unwrap_or_default();
+                Ok(json!({ "sum": sum }))
+            })
+            .unwrap();
+        registry
+    }
+
+    fn request(registry: &ToolRegistry, client_tools: &[&str]) -> AIRequest {
+        let mut request = AIRequest {
+            messages: vec![ChatMessage::text(ChatRole::User, "what is 2 + 3?")],
+            provider: None,
+            model: None,
+            params: GenerationParams::default(),
+            tools: client_tools.iter().map(|name| definition(name)).collect(),
+            response_schema: None,
+        };
+        registry.enable(&mut request, &["add".to_string()]).unwrap();
+        request
+    }
+
+    fn call(id: &str, name: &str, arguments: serde_json::Value) -> ToolCall {
+        ToolCall { id: id.to_string(), name: name.to_string(), arguments }
+    }
+
+    fn tool_response(calls: Vec<ToolCall>, output_tokens: u64) -> AIResponse {
+        AIResponse {
+            tool_calls: calls,
+            usage: Some(TokenUsage { input_tokens: Some(10), output_tokens: Some(output_tokens), ..TokenUsage::default() }),
+            ..AIResponse::default()
+        }
+    }
+
+    fn context() -> ToolContext {
+        ToolContext { user_id: Uuid::nil() }
+    }
+
+    #[tokio::test]
+    async fn executes_server_tools_until_final_answer() {
+        let registry = registry(5);
+        let service = ScriptedService::new(vec![
+            tool_response(vec![call("call_0", "add", json!({ "a": 2, "b": 3 }))], 4),
+            AIResponse { content: "5".to_string(), ..tool_response(Vec::new(), 1) },
+        ]);
+
+        let response = registry.run(&service, context(), request(&registry, &[])).await.unwrap();
+
+        assert_eq!(response.content, "5");
+        let usage = response.usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (Some(20), Some(5)));
+        let requests = service.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].role, ChatRole::Assistant);
+        assert_eq!(messages[1].tool_calls[0].id, "call_0");
+        assert_eq!(messages[2].tool_call_id.as_deref(), Some("call_0"));
+        assert_eq!(messages[2].text_content(), r#"{"sum":5}"#);
+    }
+
+    #[tokio::test]
+    async fn client_tool_call_ends_the_loop() {
+        let registry = registry(5);
+        let service = ScriptedService::new(vec![tool_response(
+            vec![call("call_0", "add", json!({ "a": 1, "b": 1 })), call("call_1", "get_weather", json!({}))],
+            4,
+        )]);
+
+        let response = registry.run(&service, context(), request(&registry, &["get_weather"])).await.unwrap();
+
+        assert_eq!(response.tool_calls.len(), 2);
+        assert_eq!(service.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_steps() {
+        let registry = registry(2);
+        let looping = || tool_response(vec![call("call_0", "add", json!({ "a": 1, "b": 1 }))], 1);
+        let service = ScriptedService::new(vec![looping(), looping(), looping()]);
+
+        let result = registry.run(&service, context(), request(&registry, &[])).await;
+
+        let error = result.unwrap_err();
+        let AppError::AIFailedWithUsage { error: cause, .. } = &error else {
+            panic!("expected usage on the error, got {:?}", error);
+        };
+        assert!(matches!(**cause, AppError::AIInvalidRequest(_)));
+        let usage = error.usage().unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (Some(20), Some(2)));
+        assert_eq!(service.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_step_returns_usage_of_earlier_steps() {
+        let registry = registry(5);
+        // 第二轮没有预设响应，调用失败
+        let service = ScriptedService::new(vec![tool_response(
+            vec![call("call_0", "add", json!({ "a": 1, "b": 1 }))],
+            3,
+        )]);
+
+        let error = registry.run(&service, context(), request(&registry, &[])).await.unwrap_err();
+
+        let AppError::AIFailedWithUsage { error: cause, .. } = &error else {
+            panic!("expected usage on the error, got {:?}", error);
+        };
+        assert!(matches!(**cause, AppError::AIServiceError(_)));
+        let usage = error.usage().unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (Some(10), Some(3)));
+    }
+
+    #[tokio::test]
+    async fn unknown_tools_and_invalid_arguments_are_reported_to_the_model() {
+        let registry = registry(5);
+
+        let unknown = registry.execute(context(), &call("call_0", "delete_everything", json!({}))).await;
+        let invalid = registry.execute(context(), &call("call_1", "add", json!("{not json"))).await;
+
+        assert_eq!(unknown, json!({ "error": "未知的工具: delete_everything" }).to_string());
+        assert_eq!(invalid, json!({ "error": "参数不是有效的 JSON" }).to_string());
+    }
+}
@@ -0,0 +1,31 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{Datelike, Utc};
+use serde::Serialize;
+
+use crate::errors::AppError;
+use crate::models::ai_quota::ModelUsage;
+use super::handlers::current_user_id;
+use super::quota::{QuotaService, QuotaStatus};
+
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    #[serde(flatten)]
+    pub quota: QuotaStatus,
+    // 本月按提供商和模型的用量
+    pub models: Vec<ModelUsage>,
+}
+
+pub async fn my_usage(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    quota: web::Data<QuotaService>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = current_user_id(&req)?;
+    let status = quota.status(user_id).await?;
+
+    let today = Utc::now().date_naive();
+    let month_start = today.with_day(1).unwrap_or(today);
+    let models = ModelUsage::list_for_user(&db, user_id, month_start).await?;
+
+    Ok(HttpResponse::Ok().json(UsageResponse { quota: status, models }))
+}
@@ -0,0 +1,91 @@
+// src/auth/audit.rs
+use std::net::SocketAddr;
+
+use actix_web::http::header::{self, HeaderMap, HeaderName};
+use actix_web::{web, HttpRequest};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::models::audit::{AuditEvent, AuditEventType, NewAuditEvent};
+
+// 只有配置了可信代理层数时才读取 Forwarded / X-Forwarded-For，否则客户端可以任意伪造地址。
+// 每层代理把它看到的对端地址追加到末尾，最左边的条目由客户端填写，
+// 因此客户端地址是从右数第 proxy_hops 个条目；条目不足时取最左边的
+pub fn client_ip(req: &HttpRequest, proxy_hops: usize) -> Option<String> {
+    let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+    if proxy_hops == 0 {
+        return peer;
+    }
+    let forwarded = forwarded_for(req.headers());
+    match forwarded.len() {
+        0 => peer,
+        len => Some(normalize_ip(forwarded[len.saturating_sub(proxy_hops)])),
+    }
+}
+
+// 优先使用标准的 Forwarded 头中的 for 参数，没有时使用 X-Forwarded-For
+fn forwarded_for(headers: &HeaderMap) -> Vec<&str> {
+    let forwarded: Vec<&str> = header_list(headers, header::FORWARDED)
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then(|| value.trim_matches('"'))
+            })
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    header_list(headers, header::X_FORWARDED_FOR).collect()
+}
+
+// 同名的多个头按出现顺序拼接为一个列表
+fn header_list(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+// 去掉端口和 IPv6 的方括号
+fn normalize_ip(addr: &str) -> String {
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => addr.trim_start_matches('[').trim_end_matches(']').to_string(),
+    }
+}
+
+pub fn user_agent(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("User-Agent")
+        .and_then(|h| h.to_str().ok())
+        .map(|ua| ua.to_string())
+}
+
+// 审计日志写入失败不影响业务请求，只记录错误
+pub async fn record(
+    db: &crate::db::DbPool,
+    req: &HttpRequest,
+    event_type: AuditEventType,
+    actor_id: Option<Uuid>,
+    target_id: Option<Uuid>,
+    payload: serde_json::Value,
+) {
+    let proxy_hops = req
+        .app_data::<web::Data<Config>>()
+        .map_or(0, |config| config.proxy_hops());
+    let event = NewAuditEvent {
+        event_type,
+        actor_id,
+        target_id,
+        ip_address: client_ip(req, proxy_hops),
+        user_agent: user_agent(req),
+        payload,
+    };
+
+    if let Err(e) = AuditEvent::record(db, event).await {
+        log::error!("审计日志写入失败 ({}): {:?}", event_type, e);
+    }
+}
@@ -0,0 +1,62 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::audit::{AuditEvent, AuditEventType, AuditQuery};
+use crate::models::pagination::{Paginated, PaginationQuery};
+use super::audit;
+use super::auth_handlers::{get_claims_from_request, require_admin};
+
+pub async fn my_audit_events(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    query: web::Query<PaginationQuery>,
+) -> Result<HttpResponse, AppError> {
+    let claims = get_claims_from_request(&req)?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::InvalidId("无效的用户ID".into()))?;
+
+    let (events, total) = AuditEvent::list_for_user(&db, user_id, &query).await?;
+
+    Ok(HttpResponse::Ok().json(Paginated::new(events, &query, total)))
+}
+
+pub async fn list_audit_events(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, AppError> {
+    let admin = require_admin(&req, &db).await?;
+
+    let (events, total) = AuditEvent::search(&db, &query).await?;
+
+    audit::record(&db, &req, AuditEventType::AdminAction, Some(admin.id), query.actor_id.or(query.target_id), json!({
+        "action": "list_audit_events",
+        "actor_id": query.actor_id,
+        "target_id": query.target_id,
+        "event_type": query.event_type,
+    })).await;
+
+    Ok(HttpResponse::Ok().json(Paginated::new(events, &query.pagination(), total)))
+}
+
+pub async fn verify_audit_chain(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+) -> Result<HttpResponse, AppError> {
+    let admin = require_admin(&req, &db).await?;
+
+    let report = AuditEvent::verify_chain(&db).await?;
+    if !report.valid {
+        log::error!("审计日志哈希链校验失败，断点记录: {:?}", report.broken_at);
+    }
+
+    audit::record(&db, &req, AuditEventType::AdminAction, Some(admin.id), None, json!({
+        "action": "verify_audit_chain",
+        "valid": report.valid,
+        "checked": report.checked,
+    })).await;
+
+    Ok(HttpResponse::Ok().json(report))
+}
@@ -1,77 +1,116 @@
 use crate::errors::AppError;
+use actix_multipart::Multipart;
+use actix_web::http::header;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use serde::Deserialize;
 use uuid::Uuid;
 use serde_json::json;
 use crate::auth::oauth::models::OAuthTokenRequest;
 use crate::auth::oauth::{google, facebook};
-use crate::models::user::{Claims, User, DEFAULT_AVATAR};
+use crate::models::audit::AuditEventType;
+use crate::models::user::{Claims, UpdateProfileRequest, UpdateUserRequest, User};
 use crate::models::user::{AuthResponse, LoginRequest, RegisterRequest, UserResponse};
+use crate::config::Config;
+use crate::service::storage::Storage;
+use super::audit;
+use super::avatar::{delete_avatar, identicon_etag, store_avatar, Identicon};
+use super::avatar::{IDENTICON_DEFAULT_SIZE, IDENTICON_MAX_SIZE, IDENTICON_MIN_SIZE};
+use super::validation::{sanitize_username, validate_email, validate_username};
+use super::validation::{validate_bio, validate_display_name, validate_locale, validate_timezone};
 use super::utils::{generate_jwt, hash_password, verify_password};
 
 pub async fn register(
+    req: HttpRequest,
     db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
     data: web::Json<RegisterRequest>,
 ) -> Result<impl Responder, AppError> {
-    if User::find_by_email(&db, &data.email).await?.is_some() {
+    let email = validate_email(&data.email)?;
+    let username = validate_username(&data.username, &config.username_policy)?;
+
+    if User::find_by_email(&db, &email).await?.is_some() {
         return Err(AppError::ValidationError("邮箱已注册".to_string()));
     }
     
-    if User::find_by_username(&db, &data.username).await?.is_some() {
+    if User::find_by_username(&db, &username).await?.is_some() {
         return Err(AppError::ValidationError("用户名已存在".to_string()));
     }
+
+    if User::find_by_username_skeleton(&db, &username).await?.is_some() {
+        return Err(AppError::ValidationError("用户名与已有用户过于相似".to_string()));
+    }
+
+    if User::is_username_reserved(&db, &username, None, config.username_policy.reservation_days).await? {
+        return Err(AppError::ValidationError("该用户名暂时不可用".to_string()));
+    }
     
     if data.password.len() < 8 {
         return Err(AppError::ValidationError("密码长度必须至少为8位".to_string()));
     }
     
     let hashed_password = hash_password(&data.password)?;
-    let user = User::create(&db, data.email.clone(), data.username.clone(), hashed_password).await?;
+    let user = User::create(&db, email, username, hashed_password).await?;
     
     let token = generate_jwt(&user.id.to_string())?;
+
+    audit::record(&db, &req, AuditEventType::Register, Some(user.id), Some(user.id), json!({
+        "email": user.email,
+        "username": user.username,
+    })).await;
     
     let response = AuthResponse {
         token,
-        user: UserResponse {
-            id: user.id.to_string(),
-            email: user.email,
-            username: user.username,
-            avatar: user.avatar_url.unwrap_or_else(|| DEFAULT_AVATAR.to_string()),
-        },
+        user: user.into(),
     };
     
     Ok(HttpResponse::Created().json(response))
 }
 
 pub async fn login(
+    req: HttpRequest,
     db: web::Data<crate::db::DbPool>,
     data: web::Json<LoginRequest>,
 ) -> Result<impl Responder, AppError> {
-    let user = User::find_by_email(&db, &data.email).await?
-        .ok_or_else(|| AppError::AuthenticationError("用户名或密码错误".to_string()))?;
+    let user = match User::find_by_email(&db, &data.email).await? {
+        Some(user) => user,
+        None => {
+            audit::record(&db, &req, AuditEventType::LoginFailure, None, None, json!({
+                "email": data.email,
+                "reason": "unknown_email",
+            })).await;
+            return Err(AppError::AuthenticationError("用户名或密码错误".to_string()));
+        }
+    };
 
     if user.password_hash.is_empty() {
+        audit::record(&db, &req, AuditEventType::LoginFailure, None, Some(user.id), json!({
+            "email": data.email,
+            "reason": "password_login_unsupported",
+        })).await;
         return Err(AppError::AuthenticationError("账户不支持密码登录".to_string()));
     }
 
     if !verify_password(&data.password, &user.password_hash)? {
+        audit::record(&db, &req, AuditEventType::LoginFailure, None, Some(user.id), json!({
+            "email": data.email,
+            "reason": "wrong_password",
+        })).await;
         return Err(AppError::AuthenticationError("用户名或密码错误".to_string()));
     }
 
     let token = generate_jwt(&user.id.to_string())?;
 
+    audit::record(&db, &req, AuditEventType::LoginSuccess, Some(user.id), Some(user.id), json!({
+        "email": user.email,
+    })).await;
+
     let user_email = user.email.clone();
-    let user_id = user.id.to_string();
-    let user_username = user.username.clone();
-    let user_avatar = user.avatar_url.clone();
 
     let response = AuthResponse {
         token,
-        user: UserResponse {
-            id: user_id,
-            email: user_email.clone(),
-            username: user_username,
-            avatar: user_avatar.unwrap_or_else(|| DEFAULT_AVATAR.to_string()),
-        },
+        user: user.into(),
     };
 
     log::info!("用户登录成功: {}", user_email);
@@ -79,7 +118,9 @@ pub async fn login(
 }
 
 pub async fn oauth_login(
+    req: HttpRequest,
     db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
     data: web::Json<OAuthTokenRequest>,
 ) -> Result<impl Responder, AppError> {
     let user_profile = match data.provider.as_str() {
@@ -88,31 +129,31 @@ pub async fn oauth_login(
         _ => return Err(AppError::ValidationError(format!("不支持的 OAuth 提供商: {}", data.provider))),
     };
 
+    let display_name = user_profile.name.unwrap_or_else(|| user_profile.email.split('@').next().unwrap_or("user").to_string());
+
     let user = User::find_or_create_oauth_user(
         &db,
-        user_profile.email.clone(),
-        user_profile.name.unwrap_or_else(|| user_profile.email.split('@').next().unwrap_or("user").to_string()),
+        user_profile.email.trim().to_string(),
+        sanitize_username(&display_name, &config.username_policy),
         user_profile.picture,
         user_profile.provider.clone(),
         user_profile.provider_user_id.clone(),
+        &config.username_policy,
     ).await?;
 
     let token = generate_jwt(&user.id.to_string())?;
 
+    audit::record(&db, &req, AuditEventType::OAuthLogin, Some(user.id), Some(user.id), json!({
+        "email": user.email,
+        "provider": data.provider,
+    })).await;
+
     let user_email = user.email.clone();
-    let user_id = user.id.to_string();
-    let user_username = user.username.clone();
-    let user_avatar = user.avatar_url.clone();
     let provider_name = data.provider.clone();
 
     let response = AuthResponse {
         token,
-        user: UserResponse {
-            id: user_id,
-            email: user_email.clone(),
-            username: user_username,
-            avatar: user_avatar.unwrap_or_else(|| DEFAULT_AVATAR.to_string()),
-        },
+        user: user.into(),
     };
 
     log::info!("OAuth 登录成功: {} ({})", user_email, provider_name);
@@ -130,10 +171,30 @@ pub fn get_claims_from_request(req: &HttpRequest) -> Result<Claims, AppError> {
     crate::auth::utils::verify_jwt(token)
 }
 
+pub async fn require_admin(
+    req: &HttpRequest,
+    db: &crate::db::DbPool,
+) -> Result<User, AppError> {
+    let claims = get_claims_from_request(req)?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::InvalidId("无效的用户ID".into()))?;
+
+    let user = User::find_by_id(db, user_id).await?
+        .ok_or_else(|| AppError::AuthenticationError("用户不存在".to_string()))?;
+
+    if !user.is_admin() {
+        return Err(AppError::PermissionDenied("需要管理员权限".to_string()));
+    }
+
+    Ok(user)
+}
+
 pub async fn update_avatar(
     req: HttpRequest,
     db: web::Data<crate::db::DbPool>,
-    avatar: web::Json<String>,
+    config: web::Data<Config>,
+    storage: web::Data<dyn Storage>,
+    mut payload: Multipart,
 ) -> Result<HttpResponse, AppError> {
     let claims = get_claims_from_request(&req)?;
     let user_id = Uuid::parse_str(&claims.sub)
@@ -142,18 +203,88 @@ pub async fn update_avatar(
     let mut user = User::find_by_id(&db, user_id).await?
         .ok_or_else(|| AppError::AuthenticationError("用户不存在".to_string()))?;
 
-    let update_req = crate::models::user::UpdateUserRequest {
-        username: None,
-        avatar_url: Some(avatar.0.clone()),
+    let max_bytes = config.avatar.max_bytes;
+    let mut image = None;
+
+    while let Some(mut field) = payload.try_next().await? {
+        let content_type = field.content_disposition();
+        let name = content_type.get_name().ok_or_else(|| 
+            AppError::ValidationError("Invalid form field".to_string()))?;
+
+        if name == "avatar" {
+            let mut bytes = web::BytesMut::new();
+            while let Some(chunk) = field.try_next().await? {
+                if bytes.len() + chunk.len() > max_bytes {
+                    return Err(AppError::ValidationError(format!("头像文件不能超过 {} 字节", max_bytes)));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            image = Some(bytes.freeze().to_vec());
+        }
+    }
+
+    let image_data = image.ok_or_else(|| 
+        AppError::ValidationError("Avatar is required".to_string()))?;
+
+    let stored = store_avatar(storage.as_ref(), &config.avatar, user.id, image_data).await?;
+    let avatar_url = stored.first()
+        .map(|avatar| avatar.url.clone())
+        .ok_or_else(|| AppError::InternalError("头像处理结果为空".to_string()))?;
+
+    let old_avatar = user.avatar_url.clone();
+    let update_req = UpdateUserRequest {
+        avatar_url: Some(avatar_url.clone()),
+        ..Default::default()
     };
     
     user.update(&db, update_req).await?;
 
+    if let Some(ref old_url) = old_avatar {
+        if *old_url != avatar_url {
+            delete_avatar(storage.as_ref(), &config.avatar, user.id, old_url).await;
+        }
+    }
+
+    audit::record(&db, &req, AuditEventType::AvatarChanged, Some(user.id), Some(user.id), json!({
+        "old_avatar": old_avatar,
+        "new_avatar": avatar_url,
+    })).await;
+
+    let sizes: serde_json::Map<String, serde_json::Value> = stored
+        .into_iter()
+        .map(|avatar| (avatar.size.to_string(), json!(avatar.url)))
+        .collect();
+
     Ok(HttpResponse::Ok().json(json!({
-        "avatar": avatar.0
+        "avatar": avatar_url,
+        "sizes": sizes,
     })))
 }
 
+// 本地存储的媒体文件直接返回，外部存储重定向到公开地址
+pub async fn serve_media(
+    storage: web::Data<dyn Storage>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, AppError> {
+    let key = path.into_inner();
+
+    if let Some(url) = storage.public_url(&key) {
+        return Ok(HttpResponse::Found()
+            .insert_header((header::LOCATION, url))
+            .finish());
+    }
+
+    let object = storage.get(&key).await?
+        .ok_or_else(|| AppError::NotFound("文件不存在".to_string()))?;
+
+    // 对象键包含内容哈希，可以长期缓存
+    Ok(HttpResponse::Ok()
+        .content_type(object.content_type)
+        .insert_header((header::CACHE_CONTROL, "public, max-age=31536000, immutable"))
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        .body(object.data))
+}
+
 pub async fn get_profile(
     req: HttpRequest,
     db: web::Data<crate::db::DbPool>,
@@ -165,10 +296,132 @@ pub async fn get_profile(
     let user = User::find_by_id(&db, user_id).await?
         .ok_or_else(|| AppError::AuthenticationError("用户不存在".to_string()))?;
 
-    Ok(HttpResponse::Ok().json(UserResponse {
-        id: user.id.to_string(),
-        email: user.email,
-        username: user.username,
-        avatar: user.avatar_url.unwrap_or_else(|| DEFAULT_AVATAR.to_string()),
-    }))
+    Ok(HttpResponse::Ok().json(UserResponse::from(user)))
+}
+
+pub async fn update_profile(
+    req: HttpRequest,
+    db: web::Data<crate::db::DbPool>,
+    config: web::Data<Config>,
+    data: web::Json<UpdateProfileRequest>,
+) -> Result<HttpResponse, AppError> {
+    let claims = get_claims_from_request(&req)?;
+    let user_id = Uuid::parse_str(&claims.sub)
+        .map_err(|_| AppError::InvalidId("无效的用户ID".into()))?;
+
+    let mut user = User::find_by_id(&db, user_id).await?
+        .ok_or_else(|| AppError::AuthenticationError("用户不存在".to_string()))?;
+
+    let data = data.into_inner();
+    let policy = &config.username_policy;
+
+    let username = match data.username {
+        Some(ref requested) => {
+            let username = validate_username(requested, policy)?;
+            if username != user.username {
+                if let Some(changed_at) = user.username_changed_at {
+                    let next_allowed = changed_at + Duration::days(policy.change_cooldown_days);
+                    if Utc::now() < next_allowed {
+                        return Err(AppError::ValidationError(format!(
+                            "用户名修改过于频繁，请在 {} 之后再试",
+                            next_allowed.to_rfc3339()
+                        )));
+                    }
+                }
+
+                // 允许只修改大小写等与自己旧用户名相似的情况
+                if let Some(existing) = User::find_by_username_skeleton(&db, &username).await? {
+                    if existing.id != user.id {
+                        return Err(AppError::ValidationError("用户名已存在或与已有用户过于相似".to_string()));
+                    }
+                }
+
+                if User::is_username_reserved(&db, &username, Some(user.id), policy.reservation_days).await? {
+                    return Err(AppError::ValidationError("该用户名暂时不可用".to_string()));
+                }
+            }
+            Some(username)
+        }
+        None => None,
+    };
+
+    let update_req = UpdateUserRequest {
+        username,
+        avatar_url: None,
+        display_name: data.display_name.as_deref().map(validate_display_name).transpose()?,
+        bio: data.bio.as_deref().map(validate_bio).transpose()?,
+        locale: data.locale.as_deref().map(validate_locale).transpose()?,
+        timezone: data.timezone.as_deref().map(validate_timezone).transpose()?,
+    };
+
+    let old_username = user.username.clone();
+    let changed_fields: Vec<&str> = [
+        ("username", update_req.username.is_some()),
+        ("display_name", update_req.display_name.is_some()),
+        ("bio", update_req.bio.is_some()),
+        ("locale", update_req.locale.is_some()),
+        ("timezone", update_req.timezone.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(field, _)| field)
+    .collect();
+
+    user.update(&db, update_req).await?;
+
+    if !changed_fields.is_empty() {
+        audit::record(&db, &req, AuditEventType::ProfileUpdated, Some(user.id), Some(user.id), json!({
+            "fields": changed_fields,
+            "old_username": old_username,
+            "new_username": user.username,
+        })).await;
+    }
+
+    Ok(HttpResponse::Ok().json(UserResponse::from(user)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GeneratedAvatarQuery {
+    pub format: Option<String>,
+    pub size: Option<u32>,
+}
+
+// 按用户 ID 生成的默认头像，结果确定，可长期缓存
+pub async fn generated_avatar(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<GeneratedAvatarQuery>,
+) -> Result<HttpResponse, AppError> {
+    let user_id = path.into_inner();
+    let size = query.size
+        .unwrap_or(IDENTICON_DEFAULT_SIZE)
+        .clamp(IDENTICON_MIN_SIZE, IDENTICON_MAX_SIZE);
+    let format = query.format.as_deref().unwrap_or("svg");
+
+    let etag = identicon_etag(user_id, format, size);
+    let cache_control = (header::CACHE_CONTROL, "public, max-age=604800");
+
+    let not_modified = req.headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag));
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .insert_header(cache_control)
+            .finish());
+    }
+
+    let identicon = Identicon::new(user_id);
+    let (content_type, body) = match format {
+        "svg" => ("image/svg+xml", identicon.to_svg(size).into_bytes()),
+        "png" => ("image/png", identicon.to_png(size)?),
+        _ => return Err(AppError::ValidationError(format!("不支持的头像格式: {}", format))),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::ETAG, etag))
+        .insert_header(cache_control)
+        .body(body))
 }
\ No newline at end of file
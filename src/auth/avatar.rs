@@ -0,0 +1,225 @@
+// src/auth/avatar.rs
+use actix_web::web;
+use image::{DynamicImage, Rgb, RgbImage};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::AvatarConfig;
+use crate::errors::AppError;
+use crate::service::image_processing::{decode_image, encode_png, sniff_image_kind, square_thumbnail, ImageKind};
+use crate::service::storage::Storage;
+
+const ALLOWED_AVATAR_KINDS: &[ImageKind] = &[ImageKind::Jpeg, ImageKind::Png, ImageKind::Gif, ImageKind::WebP];
+
+fn avatar_key(user_id: Uuid, digest: &str, size: u32) -> String {
+    format!("avatars/{}/{}_{}.png", user_id, digest, size)
+}
+
+pub struct StoredAvatar {
+    pub size: u32,
+    pub url: String,
+}
+
+// 校验上传的头像并生成各尺寸缩略图，重新编码后去除 EXIF 等元数据
+pub async fn store_avatar(
+    storage: &dyn Storage,
+    config: &AvatarConfig,
+    user_id: Uuid,
+    data: Vec<u8>,
+) -> Result<Vec<StoredAvatar>, AppError> {
+    if data.len() > config.max_bytes {
+        return Err(AppError::ValidationError(format!("头像文件不能超过 {} 字节", config.max_bytes)));
+    }
+
+    let kind = sniff_image_kind(&data)
+        .filter(|kind| ALLOWED_AVATAR_KINDS.contains(kind))
+        .ok_or_else(|| AppError::ValidationError("不支持的图片格式，仅支持 JPEG/PNG/GIF/WebP".to_string()))?;
+
+    let digest = hex::encode(Sha256::digest(&data))[..16].to_string();
+    let max_dimension = config.max_dimension;
+    let sizes = config.sizes.clone();
+
+    // 解码和缩放是 CPU 密集操作，放到阻塞线程池
+    let variants = web::block(move || -> Result<Vec<(u32, Vec<u8>)>, AppError> {
+        let image = decode_image(&data, kind, max_dimension)?;
+        sizes
+            .into_iter()
+            .map(|size| Ok((size, encode_png(&square_thumbnail(&image, size))?)))
+            .collect()
+    })
+    .await
+    .map_err(|e| AppError::InternalError(format!("头像处理任务失败: {}", e)))??;
+
+    let mut stored = Vec::with_capacity(variants.len());
+    for (size, bytes) in variants {
+        let key = avatar_key(user_id, &digest, size);
+        storage.put(&key, bytes, "image/png").await?;
+        stored.push(StoredAvatar {
+            size,
+            url: storage.url_for(&key),
+        });
+    }
+
+    Ok(stored)
+}
+
+// 删除旧头像的全部尺寸，外部 URL（如 OAuth 头像）不处理
+pub async fn delete_avatar(
+    storage: &dyn Storage,
+    config: &AvatarConfig,
+    user_id: Uuid,
+    avatar_url: &str,
+) {
+    let Some(key) = storage.key_from_url(avatar_url) else {
+        return;
+    };
+    let prefix = format!("avatars/{}/", user_id);
+    let Some(digest) = key
+        .strip_prefix(&prefix)
+        .and_then(|name| name.split('_').next())
+    else {
+        return;
+    };
+
+    for size in &config.sizes {
+        if let Err(e) = storage.delete(&avatar_key(user_id, digest, *size)).await {
+            log::warn!("删除旧头像失败 {}: {:?}", user_id, e);
+        }
+    }
+}
+
+// 生成头像的网格大小（左右对称）和背景色
+const IDENTICON_GRID: u32 = 5;
+const IDENTICON_BACKGROUND: [u8; 3] = [0xF0, 0xF0, 0xF0];
+pub const IDENTICON_DEFAULT_SIZE: u32 = 128;
+pub const IDENTICON_MIN_SIZE: u32 = 16;
+pub const IDENTICON_MAX_SIZE: u32 = 512;
+
+// 生成结果只取决于参数，修改图案算法时需要更新版本号
+pub fn identicon_etag(user_id: Uuid, format: &str, size: u32) -> String {
+    format!("\"identicon-v1-{}-{}-{}\"", user_id, format, size)
+}
+
+// 由用户 ID 确定的对称图案和颜色，同一用户每次生成结果相同
+pub struct Identicon {
+    cells: [[bool; IDENTICON_GRID as usize]; IDENTICON_GRID as usize],
+    color: [u8; 3],
+}
+
+impl Identicon {
+    pub fn new(user_id: Uuid) -> Self {
+        let hash = Sha256::digest(user_id.as_bytes());
+
+        let hue = u16::from_be_bytes([hash[0], hash[1]]) as f64 / u16::MAX as f64 * 360.0;
+        let color = hsl_to_rgb(hue, 0.55, 0.55);
+
+        let mut cells = [[false; IDENTICON_GRID as usize]; IDENTICON_GRID as usize];
+        let half = IDENTICON_GRID.div_ceil(2) as usize;
+        for (row, cells_row) in cells.iter_mut().enumerate() {
+            for col in 0..half {
+                let filled = hash[2 + row * half + col] % 2 == 0;
+                cells_row[col] = filled;
+                cells_row[IDENTICON_GRID as usize - 1 - col] = filled;
+            }
+        }
+
+        Self { cells, color }
+    }
+
+    fn filled_cells(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.cells.iter().enumerate().flat_map(|(row, cells_row)| {
+            cells_row
+                .iter()
+                .enumerate()
+                .filter(|(_, filled)| **filled)
+                .map(move |(col, _)| (col as u32, row as u32))
+        })
+    }
+
+    pub fn to_svg(&self, size: u32) -> String {
+        // 网格外留半格边距
+        let units = IDENTICON_GRID + 1;
+        let [r, g, b] = self.color;
+        let [br, bg, bb] = IDENTICON_BACKGROUND;
+
+        let rects: String = self
+            .filled_cells()
+            .map(|(x, y)| format!(
+                r#"<rect x="{}" y="{}" width="2" height="2"/>"#,
+                x * 2 + 1,
+                y * 2 + 1
+            ))
+            .collect();
+
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {view} {view}" shape-rendering="crispEdges"><rect width="{view}" height="{view}" fill="#{br:02x}{bg:02x}{bb:02x}"/><g fill="#{r:02x}{g:02x}{b:02x}">{rects}</g></svg>"##,
+            size = size,
+            view = units * 2,
+        )
+    }
+
+    pub fn to_png(&self, size: u32) -> Result<Vec<u8>, AppError> {
+        let units = (IDENTICON_GRID + 1) * 2;
+        let mut image = RgbImage::from_pixel(size, size, Rgb(IDENTICON_BACKGROUND));
+        let filled: Vec<(u32, u32)> = self.filled_cells().collect();
+
+        for (px, py, pixel) in image.enumerate_pixels_mut() {
+            // 像素中心映射到网格坐标
+            let ux = (px * 2 + 1) * units / (size * 2);
+            let uy = (py * 2 + 1) * units / (size * 2);
+            if ux == 0 || uy == 0 || ux >= units - 1 || uy >= units - 1 {
+                continue;
+            }
+            if filled.contains(&((ux - 1) / 2, (uy - 1) / 2)) {
+                *pixel = Rgb(self.color);
+            }
+        }
+
+        encode_png(&DynamicImage::ImageRgb8(image))
+    }
+}
+
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    [
+        ((r + m) * 255.0).round() as u8,
+        ((g + m) * 255.0).round() as u8,
+        ((b + m) * 255.0).round() as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identicon_is_deterministic() {
+        let user_id = Uuid::parse_str("7c9e6679-7425-40de-944b-e07fc1f90ae7").unwrap();
+
+        assert_eq!(Identicon::new(user_id).to_svg(64), Identicon::new(user_id).to_svg(64));
+        assert_eq!(Identicon::new(user_id).to_png(64).unwrap(), Identicon::new(user_id).to_png(64).unwrap());
+        assert_ne!(Identicon::new(user_id).to_svg(64), Identicon::new(Uuid::nil()).to_svg(64));
+    }
+
+    #[test]
+    fn etag_is_stable() {
+        let user_id = Uuid::parse_str("7c9e6679-7425-40de-944b-e07fc1f90ae7").unwrap();
+
+        assert_eq!(
+            identicon_etag(user_id, "png", 128),
+            "\"identicon-v1-7c9e6679-7425-40de-944b-e07fc1f90ae7-png-128\""
+        );
+        assert_ne!(identicon_etag(user_id, "png", 128), identicon_etag(user_id, "svg", 128));
+    }
+}
@@ -1,4 +1,8 @@
 pub mod utils;
 pub mod auth_handlers;
+pub mod audit;
+pub mod avatar;
+pub mod audit_handlers;
 pub mod oauth;
-pub mod routes;
\ No newline at end of file
+pub mod routes;
+pub mod validation;
\ No newline at end of file
@@ -1,5 +1,6 @@
 use actix_web::web;
-use crate::auth::auth_handlers;
+use crate::ai::admin_handlers as ai_admin_handlers;
+use crate::auth::{audit_handlers, auth_handlers};
 
 pub fn auth_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -11,6 +12,27 @@ pub fn auth_config(cfg: &mut web::ServiceConfig) {
     .service(
         web::scope("/user")
             .route("/profile", web::get().to(auth_handlers::get_profile))
+            .route("/profile", web::patch().to(auth_handlers::update_profile))
             .route("/update_avatar", web::put().to(auth_handlers::update_avatar))
+            .route("/audit_events", web::get().to(audit_handlers::my_audit_events))
+    )
+    .service(
+        web::scope("/avatars")
+            .route("/{user_id}", web::get().to(auth_handlers::generated_avatar))
+    )
+    .service(
+        web::scope("/media")
+            .route("/{key:.*}", web::get().to(auth_handlers::serve_media))
+    )
+    .service(
+        web::scope("/admin")
+            .route("/audit_events", web::get().to(audit_handlers::list_audit_events))
+            .route("/audit_events/verify", web::get().to(audit_handlers::verify_audit_chain))
+            .route("/ai/providers", web::get().to(ai_admin_handlers::provider_status))
+            .route("/ai/providers/{provider}/reset", web::post().to(ai_admin_handlers::reset_provider_circuit))
+            .route("/ai/usage", web::get().to(ai_admin_handlers::usage_report))
+            .route("/ai/plans", web::get().to(ai_admin_handlers::list_plans))
+            .route("/ai/plans/{name}", web::put().to(ai_admin_handlers::update_plan))
+            .route("/ai/users/{user_id}/quota", web::put().to(ai_admin_handlers::update_user_quota))
     );
 }
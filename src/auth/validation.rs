@@ -0,0 +1,313 @@
+// src/auth/validation.rs
+use email_address::{EmailAddress, Options};
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{skeleton, MixedScript};
+
+use chrono_tz::Tz;
+
+use crate::config::UsernamePolicy;
+use crate::errors::AppError;
+
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_DISPLAY_NAME_LENGTH: usize = 100;
+const MAX_BIO_LENGTH: usize = 500;
+// users.locale 为 VARCHAR(35)，即 BCP 47 建议支持的最大长度
+const MAX_LOCALE_LENGTH: usize = 35;
+
+// 邮箱规范形式：去除首尾空白、NFC 归一化、整体小写
+pub fn normalize_email(email: &str) -> String {
+    email.trim().nfc().collect::<String>().to_lowercase()
+}
+
+// 校验 RFC 5322 邮箱语法，返回规范化后的邮箱
+pub fn validate_email(email: &str) -> Result<String, AppError> {
+    let normalized = normalize_email(email);
+    if normalized.is_empty() {
+        return Err(AppError::ValidationError("邮箱不能为空".to_string()));
+    }
+    if normalized.len() > MAX_EMAIL_LENGTH {
+        return Err(AppError::ValidationError("邮箱长度超出限制".to_string()));
+    }
+
+    let options = Options::default()
+        .with_required_tld()
+        .without_domain_literal()
+        .without_display_text();
+    EmailAddress::parse_with_options(&normalized, options)
+        .map_err(|e| AppError::ValidationError(format!("邮箱格式无效: {}", e)))?;
+
+    Ok(normalized)
+}
+
+// UTS #39 混淆骨架，大小写不敏感，用于检测 "paypal" / "раураl" 这类冒充
+pub fn username_skeleton(username: &str) -> String {
+    let lowered = username.nfc().collect::<String>().to_lowercase();
+    skeleton(&lowered).collect()
+}
+
+fn is_allowed_char(c: char, policy: &UsernamePolicy) -> bool {
+    if policy.extra_chars.contains(c) {
+        return true;
+    }
+    if policy.allow_unicode {
+        c.is_alphanumeric()
+    } else {
+        c.is_ascii_alphanumeric()
+    }
+}
+
+fn is_reserved(username: &str, policy: &UsernamePolicy) -> bool {
+    let skeleton = username_skeleton(username);
+    policy
+        .reserved
+        .iter()
+        .any(|reserved| username_skeleton(reserved) == skeleton)
+}
+
+// 校验用户名字符集、长度和保留名，返回 NFC 归一化后的用户名
+pub fn validate_username(username: &str, policy: &UsernamePolicy) -> Result<String, AppError> {
+    let username: String = username.trim().nfc().collect();
+    let length = username.chars().count();
+
+    if length < policy.min_length || length > policy.max_length {
+        return Err(AppError::ValidationError(format!(
+            "用户名长度必须在{}到{}个字符之间",
+            policy.min_length, policy.max_length
+        )));
+    }
+
+    if let Some(c) = username.chars().find(|c| !is_allowed_char(*c, policy)) {
+        return Err(AppError::ValidationError(format!("用户名包含不允许的字符: {:?}", c)));
+    }
+
+    if !username.chars().next().is_some_and(|c| c.is_alphanumeric()) {
+        return Err(AppError::ValidationError("用户名必须以字母或数字开头".to_string()));
+    }
+
+    if policy.allow_unicode && !username.as_str().is_single_script() {
+        return Err(AppError::ValidationError("用户名不能混用多种书写系统".to_string()));
+    }
+
+    if is_reserved(&username, policy) {
+        return Err(AppError::ValidationError("该用户名为系统保留".to_string()));
+    }
+
+    Ok(username)
+}
+
+// 将 OAuth 返回的显示名转换为合法用户名，非法字符替换为下划线
+pub fn sanitize_username(name: &str, policy: &UsernamePolicy) -> String {
+    let mut candidate: String = name
+        .trim()
+        .nfc()
+        .map(|c| if is_allowed_char(c, policy) { c } else { '_' })
+        .collect::<String>()
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .chars()
+        .take(policy.max_length)
+        .collect();
+
+    if validate_username(&candidate, policy).is_err() {
+        candidate = candidate
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+            .take(policy.max_length.saturating_sub(5))
+            .collect();
+        if candidate.is_empty() || is_reserved(&candidate, policy) {
+            candidate = format!("user_{}", candidate);
+        }
+        while candidate.chars().count() < policy.min_length {
+            candidate.push('0');
+        }
+    }
+
+    candidate
+}
+
+// 追加 _N 后缀，截断基础部分使整体不超过 max_length
+pub fn username_with_suffix(base: &str, counter: usize, max_length: usize) -> String {
+    let suffix = format!("_{}", counter);
+    let base: String = base.chars().take(max_length.saturating_sub(suffix.len())).collect();
+    format!("{}{}", base, suffix)
+}
+
+fn validate_text(value: &str, max_length: usize, field: &str, allow_newlines: bool) -> Result<String, AppError> {
+    let value: String = value.trim().nfc().collect();
+    if value.chars().count() > max_length {
+        return Err(AppError::ValidationError(format!("{}不能超过{}个字符", field, max_length)));
+    }
+    if value.chars().any(|c| c.is_control() && !(allow_newlines && c == '\n')) {
+        return Err(AppError::ValidationError(format!("{}包含控制字符", field)));
+    }
+    Ok(value)
+}
+
+pub fn validate_display_name(name: &str) -> Result<String, AppError> {
+    validate_text(name, MAX_DISPLAY_NAME_LENGTH, "显示名称", false)
+}
+
+pub fn validate_bio(bio: &str) -> Result<String, AppError> {
+    validate_text(&bio.replace("\r\n", "\n"), MAX_BIO_LENGTH, "个人简介", true)
+}
+
+// BCP 47 语言标签的基本形式检查，如 zh-CN、en、zh-Hant-TW，返回规范大小写
+pub fn validate_locale(locale: &str) -> Result<String, AppError> {
+    let locale = locale.trim();
+    if locale.is_empty() {
+        return Ok(String::new());
+    }
+
+    if locale.len() > MAX_LOCALE_LENGTH {
+        return Err(AppError::ValidationError(format!("语言区域不能超过{}个字符", MAX_LOCALE_LENGTH)));
+    }
+
+    let invalid = || AppError::ValidationError(format!("无效的语言区域: {}", locale));
+    let mut parts = locale.split(['-', '_']);
+    let language = parts.next().ok_or_else(invalid)?;
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(invalid());
+    }
+
+    let mut normalized = vec![language.to_ascii_lowercase()];
+    for part in parts {
+        if !(2..=8).contains(&part.len()) || !part.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid());
+        }
+        normalized.push(match part.len() {
+            2 => part.to_ascii_uppercase(),
+            4 => {
+                let mut script = part.to_ascii_lowercase();
+                script[..1].make_ascii_uppercase();
+                script
+            }
+            _ => part.to_ascii_lowercase(),
+        });
+    }
+
+    Ok(normalized.join("-"))
+}
+
+// IANA 时区名，如 Asia/Shanghai
+pub fn validate_timezone(timezone: &str) -> Result<String, AppError> {
+    let timezone = timezone.trim();
+    if timezone.is_empty() {
+        return Ok(String::new());
+    }
+    timezone
+        .parse::<Tz>()
+        .map(|tz| tz.name().to_string())
+        .map_err(|_| AppError::ValidationError(format!("无效的时区: {}", timezone)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow_unicode: bool) -> UsernamePolicy {
+        UsernamePolicy {
+            min_length: 3,
+            max_length: 12,
+            allow_unicode,
+            extra_chars: "_-.".to_string(),
+            reserved: ["admin".to_string(), "support".to_string()].into_iter().collect(),
+            change_cooldown_days: 30,
+            reservation_days: 90,
+        }
+    }
+
+    #[test]
+    fn normalizes_and_validates_email() {
+        assert_eq!(validate_email("  Foo.Bar@Example.COM ").unwrap(), "foo.bar@example.com");
+        // 组合字符 e + U+0301 归一化为 é
+        assert_eq!(validate_email("Re\u{301}ne@example.com").unwrap(), "r\u{e9}ne@example.com");
+
+        for email in ["", "   ", "no-at-sign", "user@localhost", "user@[127.0.0.1]", "Name <user@example.com>"] {
+            assert!(validate_email(email).is_err(), "{}", email);
+        }
+        assert!(validate_email(&format!("{}@example.com", "a".repeat(250))).is_err());
+    }
+
+    #[test]
+    fn validates_username_rules() {
+        let policy = policy(false);
+
+        assert_eq!(validate_username(" alice_01 ", &policy).unwrap(), "alice_01");
+        for username in ["ab", "abcdefghijklm", "_alice", "alice!", "名字abc"] {
+            assert!(validate_username(username, &policy).is_err(), "{}", username);
+        }
+    }
+
+    #[test]
+    fn rejects_mixed_scripts_when_unicode_allowed() {
+        let policy = policy(true);
+
+        assert!(validate_username("名字abc", &policy).unwrap_err().to_string().contains("书写系统"));
+        assert_eq!(validate_username("Андрей", &policy).unwrap(), "Андрей");
+        // 拉丁字母混入西里尔字母 а
+        assert!(validate_username("p\u{430}ypal", &policy).is_err());
+    }
+
+    #[test]
+    fn rejects_reserved_usernames_and_lookalikes() {
+        let policy = policy(false);
+
+        for username in ["admin", "ADMIN", "Support", "adrnin"] {
+            assert!(validate_username(username, &policy).unwrap_err().to_string().contains("保留"), "{}", username);
+        }
+        assert!(validate_username("admins", &policy).is_ok());
+    }
+
+    #[test]
+    fn normalizes_locales() {
+        assert_eq!(validate_locale(" zh_cn ").unwrap(), "zh-CN");
+        assert_eq!(validate_locale("ZH-hant-tw").unwrap(), "zh-Hant-TW");
+        assert_eq!(validate_locale("en").unwrap(), "en");
+        assert_eq!(validate_locale("").unwrap(), "");
+
+        for locale in ["e", "english", "zh-", "zh-C", "en-US!", "12-US", "zh-abcdefghi"] {
+            assert!(validate_locale(locale).is_err(), "{}", locale);
+        }
+        // 每段都合法，但总长度超出 VARCHAR(35)
+        assert!(validate_locale("en-abcdefgh-abcdefgh-abcdefgh-abcdefgh").is_err());
+    }
+
+    #[test]
+    fn validates_timezones() {
+        assert_eq!(validate_timezone(" Asia/Shanghai ").unwrap(), "Asia/Shanghai");
+        assert_eq!(validate_timezone("UTC").unwrap(), "UTC");
+        assert_eq!(validate_timezone("").unwrap(), "");
+        assert!(validate_timezone("Mars/Olympus_Mons").is_err());
+        assert!(validate_timezone("+08:00").is_err());
+    }
+
+    #[test]
+    fn skeleton_matches_confusables() {
+        assert_eq!(username_skeleton("paypal"), username_skeleton("\u{440}\u{430}\u{443}\u{440}\u{430}l"));
+        assert_eq!(username_skeleton("Admin"), username_skeleton("admin"));
+        assert_eq!(username_skeleton("rn"), username_skeleton("m"));
+        assert_ne!(username_skeleton("alice"), username_skeleton("alicia"));
+    }
+
+    #[test]
+    fn sanitizes_oauth_names() {
+        let policy = policy(false);
+
+        assert_eq!(sanitize_username("John Smith", &policy), "John_Smith");
+        assert_eq!(sanitize_username("  --Jane!!  ", &policy), "Jane");
+        assert_eq!(sanitize_username("A very long display name", &policy), "A_very_long_");
+        assert_eq!(sanitize_username("张三", &policy), "user_");
+        assert_eq!(sanitize_username("Admin", &policy), "user_Admin");
+        assert_eq!(sanitize_username("Jo", &policy), "Jo0");
+        for name in ["John Smith", "张三", "Admin", "Jo", "!!!"] {
+            let username = sanitize_username(name, &policy);
+            assert!(validate_username(&username, &policy).is_ok(), "{} -> {}", name, username);
+        }
+    }
+
+    #[test]
+    fn suffix_fits_max_length() {
+        assert_eq!(username_with_suffix("alice", 1, 12), "alice_1");
+        assert_eq!(username_with_suffix("abcdefghijkl", 1, 12), "abcdefghij_1");
+        assert_eq!(username_with_suffix("abcdefghijkl", 100, 12), "abcdefgh_100");
+    }
+}
@@ -1,16 +1,541 @@
 use std::env;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use crate::errors::AppError;
+use crate::models::ai::GenerationParams;
+
+const DEFAULT_RESERVED_USERNAMES: &[&str] = &[
+    "admin", "administrator", "root", "system", "support", "help", "api", "auth",
+    "oauth", "user", "users", "me", "null", "undefined", "anonymous", "moderator",
+    "security", "staff", "official", "www", "mail", "settings", "profile",
+];
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub server_host: String,
     pub server_port: u16,
+    // 位于反向代理之后时，客户端地址取自 X-Forwarded-For / Forwarded
+    pub trust_proxy_headers: bool,
+    // 客户端与本服务之间可信反向代理的层数
+    pub trusted_proxy_hops: usize,
     pub database_url: String,
     pub redis_url: String,
     pub ai_providers: AIProviderConfig,
+    pub ai_default_provider: String,
+    pub ai_model_routes: Vec<ModelRoute>,
+    pub ai_resilience: AIResilienceConfig,
+    pub ai_conversations: AIConversationConfig,
+    pub ai_generation: AIGenerationConfig,
+    // 是否在 AI 响应中返回提供商的原始响应体
+    pub ai_include_raw_response: bool,
+    // 未单独设置方案的用户使用的 AI 额度方案
+    pub ai_default_plan: String,
+    pub ai_cache: AICacheConfig,
+    pub ai_images: AIImageConfig,
+    pub ai_embeddings: AIEmbeddingConfig,
+    // 一次请求中模型调用服务端工具的最大轮数
+    pub ai_tool_max_steps: usize,
+    // 结构化输出未通过 JSON Schema 校验时最多修复重试的次数
+    pub ai_json_max_repairs: usize,
+    pub rate_limit: RateLimitConfig,
     pub database_max_connections: u32,
     pub database_min_connections: u32,
+    pub username_policy: UsernamePolicy,
+    pub storage: StorageConfig,
+    pub avatar: AvatarConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    // local 或 s3
+    pub backend: String,
+    pub local_path: PathBuf,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    // 设置后媒体请求重定向到该地址（CDN 或公共读桶）
+    pub s3_public_url: Option<String>,
+}
+
+impl StorageConfig {
+    pub fn from_env() -> Self {
+        Self {
+            backend: env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string()),
+            local_path: PathBuf::from(env::var("STORAGE_LOCAL_PATH").unwrap_or_else(|_| "./uploads".to_string())),
+            s3_endpoint: env::var("S3_ENDPOINT").ok(),
+            s3_bucket: env::var("S3_BUCKET").ok(),
+            s3_region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            s3_access_key: env::var("S3_ACCESS_KEY").ok(),
+            s3_secret_key: env::var("S3_SECRET_KEY").ok(),
+            s3_public_url: env::var("S3_PUBLIC_URL").ok(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AvatarConfig {
+    pub max_bytes: usize,
+    pub max_dimension: u32,
+    // 生成的缩略图边长，第一个作为默认头像地址
+    pub sizes: Vec<u32>,
+}
+
+impl AvatarConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let sizes = env::var("AVATAR_SIZES")
+            .unwrap_or_else(|_| "256,128,64".to_string())
+            .split(',')
+            .map(|size| size.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| AppError::ConfigError("无效的头像尺寸配置".to_string()))?;
+        if sizes.is_empty() || sizes.contains(&0) {
+            return Err(AppError::ConfigError("无效的头像尺寸配置".to_string()));
+        }
+
+        Ok(Self {
+            max_bytes: env::var("AVATAR_MAX_BYTES")
+                .unwrap_or_else(|_| "5242880".to_string())
+                .parse()
+                .map_err(|_| AppError::ConfigError("无效的头像大小限制".to_string()))?,
+            max_dimension: env::var("AVATAR_MAX_DIMENSION")
+                .unwrap_or_else(|_| "4096".to_string())
+                .parse()
+                .map_err(|_| AppError::ConfigError("无效的头像尺寸限制".to_string()))?,
+            sizes,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AIResilienceConfig {
+    // 单个提供商的最大重试次数（不含首次请求）
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    // Retry-After 超过该值时不再等待，直接切换到下一个提供商
+    pub max_retry_after_secs: u64,
+    // 非流式请求每次尝试的总时长上限；流式请求只限制到开始返回为止
+    pub request_timeout_secs: u64,
+    pub connect_timeout_secs: u64,
+    // 流式响应超过该时长没有新数据时中断
+    pub stream_idle_timeout_secs: u64,
+    // 主提供商失败后依次尝试的提供商
+    pub fallback_chain: Vec<String>,
+    // 连续失败多少次后熔断，以及熔断持续时间
+    pub circuit_failure_threshold: u32,
+    pub circuit_open_secs: u64,
+}
+
+fn parse_env<T: std::str::FromStr>(key: &str, default: &str, error: &str) -> Result<T, AppError> {
+    env::var(key)
+        .unwrap_or_else(|_| default.to_string())
+        .parse()
+        .map_err(|_| AppError::ConfigError(error.to_string()))
+}
+
+impl AIResilienceConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let circuit_failure_threshold = parse_env("AI_CIRCUIT_FAILURE_THRESHOLD", "5", "无效的熔断阈值")?;
+        if circuit_failure_threshold == 0 {
+            return Err(AppError::ConfigError("无效的熔断阈值".to_string()));
+        }
+
+        Ok(Self {
+            max_retries: parse_env("AI_RETRY_MAX_RETRIES", "2", "无效的 AI 重试次数")?,
+            retry_base_delay_ms: parse_env("AI_RETRY_BASE_DELAY_MS", "250", "无效的 AI 重试间隔")?,
+            retry_max_delay_ms: parse_env("AI_RETRY_MAX_DELAY_MS", "4000", "无效的 AI 重试间隔")?,
+            max_retry_after_secs: parse_env("AI_RETRY_MAX_RETRY_AFTER_SECS", "10", "无效的 Retry-After 上限")?,
+            request_timeout_secs: parse_env("AI_REQUEST_TIMEOUT_SECS", "60", "无效的 AI 请求超时")?,
+            connect_timeout_secs: parse_env("AI_CONNECT_TIMEOUT_SECS", "10", "无效的 AI 连接超时")?,
+            stream_idle_timeout_secs: parse_env("AI_STREAM_IDLE_TIMEOUT_SECS", "60", "无效的 AI 流式空闲超时")?,
+            fallback_chain: env::var("AI_FALLBACK_CHAIN")
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            circuit_failure_threshold,
+            circuit_open_secs: parse_env("AI_CIRCUIT_OPEN_SECS", "30", "无效的熔断时长")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AIConversationConfig {
+    // 每次请求携带的历史消息条数上限
+    pub history_limit: i64,
+    pub attachment_max_bytes: usize,
+}
+
+impl AIConversationConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let history_limit = parse_env("AI_CONVERSATION_HISTORY_LIMIT", "20", "无效的对话历史条数")?;
+        if history_limit <= 0 {
+            return Err(AppError::ConfigError("无效的对话历史条数".to_string()));
+        }
+        Ok(Self {
+            history_limit,
+            attachment_max_bytes: parse_env("AI_ATTACHMENT_MAX_BYTES", "10485760", "无效的附件大小限制")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AIImageConfig {
+    // 单条消息最多携带的图片数，上传的图片和图片 URL 合计
+    pub max_per_message: usize,
+    // 上传的单张图片大小上限
+    pub max_upload_bytes: usize,
+    // 由服务端下载图片 URL，仅用于不能直接接收 URL 的提供商
+    pub fetch_enabled: bool,
+    pub fetch_max_bytes: usize,
+    pub fetch_timeout_secs: u64,
+}
+
+impl AIImageConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let max_per_message = parse_env("AI_IMAGE_MAX_PER_MESSAGE", "8", "无效的单条消息图片数量上限")?;
+        if max_per_message == 0 {
+            return Err(AppError::ConfigError("无效的单条消息图片数量上限".to_string()));
+        }
+        Ok(Self {
+            max_per_message,
+            max_upload_bytes: parse_env("AI_IMAGE_MAX_BYTES", "20971520", "无效的图片大小限制")?,
+            fetch_enabled: parse_env("AI_IMAGE_FETCH_ENABLED", "false", "无效的 AI_IMAGE_FETCH_ENABLED")?,
+            fetch_max_bytes: parse_env("AI_IMAGE_FETCH_MAX_BYTES", "10485760", "无效的图片下载大小限制")?,
+            fetch_timeout_secs: parse_env("AI_IMAGE_FETCH_TIMEOUT_SECS", "10", "无效的图片下载超时时间")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AICacheConfig {
+    // 关闭时所有请求都直接调用提供商
+    pub enabled: bool,
+    pub ttl_secs: u64,
+}
+
+impl AICacheConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let ttl_secs = parse_env("AI_CACHE_TTL_SECS", "3600", "无效的 AI 缓存时间")?;
+        if ttl_secs == 0 {
+            return Err(AppError::ConfigError("无效的 AI 缓存时间".to_string()));
+        }
+        Ok(Self {
+            enabled: parse_env("AI_CACHE_ENABLED", "false", "无效的 AI_CACHE_ENABLED")?,
+            ttl_secs,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AIEmbeddingConfig {
+    // 单个请求最多的输入条数，超过提供商上限的部分自动分批
+    pub max_inputs: usize,
+    // 按提供商、模型和文本哈希缓存向量
+    pub cache_enabled: bool,
+    pub cache_ttl_secs: u64,
+}
+
+impl AIEmbeddingConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        let max_inputs = parse_env("AI_EMBEDDING_MAX_INPUTS", "256", "无效的 AI_EMBEDDING_MAX_INPUTS")?;
+        let cache_ttl_secs = parse_env("AI_EMBEDDING_CACHE_TTL_SECS", "604800", "无效的向量缓存时间")?;
+        if max_inputs == 0 || cache_ttl_secs == 0 {
+            return Err(AppError::ConfigError("无效的向量嵌入配置".to_string()));
+        }
+        Ok(Self {
+            max_inputs,
+            cache_enabled: parse_env("AI_EMBEDDING_CACHE_ENABLED", "false", "无效的 AI_EMBEDDING_CACHE_ENABLED")?,
+            cache_ttl_secs,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UsernamePolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    // 允许 Unicode 字母/数字（同一书写系统内），否则仅允许 ASCII 字母数字
+    pub allow_unicode: bool,
+    // 字母数字之外额外允许的字符
+    pub extra_chars: String,
+    pub reserved: HashSet<String>,
+    // 两次修改用户名之间的最短间隔
+    pub change_cooldown_days: i64,
+    // 旧用户名在此期间内不能被其他用户使用
+    pub reservation_days: i64,
+}
+
+impl UsernamePolicy {
+    pub fn from_env() -> Result<Self, AppError> {
+        let min_length = env::var("USERNAME_MIN_LENGTH")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse()
+            .map_err(|_| AppError::ConfigError("无效的用户名最小长度".to_string()))?;
+        let max_length = env::var("USERNAME_MAX_LENGTH")
+            .unwrap_or_else(|_| "32".to_string())
+            .parse()
+            .map_err(|_| AppError::ConfigError("无效的用户名最大长度".to_string()))?;
+        if min_length == 0 || min_length > max_length {
+            return Err(AppError::ConfigError("用户名长度范围配置错误".to_string()));
+        }
+
+        let mut reserved: HashSet<String> = DEFAULT_RESERVED_USERNAMES
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        if let Ok(extra) = env::var("USERNAME_RESERVED") {
+            reserved.extend(
+                extra
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty()),
+            );
+        }
+
+        Ok(Self {
+            min_length,
+            max_length,
+            allow_unicode: env::var("USERNAME_ALLOW_UNICODE")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false),
+            extra_chars: env::var("USERNAME_EXTRA_CHARS").unwrap_or_else(|_| "_-.".to_string()),
+            reserved,
+            change_cooldown_days: env::var("USERNAME_CHANGE_COOLDOWN_DAYS")
+                .unwrap_or_else(|_| "30".to_string())
+                .parse()
+                .map_err(|_| AppError::ConfigError("无效的用户名修改间隔".to_string()))?,
+            reservation_days: env::var("USERNAME_RESERVATION_DAYS")
+                .unwrap_or_else(|_| "90".to_string())
+                .parse()
+                .map_err(|_| AppError::ConfigError("无效的用户名保留期".to_string()))?,
+        })
+    }
+}
+
+// 未配置 AI_MODEL_ROUTES 时的模型路由规则
+const DEFAULT_MODEL_ROUTES: &str = "gpt-*=openai,qwen-*=tongyi,claude-*=anthropic";
+
+// 模型名到提供商的路由规则，pattern 以 * 结尾时按前缀匹配，否则精确匹配
+#[derive(Clone, Debug)]
+pub struct ModelRoute {
+    pub pattern: String,
+    pub provider: String,
+}
+
+// pattern 以 * 结尾时按前缀匹配，否则精确匹配
+pub fn model_matches(pattern: &str, model: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => model.starts_with(prefix),
+        None => model == pattern,
+    }
+}
+
+impl ModelRoute {
+    pub fn matches(&self, model: &str) -> bool {
+        model_matches(&self.pattern, model)
+    }
+
+    // 格式：gpt-*=openai,qwen-*=tongyi
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, AppError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let (pattern, provider) = rule
+                    .split_once('=')
+                    .ok_or_else(|| AppError::ConfigError(format!("无效的模型路由规则: {}", rule)))?;
+                let (pattern, provider) = (pattern.trim(), provider.trim());
+                if pattern.is_empty() || provider.is_empty() {
+                    return Err(AppError::ConfigError(format!("无效的模型路由规则: {}", rule)));
+                }
+                Ok(Self {
+                    pattern: pattern.to_string(),
+                    provider: provider.to_lowercase(),
+                })
+            })
+            .collect()
+    }
+}
+
+// 按模型名配置的生成参数默认值和 max_tokens 上限，均按顺序取第一条匹配的规则
+#[derive(Clone, Debug)]
+pub struct AIGenerationConfig {
+    pub defaults: Vec<(String, GenerationParams)>,
+    pub max_tokens: Vec<(String, u32)>,
+}
+
+impl AIGenerationConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        Ok(Self {
+            defaults: Self::parse_defaults(&env::var("AI_GENERATION_DEFAULTS").unwrap_or_default())?,
+            max_tokens: Self::parse_max_tokens(&env::var("AI_GENERATION_MAX_TOKENS").unwrap_or_default())?,
+        })
+    }
+
+    pub fn defaults_for(&self, model: &str) -> Option<&GenerationParams> {
+        self.defaults
+            .iter()
+            .find(|(pattern, _)| model_matches(pattern, model))
+            .map(|(_, params)| params)
+    }
+
+    pub fn max_tokens_for(&self, model: &str) -> Option<u32> {
+        self.max_tokens
+            .iter()
+            .find(|(pattern, _)| model_matches(pattern, model))
+            .map(|(_, max_tokens)| *max_tokens)
+    }
+
+    // 格式：qwen-*:temperature=0.7,top_p=0.8;*:max_tokens=1024
+    fn parse_defaults(value: &str) -> Result<Vec<(String, GenerationParams)>, AppError> {
+        value
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let invalid = || AppError::ConfigError(format!("无效的生成参数默认值: {}", rule));
+                let (pattern, pairs) = rule.split_once(':').ok_or_else(invalid)?;
+                let mut fields = serde_json::Map::new();
+                for pair in pairs.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                    let (key, value) = pair.split_once('=').ok_or_else(invalid)?;
+                    let value = value.trim();
+                    // 数字和布尔值按 JSON 解析，其余按字符串处理
+                    let value = serde_json::from_str(value)
+                        .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+                    fields.insert(key.trim().to_string(), value);
+                }
+                let params: GenerationParams = serde_json::from_value(serde_json::Value::Object(fields))
+                    .map_err(|_| invalid())?;
+                params.validate().map_err(|_| invalid())?;
+                Ok((pattern.trim().to_string(), params))
+            })
+            .collect()
+    }
+
+    // 格式：gpt-4o*=16384,*=4096
+    fn parse_max_tokens(value: &str) -> Result<Vec<(String, u32)>, AppError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let invalid = || AppError::ConfigError(format!("无效的 max_tokens 上限: {}", rule));
+                let (pattern, max_tokens) = rule.split_once('=').ok_or_else(invalid)?;
+                let max_tokens: u32 = max_tokens.trim().parse().map_err(|_| invalid())?;
+                if pattern.trim().is_empty() || max_tokens == 0 {
+                    return Err(invalid());
+                }
+                Ok((pattern.trim().to_string(), max_tokens))
+            })
+            .collect()
+    }
+}
+
+const DEFAULT_RATE_LIMIT_RULES: &str =
+    "/ai/text*=user:20/60,ip:60/60;/ai/image=user:10/60,ip:30/60;/ai/*=user:120/60,ip:240/60";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+    User,
+    // X-API-Key 请求头
+    ApiKey,
+    Ip,
+}
+
+impl RateLimitKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitKey::User => "user",
+            RateLimitKey::ApiKey => "api_key",
+            RateLimitKey::Ip => "ip",
+        }
+    }
+}
+
+// 令牌桶：最多积累 capacity 个令牌，每 period_secs 秒补满
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    pub key: RateLimitKey,
+    pub capacity: u32,
+    pub period_secs: u32,
+}
+
+// 请求路径匹配 pattern 时应用其中所有限制，按顺序取第一条匹配的规则
+#[derive(Clone, Debug)]
+pub struct RateLimitRule {
+    pub pattern: String,
+    pub limits: Vec<RateLimit>,
+}
+
+impl RateLimitRule {
+    pub fn matches(&self, path: &str) -> bool {
+        model_matches(&self.pattern, path)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    pub rules: Vec<RateLimitRule>,
+}
+
+impl RateLimitConfig {
+    pub fn from_env() -> Result<Self, AppError> {
+        Ok(Self {
+            rules: Self::parse_rules(
+                &env::var("RATE_LIMIT_RULES").unwrap_or_else(|_| DEFAULT_RATE_LIMIT_RULES.to_string()),
+            )?,
+        })
+    }
+
+    pub fn rule_for(&self, path: &str) -> Option<&RateLimitRule> {
+        self.rules.iter().find(|rule| rule.matches(path))
+    }
+
+    // 格式：/ai/text*=user:20/60,ip:60/60;/ai/*=user:120/60
+    fn parse_rules(value: &str) -> Result<Vec<RateLimitRule>, AppError> {
+        value
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let invalid = || AppError::ConfigError(format!("无效的限流规则: {}", rule));
+                let (pattern, limits) = rule.split_once('=').ok_or_else(invalid)?;
+                let limits = limits
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|limit| !limit.is_empty())
+                    .map(|limit| {
+                        let (key, rate) = limit.split_once(':').ok_or_else(invalid)?;
+                        let (capacity, period_secs) = rate.split_once('/').ok_or_else(invalid)?;
+                        let key = match key.trim() {
+                            "user" => RateLimitKey::User,
+                            "api_key" => RateLimitKey::ApiKey,
+                            "ip" => RateLimitKey::Ip,
+                            _ => return Err(invalid()),
+                        };
+                        let capacity: u32 = capacity.trim().parse().map_err(|_| invalid())?;
+                        let period_secs: u32 = period_secs.trim().parse().map_err(|_| invalid())?;
+                        if capacity == 0 || period_secs == 0 {
+                            return Err(invalid());
+                        }
+                        Ok(RateLimit { key, capacity, period_secs })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if pattern.trim().is_empty() || limits.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(RateLimitRule {
+                    pattern: pattern.trim().to_string(),
+                    limits,
+                })
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -29,6 +554,10 @@ impl AIProviderConfig {
         self.providers.get(provider)
     }
 
+    pub fn configured_providers(&self) -> impl Iterator<Item = &str> {
+        self.providers.keys().map(String::as_str)
+    }
+
     pub fn load_from_env(&mut self, provider: &str, config_keys: &[&str]) {
         let mut config = HashMap::new();
         for key in config_keys {
@@ -47,8 +576,39 @@ impl Config {
     pub fn from_env() -> Result<Self, AppError> {
         let mut ai_providers = AIProviderConfig::new();
         
-        ai_providers.load_from_env("tongyi", &["API_KEY", "API_ENDPOINT"]);
-        ai_providers.load_from_env("openai", &["API_KEY", "API_ENDPOINT"]);
+        ai_providers.load_from_env(
+            "tongyi",
+            &["API_KEY", "API_ENDPOINT", "VISION_MODEL", "VISION_MODELS", "EMBEDDING_MODEL", "EMBEDDING_BATCH_SIZE"],
+        );
+        ai_providers.load_from_env(
+            "openai",
+            &[
+                "API_KEY", "API_ENDPOINT", "ORGANIZATION", "DEFAULT_MODEL", "VISION_MODEL", "VISION_MODELS",
+                "EMBEDDING_MODEL", "EMBEDDING_BATCH_SIZE", "RESPONSE_FORMAT",
+            ],
+        );
+        ai_providers.load_from_env(
+            "anthropic",
+            &["API_KEY", "API_ENDPOINT", "DEFAULT_MODEL", "MAX_TOKENS", "SYSTEM_PROMPT", "VISION_MODEL", "VISION_MODELS"],
+        );
+        ai_providers.load_from_env("ollama", &["API_ENDPOINT", "DEFAULT_MODEL", "VISION_MODEL", "VISION_MODELS"]);
+        ai_providers.load_from_env(
+            "llamacpp",
+            &[
+                "API_KEY", "API_ENDPOINT", "DEFAULT_MODEL", "VISION_MODEL", "VISION_MODELS",
+                "EMBEDDING_MODEL", "EMBEDDING_BATCH_SIZE", "RESPONSE_FORMAT",
+            ],
+        );
+
+        let ai_tool_max_steps = parse_env("AI_TOOL_MAX_STEPS", "5", "无效的 AI_TOOL_MAX_STEPS")?;
+        if ai_tool_max_steps == 0 {
+            return Err(AppError::ConfigError("无效的 AI_TOOL_MAX_STEPS".to_string()));
+        }
+        let ai_json_max_repairs = parse_env("AI_JSON_MAX_REPAIRS", "2", "无效的 AI_JSON_MAX_REPAIRS")?;
+        let trusted_proxy_hops = parse_env("TRUSTED_PROXY_HOPS", "1", "无效的 TRUSTED_PROXY_HOPS")?;
+        if trusted_proxy_hops == 0 {
+            return Err(AppError::ConfigError("无效的 TRUSTED_PROXY_HOPS".to_string()));
+        }
 
         Ok(Config {
             server_host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
@@ -56,6 +616,8 @@ impl Config {
                 .unwrap_or_else(|_| "8080".to_string())
                 .parse()
                 .map_err(|_| AppError::ConfigError("无效的服务器端口".to_string()))?,
+            trust_proxy_headers: parse_env("TRUST_PROXY_HEADERS", "false", "无效的 TRUST_PROXY_HEADERS")?,
+            trusted_proxy_hops,
             
             database_url: env::var("DATABASE_URL").map_err(|_| {
                 AppError::ConfigError("DATABASE_URL 环境变量未设置".to_string())
@@ -71,6 +633,33 @@ impl Config {
             
             redis_url: env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string()),
             ai_providers,
+            ai_default_provider: env::var("AI_DEFAULT_PROVIDER").unwrap_or_else(|_| "tongyi".to_string()),
+            ai_model_routes: ModelRoute::parse_list(
+                &env::var("AI_MODEL_ROUTES").unwrap_or_else(|_| DEFAULT_MODEL_ROUTES.to_string()),
+            )?,
+            ai_resilience: AIResilienceConfig::from_env()?,
+            ai_conversations: AIConversationConfig::from_env()?,
+            ai_generation: AIGenerationConfig::from_env()?,
+            ai_include_raw_response: parse_env("AI_INCLUDE_RAW_RESPONSE", "true", "无效的 AI_INCLUDE_RAW_RESPONSE")?,
+            ai_default_plan: env::var("AI_DEFAULT_PLAN").unwrap_or_else(|_| "free".to_string()),
+            ai_cache: AICacheConfig::from_env()?,
+            ai_images: AIImageConfig::from_env()?,
+            ai_embeddings: AIEmbeddingConfig::from_env()?,
+            ai_tool_max_steps,
+            ai_json_max_repairs,
+            rate_limit: RateLimitConfig::from_env()?,
+            username_policy: UsernamePolicy::from_env()?,
+            storage: StorageConfig::from_env(),
+            avatar: AvatarConfig::from_env()?,
         })
     }
+
+    // 读取代理头时可信代理的层数，0 表示直接使用连接的对端地址
+    pub fn proxy_hops(&self) -> usize {
+        if self.trust_proxy_headers {
+            self.trusted_proxy_hops
+        } else {
+            0
+        }
+    }
 }
\ No newline at end of file
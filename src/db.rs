@@ -24,5 +24,10 @@ pub async fn init_db(config: &Config) -> Result<DbPool, AppError> {
         log::info!("PostgreSQL 连接成功");
     }
 
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("数据库迁移失败: {}", e)))?;
+
     Ok(pool)
 }
\ No newline at end of file
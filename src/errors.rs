@@ -2,13 +2,19 @@ use std::str::Utf8Error;
 
 use actix_multipart::MultipartError;
 // src/errors.rs
+use actix_web::http::header;
 use actix_web::{HttpResponse, ResponseError};
 use thiserror::Error;
 
+use crate::models::ai::TokenUsage;
+
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("认证失败: {0}")]
     AuthenticationError(String),
+
+    #[error("权限不足: {0}")]
+    PermissionDenied(String),
     
     #[error("数据库错误: {0}")]
     DatabaseError(String),
@@ -22,6 +28,9 @@ pub enum AppError {
     #[error("内部服务器错误: {0}")]
     InternalError(String),
 
+    #[error("资源不存在: {0}")]
+    NotFound(String),
+
     #[error("无效的帖子")]
     InvalidId(String),
 
@@ -30,6 +39,60 @@ pub enum AppError {
 
     #[error("AI调用出错了")]
     AIServiceError(String),
+
+    #[error("AI请求无效: {0}")]
+    AIInvalidRequest(String),
+
+    #[error("AI服务请求过于频繁")]
+    AIRateLimited { message: String, retry_after: Option<u64> },
+
+    #[error("AI服务繁忙")]
+    AIOverloaded { message: String, retry_after: Option<u64> },
+
+    #[error("AI上游服务错误")]
+    AIUpstreamError { status: u16, message: String },
+
+    #[error("AI服务响应超时")]
+    AITimeout(String),
+
+    #[error("AI服务暂不可用")]
+    AIUnavailable(String),
+
+    #[error("请求过于频繁，请 {retry_after} 秒后再试")]
+    RateLimited { retry_after: u64 },
+
+    #[error("今日 AI 额度已用完: {message}")]
+    AIDailyQuotaExceeded { message: String, retry_after: u64 },
+
+    #[error("本月 AI 额度已用完: {message}")]
+    AIMonthlyQuotaExceeded { message: String, retry_after: u64 },
+
+    // 失败前已经产生的 token 用量随错误一起返回，由调用记录计入额度
+    #[error("{error}")]
+    AIFailedWithUsage { error: Box<AppError>, usage: TokenUsage },
+}
+
+impl AppError {
+    // 附加已产生的用量，多次附加时累加
+    pub fn with_usage(self, usage: Option<TokenUsage>) -> Self {
+        let Some(usage) = usage else {
+            return self;
+        };
+        match self {
+            AppError::AIFailedWithUsage { error, usage: incurred } => AppError::AIFailedWithUsage {
+                error,
+                usage: incurred.add(&usage),
+            },
+            error => AppError::AIFailedWithUsage { error: Box::new(error), usage },
+        }
+    }
+
+    pub fn usage(&self) -> Option<&TokenUsage> {
+        match self {
+            AppError::AIFailedWithUsage { usage, .. } => Some(usage),
+            _ => None,
+        }
+    }
 }
 
 impl ResponseError for AppError {
@@ -38,9 +101,15 @@ impl ResponseError for AppError {
             AppError::AuthenticationError(_) => {
                 HttpResponse::Unauthorized().json(json_error_response(&self.to_string()))
             }
+            AppError::PermissionDenied(_) => {
+                HttpResponse::Forbidden().json(json_error_response(&self.to_string()))
+            }
             AppError::ValidationError(_) => {
                 HttpResponse::BadRequest().json(json_error_response(&self.to_string()))
             }
+            AppError::NotFound(_) => {
+                HttpResponse::NotFound().json(json_error_response(&self.to_string()))
+            }
             AppError::ConfigError(_) => {
                 log::error!("配置错误: {:?}", self);
                 HttpResponse::InternalServerError().json(json_error_response("服务器配置错误"))
@@ -61,6 +130,54 @@ impl ResponseError for AppError {
                 log::error!("AI报错: {:?}", self);
                 HttpResponse::InternalServerError().json(json_error_response("AI服务出错"))
             }
+            AppError::AIInvalidRequest(_) => {
+                HttpResponse::BadRequest().json(json_error_response(&self.to_string()))
+            }
+            AppError::AIRateLimited { retry_after, .. } => {
+                log::warn!("AI限流: {:?}", self);
+                let mut response = HttpResponse::TooManyRequests();
+                if let Some(seconds) = retry_after {
+                    response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+                }
+                response.json(json_error_response(&self.to_string()))
+            }
+            AppError::AIOverloaded { retry_after, .. } => {
+                log::warn!("AI服务过载: {:?}", self);
+                let mut response = HttpResponse::ServiceUnavailable();
+                if let Some(seconds) = retry_after {
+                    response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+                }
+                response.json(json_error_response(&self.to_string()))
+            }
+            AppError::AIUpstreamError { .. } => {
+                log::error!("AI上游错误: {:?}", self);
+                HttpResponse::BadGateway().json(json_error_response(&self.to_string()))
+            }
+            AppError::AITimeout(_) => {
+                log::error!("AI超时: {:?}", self);
+                HttpResponse::GatewayTimeout().json(json_error_response(&self.to_string()))
+            }
+            AppError::AIUnavailable(_) => {
+                log::error!("AI服务不可用: {:?}", self);
+                HttpResponse::ServiceUnavailable().json(json_error_response(&self.to_string()))
+            }
+            AppError::RateLimited { retry_after } => {
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(json_error_response(&self.to_string()))
+            }
+            // 每日额度次日即恢复，按限流处理；月度额度需要升级方案
+            AppError::AIDailyQuotaExceeded { retry_after, .. } => {
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(json_error_response(&self.to_string()))
+            }
+            AppError::AIMonthlyQuotaExceeded { retry_after, .. } => {
+                HttpResponse::PaymentRequired()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .json(json_error_response(&self.to_string()))
+            }
+            AppError::AIFailedWithUsage { error, .. } => error.error_response(),
         }
     }
 }
@@ -10,6 +10,7 @@ mod db;
 mod errors;
 mod models;
 mod service;
+mod middleware;
 mod ai;
 
 #[actix_web::main]
@@ -19,23 +20,54 @@ async fn main() -> std::io::Result<()> {
     
     let config = config::Config::from_env().expect("配置错误");
     let db = db::init_db(&config).await.expect("数据库连接失败");
+    models::user::User::backfill_normalized_emails(&db).await.expect("规范化邮箱回填失败");
+    models::user::User::backfill_username_skeletons(&db).await.expect("用户名骨架回填失败");
     let redis_service = RedisService::new(&config.redis_url)
     .expect("Redis 服务初始化失败");
-    let ai_service = ai::service::AIServiceImpl::new(config.clone());
+    let ai_service = ai::service::AIServiceImpl::new(&config).expect("AI 服务初始化失败");
+    let response_cache = ai::cache::ResponseCache::new(redis_service.clone(), &config.ai_cache);
+    let embedding_cache = ai::cache::EmbeddingCache::new(redis_service.clone(), &config.ai_embeddings);
+    let rate_limiter =
+        middleware::rate_limit::RateLimiter::new(redis_service.clone(), &config.rate_limit, config.proxy_hops());
+    let quota_service = ai::quota::QuotaService::new(db.clone(), redis_service.clone(), &config.ai_default_plan);
+    let tool_registry = ai::tools::ToolRegistry::with_builtin_tools(config.ai_tool_max_steps, quota_service.clone())
+        .expect("AI 工具注册失败");
+    let app_config = web::Data::new(config.clone());
+    let storage: web::Data<dyn service::storage::Storage> =
+        web::Data::from(service::storage::from_config(&config.storage).expect("存储服务初始化失败"));
     
     log::info!("启动服务器 http://{}:{}", config.server_host, config.server_port);
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
-            .allowed_headers(vec![header::CONTENT_TYPE, header::AUTHORIZATION])
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
+            .allowed_headers(vec![
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                header::HeaderName::from_static("x-api-key"),
+            ])
+            .expose_headers(vec![
+                header::RETRY_AFTER,
+                header::HeaderName::from_static("ratelimit-limit"),
+                header::HeaderName::from_static("ratelimit-remaining"),
+                header::HeaderName::from_static("ratelimit-reset"),
+                header::HeaderName::from_static("ratelimit-policy"),
+            ])
             .max_age(3600);
     
         App::new()
+            // 限流在 CORS 之内，被拒绝的响应同样带有 CORS 头
+            .wrap(rate_limiter.clone())
             .wrap(cors)
+            .app_data(app_config.clone())
             .app_data(web::Data::new(db.clone()))
             .app_data(web::Data::new(redis_service.clone()))
+            .app_data(storage.clone())
             .app_data(web::Data::new(ai_service.clone()))
+            .app_data(web::Data::new(quota_service.clone()))
+            .app_data(web::Data::new(response_cache.clone()))
+            .app_data(web::Data::new(embedding_cache.clone()))
+            .app_data(web::Data::new(tool_registry.clone()))
             .configure(auth::routes::auth_config)
             .configure(ai::routes::ai_config)
     })
@@ -0,0 +1,338 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{Error, HttpRequest, HttpResponse, ResponseError};
+use futures::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+
+use crate::auth::audit;
+use crate::auth::auth_handlers::get_claims_from_request;
+use crate::config::{RateLimit, RateLimitConfig, RateLimitKey};
+use crate::errors::AppError;
+use crate::service::redis_service::RedisService;
+
+// 令牌桶，时间取 Redis 服务器时间，多个实例共享同一个桶
+// KEYS[1]: 桶；ARGV[1]: 容量；ARGV[2]: 补满所需毫秒数
+// 返回：是否放行、剩余令牌、下一个令牌的等待毫秒数、补满的等待毫秒数
+const TOKEN_BUCKET: &str = r#"
+local capacity = tonumber(ARGV[1])
+local period = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * capacity / period)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('PEXPIRE', KEYS[1], period)
+local next_ms = 0
+if tokens < 1 then
+    next_ms = math.ceil((1 - tokens) * period / capacity)
+end
+return {allowed, math.floor(tokens), next_ms, math.ceil((capacity - tokens) * period / capacity)}
+"#;
+
+const API_KEY_HEADER: &str = "X-API-Key";
+
+// 请求命中的限制中最紧的一个决定是否放行及响应头
+#[derive(Debug)]
+struct BucketState {
+    limit: RateLimit,
+    allowed: bool,
+    remaining: u64,
+    retry_after_ms: u64,
+    reset_ms: u64,
+}
+
+impl BucketState {
+    fn retry_after(&self) -> u64 {
+        self.retry_after_ms.div_ceil(1000).max(1)
+    }
+
+    // 是否比 other 更紧：先看是否被拒绝，再看剩余令牌
+    fn tighter_than(&self, other: &BucketState) -> bool {
+        (!self.allowed && other.allowed) || (self.allowed == other.allowed && self.remaining < other.remaining)
+    }
+
+    // 被拒绝时返回 429，Retry-After 为下一个令牌的等待秒数
+    fn rejection(&self) -> HttpResponse {
+        let mut response = AppError::RateLimited { retry_after: self.retry_after() }.error_response();
+        self.apply_headers(response.headers_mut());
+        response
+    }
+
+    fn apply_headers(&self, headers: &mut HeaderMap) {
+        let reset = if self.allowed { self.reset_ms.div_ceil(1000) } else { self.retry_after() };
+        let values = [
+            ("ratelimit-limit", self.limit.capacity.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", reset.to_string()),
+            ("ratelimit-policy", format!("{};w={}", self.limit.capacity, self.limit.period_secs)),
+        ];
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(HeaderName::from_static(name), value);
+            }
+        }
+    }
+}
+
+// 按配置的路径规则，对用户、API Key 和客户端 IP 分别限流
+#[derive(Clone)]
+pub struct RateLimiter {
+    redis: RedisService,
+    config: Arc<RateLimitConfig>,
+    // 按 IP 限流时可信反向代理的层数，见 audit::client_ip
+    proxy_hops: usize,
+    script: Arc<redis::Script>,
+}
+
+impl RateLimiter {
+    pub fn new(redis: RedisService, config: &RateLimitConfig, proxy_hops: usize) -> Self {
+        Self {
+            redis,
+            config: Arc::new(config.clone()),
+            proxy_hops,
+            script: Arc::new(redis::Script::new(TOKEN_BUCKET)),
+        }
+    }
+
+    fn identity(&self, req: &HttpRequest, key: RateLimitKey) -> Option<String> {
+        match key {
+            RateLimitKey::User => get_claims_from_request(req).ok().map(|claims| claims.sub),
+            // 只保存哈希，Redis 中不出现明文 Key
+            RateLimitKey::ApiKey => req
+                .headers()
+                .get(API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .map(|value| hex::encode(Sha256::digest(value.as_bytes()))),
+            RateLimitKey::Ip => audit::client_ip(req, self.proxy_hops),
+        }
+    }
+
+    async fn take(&self, bucket: &str, limit: &RateLimit) -> Result<BucketState, AppError> {
+        let mut invocation = self.script.key(bucket);
+        invocation.arg(limit.capacity).arg(u64::from(limit.period_secs) * 1000);
+        let (allowed, remaining, retry_after_ms, reset_ms): (u8, u64, u64, u64) =
+            self.redis.invoke(&invocation).await?;
+        Ok(BucketState {
+            limit: limit.clone(),
+            allowed: allowed == 1,
+            remaining,
+            retry_after_ms,
+            reset_ms,
+        })
+    }
+
+    // 每个限制各消耗一个令牌；Redis 不可用时放行
+    async fn check(&self, req: &HttpRequest) -> Option<BucketState> {
+        let rule = self.config.rule_for(req.path())?;
+        let mut decision: Option<BucketState> = None;
+
+        for limit in &rule.limits {
+            let Some(identity) = self.identity(req, limit.key) else {
+                continue;
+            };
+            let bucket = format!("ratelimit:{}:{}:{}", rule.pattern, limit.key.as_str(), identity);
+            let state = match self.take(&bucket, limit).await {
+                Ok(state) => state,
+                Err(e) => {
+                    log::warn!("限流检查失败，放行请求: {:?}", e);
+                    return None;
+                }
+            };
+
+            if decision.as_ref().is_none_or(|current| state.tighter_than(current)) {
+                decision = Some(state);
+            }
+        }
+
+        decision
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let decision = limiter.check(req.request()).await;
+
+            if let Some(decision) = decision.as_ref().filter(|decision| !decision.allowed) {
+                return Ok(req.into_response(decision.rejection()).map_into_right_body());
+            }
+
+            let mut response = service.call(req).await?;
+            if let Some(decision) = decision {
+                decision.apply_headers(response.headers_mut());
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use crate::config::RateLimitRule;
+    use actix_web::test::TestRequest;
+    use actix_web::test::{call_service, init_service};
+    use actix_web::{web, App};
+
+    fn limit(key: RateLimitKey, capacity: u32) -> RateLimit {
+        RateLimit { key, capacity, period_secs: 60 }
+    }
+
+    fn config() -> RateLimitConfig {
+        let rule = |pattern: &str, capacity| RateLimitRule {
+            pattern: pattern.to_string(),
+            limits: vec![limit(RateLimitKey::User, capacity), limit(RateLimitKey::Ip, capacity * 3)],
+        };
+        RateLimitConfig {
+            rules: vec![rule("/ai/text*", 20), rule("/ai/image", 10), rule("/ai/*", 120)],
+        }
+    }
+
+    // 连接被拒绝的地址，用来模拟 Redis 不可用
+    fn limiter(proxy_hops: usize) -> RateLimiter {
+        RateLimiter::new(RedisService::new("redis://127.0.0.1:1").unwrap(), &config(), proxy_hops)
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config = config();
+        let pattern = |path| config.rule_for(path).map(|rule| rule.pattern.as_str());
+
+        assert_eq!(pattern("/ai/text"), Some("/ai/text*"));
+        assert_eq!(pattern("/ai/text/stream"), Some("/ai/text*"));
+        assert_eq!(pattern("/ai/image"), Some("/ai/image"));
+        assert_eq!(pattern("/ai/image/analyze"), Some("/ai/*"));
+        assert_eq!(pattern("/auth/login"), None);
+    }
+
+    #[test]
+    fn client_ip_ignores_forwarded_headers_unless_trusted() {
+        // 客户端伪造了 198.51.100.1，两层代理依次追加了 203.0.113.7 和 10.0.0.1
+        let request = || {
+            TestRequest::get()
+                .uri("/ai/text")
+                .peer_addr("10.0.0.2:52000".parse().unwrap())
+                .insert_header(("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.0.0.1"))
+                .to_http_request()
+        };
+        let ip = |proxy_hops| limiter(proxy_hops).identity(&request(), RateLimitKey::Ip);
+
+        assert_eq!(ip(0).as_deref(), Some("10.0.0.2"));
+        assert_eq!(ip(1).as_deref(), Some("10.0.0.1"));
+        assert_eq!(ip(2).as_deref(), Some("203.0.113.7"));
+        assert_eq!(ip(5).as_deref(), Some("198.51.100.1"));
+    }
+
+    #[test]
+    fn client_ip_prefers_forwarded_header() {
+        let request = TestRequest::get()
+            .uri("/ai/text")
+            .peer_addr("10.0.0.2:52000".parse().unwrap())
+            .insert_header(("Forwarded", r#"for=198.51.100.1, for="[2001:db8::7]:4711";proto=https"#))
+            .insert_header(("X-Forwarded-For", "192.0.2.1"))
+            .to_http_request();
+
+        assert_eq!(limiter(1).identity(&request, RateLimitKey::Ip).as_deref(), Some("2001:db8::7"));
+    }
+
+    #[test]
+    fn rejection_is_429_with_retry_after() {
+        let state = BucketState {
+            limit: limit(RateLimitKey::User, 20),
+            allowed: false,
+            remaining: 0,
+            retry_after_ms: 2_400,
+            reset_ms: 60_000,
+        };
+
+        let response = state.rejection();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let header = |name: &str| response.headers().get(name).and_then(|value| value.to_str().ok());
+        assert_eq!(header("retry-after"), Some("3"));
+        assert_eq!(header("ratelimit-reset"), Some("3"));
+        assert_eq!(header("ratelimit-remaining"), Some("0"));
+        assert_eq!(header("ratelimit-policy"), Some("20;w=60"));
+    }
+
+    #[test]
+    fn rejected_bucket_is_tighter_than_allowed_one() {
+        let state = |allowed, remaining| BucketState {
+            limit: limit(RateLimitKey::Ip, 60),
+            allowed,
+            remaining,
+            retry_after_ms: 0,
+            reset_ms: 0,
+        };
+
+        assert!(state(false, 0).tighter_than(&state(true, 0)));
+        assert!(state(true, 1).tighter_than(&state(true, 5)));
+        assert!(!state(true, 5).tighter_than(&state(false, 0)));
+    }
+
+    #[actix_web::test]
+    async fn requests_pass_without_headers_while_redis_is_unavailable() {
+        let app = init_service(
+            App::new().wrap(limiter(0)).route("/ai/text", web::get().to(HttpResponse::Ok)),
+        ).await;
+
+        let response = call_service(
+            &app,
+            TestRequest::get().uri("/ai/text").peer_addr("10.0.0.2:52000".parse().unwrap()).to_request(),
+        ).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("ratelimit-limit").is_none());
+    }
+}
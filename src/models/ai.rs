@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::errors::AppError;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "content")]
 pub enum AIInput {
@@ -11,17 +13,511 @@ pub enum AIInput {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// 未提供提示词时图片请求使用的默认提示词
+pub const DEFAULT_IMAGE_PROMPT: &str = "请分析这张图片";
+
+impl AIInput {
+    // 旧版输入转换为一条 user 消息：图片请求优先使用 prompt，图片在前、文本在后
+    pub fn into_message(self, prompt: Option<String>) -> ChatMessage {
+        let (image, text) = match self {
+            AIInput::Text(text) => return ChatMessage::text(ChatRole::User, text),
+            AIInput::Image(image) => (image, prompt.unwrap_or_else(|| DEFAULT_IMAGE_PROMPT.to_string())),
+            AIInput::ImageWithText { image, text } => (image, prompt.unwrap_or(text)),
+        };
+        ChatMessage {
+            role: ChatRole::User,
+            content: vec![ContentPart::Image { data: image }, ContentPart::Text { text }],
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::System => "system",
+            ChatRole::User => "user",
+            ChatRole::Assistant => "assistant",
+            ChatRole::Tool => "tool",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    // 图片原始字节，与 AIInput 相同以字节数组传输
+    Image { data: Vec<u8> },
+    ImageUrl { url: String },
+}
+
+// 提供给模型调用的工具，parameters 为描述参数的 JSON Schema
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ToolDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default = "empty_object_schema")]
+    pub parameters: serde_json::Value,
+}
+
+fn empty_object_schema() -> serde_json::Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+impl ToolDefinition {
+    // 名称规则取各提供商限制的交集
+    pub fn validate(&self) -> Result<(), AppError> {
+        let valid_name = !self.name.is_empty()
+            && self.name.len() <= 64
+            && self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return Err(AppError::ValidationError(format!(
+                "工具名只能包含字母、数字、下划线和连字符，且不超过 64 个字符: {}", self.name
+            )));
+        }
+        if !self.parameters.is_object() {
+            return Err(AppError::ValidationError(format!("工具 {} 的 parameters 必须是 JSON Schema 对象", self.name)));
+        }
+        Ok(())
+    }
+}
+
+// 模型发起的一次工具调用，arguments 为解析后的 JSON；提供商返回的参数不是合法 JSON 时保留原始字符串
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+impl ToolCall {
+    // OpenAI 兼容接口和 DashScope 以 JSON 字符串返回参数
+    pub fn from_json_arguments(id: String, name: String, arguments: &str) -> Self {
+        let arguments = match arguments.trim() {
+            "" => serde_json::json!({}),
+            text => serde_json::from_str(text).unwrap_or_else(|_| serde_json::Value::String(text.to_string())),
+        };
+        Self { id, name, arguments }
+    }
+
+    pub fn arguments_json(&self) -> String {
+        match &self.arguments {
+            serde_json::Value::String(text) => text.clone(),
+            arguments => arguments.to_string(),
+        }
+    }
+}
+
+// 与提供商无关的对话消息，content 按顺序由文本和图片组成
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    #[serde(default, deserialize_with = "deserialize_content")]
+    pub content: Vec<ContentPart>,
+    // assistant 消息中模型发起的工具调用
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    // tool 消息所回应的工具调用 ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+// content 既可以是字符串，也可以是 content part 列表
+fn deserialize_content<'de, D>(deserializer: D) -> Result<Vec<ContentPart>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Content {
+        Text(String),
+        Parts(Vec<ContentPart>),
+    }
+
+    Ok(match Content::deserialize(deserializer)? {
+        Content::Text(text) => vec![ContentPart::Text { text }],
+        Content::Parts(parts) => parts,
+    })
+}
+
+impl ChatMessage {
+    pub fn text(role: ChatRole, text: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: vec![ContentPart::Text { text: text.into() }],
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    // 所有文本部分按顺序拼接，供只接受纯文本的场景使用
+    pub fn text_content(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    pub fn has_images(&self) -> bool {
+        self.content.iter().any(|part| !matches!(part, ContentPart::Text { .. }))
+    }
+}
+
+// 生成参数，未设置的字段使用模型默认值；各提供商映射到自己的请求字段，不支持的参数直接拒绝
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    // 以下为通义千问专有参数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable_search: Option<bool>,
+    // text 或 message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_format: Option<String>,
+}
+
+impl GenerationParams {
+    // 已设置的参数名
+    pub fn set_fields(&self) -> Vec<&'static str> {
+        [
+            ("temperature", self.temperature.is_some()),
+            ("top_p", self.top_p.is_some()),
+            ("max_tokens", self.max_tokens.is_some()),
+            ("stop", self.stop.is_some()),
+            ("seed", self.seed.is_some()),
+            ("enable_search", self.enable_search.is_some()),
+            ("result_format", self.result_format.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+    }
+
+    // 请求中未设置的字段取 defaults 中的值
+    pub fn or(self, defaults: &GenerationParams) -> Self {
+        Self {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            stop: self.stop.or_else(|| defaults.stop.clone()),
+            seed: self.seed.or(defaults.seed),
+            enable_search: self.enable_search.or(defaults.enable_search),
+            result_format: self.result_format.or_else(|| defaults.result_format.clone()),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), AppError> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(AppError::ValidationError("temperature 必须在 0 到 2 之间".to_string()));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(AppError::ValidationError("top_p 必须大于 0 且不超过 1".to_string()));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(AppError::ValidationError("max_tokens 必须大于 0".to_string()));
+        }
+        if let Some(stop) = &self.stop {
+            if stop.is_empty() || stop.iter().any(|sequence| sequence.is_empty()) {
+                return Err(AppError::ValidationError("stop 不能为空".to_string()));
+            }
+        }
+        if let Some(format) = &self.result_format {
+            if format != "text" && format != "message" {
+                return Err(AppError::ValidationError("result_format 只能为 text 或 message".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// 发给提供商的请求；JSON 中的 system、input 和 prompt 会转换为 messages
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(try_from = "AIRequestBody")]
 pub struct AIRequest {
-    pub input: AIInput,
+    pub messages: Vec<ChatMessage>,
+    // 显式指定提供商；为空时按模型名路由或使用默认提供商
+    pub provider: Option<String>,
     pub model: Option<String>,
-    pub prompt: Option<String>,
+    pub params: GenerationParams,
+    // 客户端定义的工具和启用的服务端工具，由模型决定是否调用
+    pub tools: Vec<ToolDefinition>,
+    // 要求模型输出符合该 JSON Schema 的 JSON，校验通过后解析到 AIResponse.data
+    pub response_schema: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// 调用记录中输入摘要的最大字符数
+const SUMMARY_MAX_CHARS: usize = 500;
+
+fn truncate_summary(text: &str) -> String {
+    let mut summary: String = text.chars().take(SUMMARY_MAX_CHARS).collect();
+    if text.chars().count() > SUMMARY_MAX_CHARS {
+        summary.push('…');
+    }
+    summary
+}
+
+impl AIRequest {
+    // 最后一条非 system 消息的文本，过长时截断，并注明附带的图片数量
+    pub fn summary(&self) -> String {
+        let Some(message) = self.messages.iter().rev().find(|message| message.role != ChatRole::System) else {
+            return String::new();
+        };
+        let mut summary = truncate_summary(&message.text_content());
+        let images = message.content.iter().filter(|part| !matches!(part, ContentPart::Text { .. })).count();
+        if images > 0 {
+            summary.push_str(&format!(" [图片 x{}]", images));
+        }
+        summary
+    }
+}
+
+#[derive(Deserialize)]
+struct AIRequestBody {
+    #[serde(default)]
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    params: GenerationParams,
+    input: Option<AIInput>,
+    provider: Option<String>,
+    model: Option<String>,
+    prompt: Option<String>,
+    system: Option<String>,
+    #[serde(default)]
+    tools: Vec<ToolDefinition>,
+    response_schema: Option<serde_json::Value>,
+}
+
+impl TryFrom<AIRequestBody> for AIRequest {
+    type Error = String;
+
+    // 顺序为 system、messages、input，兼容只传 input 的旧客户端
+    fn try_from(body: AIRequestBody) -> Result<Self, Self::Error> {
+        let mut messages = Vec::with_capacity(body.messages.len() + 2);
+        if let Some(system) = body.system {
+            messages.push(ChatMessage::text(ChatRole::System, system));
+        }
+        messages.extend(body.messages);
+        if let Some(input) = body.input {
+            messages.push(input.into_message(body.prompt));
+        }
+
+        if messages.iter().all(|message| message.role == ChatRole::System) {
+            return Err("请求至少需要一条非 system 消息（input 或 messages）".to_string());
+        }
+        if let Some(schema) = &body.response_schema {
+            if !schema.is_object() {
+                return Err("response_schema 必须是 JSON Schema 对象".to_string());
+            }
+            jsonschema::validator_for(schema).map_err(|e| format!("无效的 response_schema: {}", e))?;
+        }
+
+        Ok(Self {
+            messages,
+            provider: body.provider,
+            model: body.model,
+            params: body.params,
+            tools: body.tools,
+            response_schema: body.response_schema,
+        })
+    }
+}
+
+// 统一字段名后的 token 用量，提供商未返回的字段为 None
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct TokenUsage {
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    // 输入中图片占用的 token，已计入 input_tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_tokens: Option<u64>,
+    pub total_tokens: Option<u64>,
+}
+
+impl TokenUsage {
+    // 多次调用的用量相加，任一方有值的字段都保留
+    pub fn add(&self, other: &TokenUsage) -> TokenUsage {
+        let sum = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+        TokenUsage {
+            input_tokens: sum(self.input_tokens, other.input_tokens),
+            output_tokens: sum(self.output_tokens, other.output_tokens),
+            image_tokens: sum(self.image_tokens, other.image_tokens),
+            total_tokens: sum(self.total_tokens, other.total_tokens),
+        }
+    }
+
+    // 把一次调用的用量累加到总用量上，两者都可能缺失
+    pub fn accumulate(total: Option<TokenUsage>, usage: Option<TokenUsage>) -> Option<TokenUsage> {
+        match (total, usage) {
+            (Some(total), Some(usage)) => Some(total.add(&usage)),
+            (total, usage) => total.or(usage),
+        }
+    }
+
+    // 提供商未返回总数时按输入加输出计算
+    pub fn with_total(mut self) -> Self {
+        if self.total_tokens.is_none() {
+            if let (Some(input), Some(output)) = (self.input_tokens, self.output_tokens) {
+                self.total_tokens = Some(input + output);
+            }
+        }
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct AIResponse {
     pub content: String,
+    // 已弃用：提供商不返回置信度，始终为 null，保留字段以兼容旧客户端
+    #[serde(default)]
     pub confidence: Option<f64>,
+    // 实际处理请求的提供商，由服务层按注册名填写
+    pub provider: String,
+    // 上游返回的模型名，可能比请求中的更具体
+    pub model: Option<String>,
+    pub finish_reason: Option<String>,
+    pub usage: Option<TokenUsage>,
+    // 上游请求 ID，便于向提供商排查问题
+    pub request_id: Option<String>,
+    // 包含重试和切换提供商在内的总耗时
+    pub latency_ms: u64,
+    // 是否来自响应缓存；命中时 usage 和 request_id 为原始调用的值
+    #[serde(default)]
+    pub cached: bool,
+    // 模型请求调用的工具；服务端工具已在返回前执行完毕，这里只剩需要客户端执行的调用
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    // 请求带 response_schema 时，content 解析并通过校验后的 JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub raw_response: Option<serde_json::Value>,
-}
\ No newline at end of file
+}
+
+// 流式响应中的事件：增量内容，以及结束时的用量、结束原因和实际使用的提供商、模型
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StreamEvent {
+    Delta {
+        content: String,
+    },
+    Done {
+        finish_reason: Option<String>,
+        usage: Option<TokenUsage>,
+        // 提供商只填上游返回的值，实际应答的提供商和使用的模型由服务层补齐
+        provider: Option<String>,
+        model: Option<String>,
+    },
+}
+
+impl StreamEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::Delta { .. } => "delta",
+            StreamEvent::Done { .. } => "done",
+        }
+    }
+}
+
+// 向量嵌入请求，input 可以是单个字符串或字符串列表
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct EmbeddingRequest {
+    #[serde(deserialize_with = "deserialize_inputs")]
+    pub input: Vec<String>,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+}
+
+fn deserialize_inputs<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Inputs {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Inputs::deserialize(deserializer)? {
+        Inputs::One(input) => vec![input],
+        Inputs::Many(inputs) => inputs,
+    })
+}
+
+impl EmbeddingRequest {
+    pub fn validate(&self, max_inputs: usize) -> Result<(), AppError> {
+        if self.input.is_empty() {
+            return Err(AppError::ValidationError("input 不能为空".to_string()));
+        }
+        if self.input.len() > max_inputs {
+            return Err(AppError::ValidationError(format!("每个请求最多 {} 条 input", max_inputs)));
+        }
+        if let Some(index) = self.input.iter().position(|input| input.trim().is_empty()) {
+            return Err(AppError::ValidationError(format!("input[{}] 不能为空", index)));
+        }
+        Ok(())
+    }
+
+    // 第一条输入的文本，多条时注明总数
+    pub fn summary(&self) -> String {
+        let mut summary = truncate_summary(self.input.first().map(String::as_str).unwrap_or_default());
+        if self.input.len() > 1 {
+            summary.push_str(&format!(" [共 {} 条]", self.input.len()));
+        }
+        summary
+    }
+}
+
+// 提供商一次嵌入调用的结果，embeddings 与输入顺序一致
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingBatch {
+    pub embeddings: Vec<Vec<f32>>,
+    pub usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EmbeddingResponse {
+    // 与 input 顺序一致
+    pub embeddings: Vec<Vec<f32>>,
+    pub provider: String,
+    pub model: String,
+    // 只包含实际调用提供商的输入，全部命中缓存时为 None
+    pub usage: Option<TokenUsage>,
+    pub latency_ms: u64,
+    // 来自缓存的输入条数
+    pub cached: usize,
+}
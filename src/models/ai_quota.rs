@@ -0,0 +1,322 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::pagination::PaginationQuery;
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct AIPlan {
+    pub name: String,
+    // NULL 表示不限
+    pub daily_token_limit: Option<i64>,
+    pub monthly_token_limit: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct UserQuota {
+    pub user_id: Uuid,
+    pub plan: Option<String>,
+    pub daily_token_limit: Option<i64>,
+    pub monthly_token_limit: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdatePlanRequest {
+    pub daily_token_limit: Option<i64>,
+    pub monthly_token_limit: Option<i64>,
+}
+
+// 额度为空时使用方案的额度，plan 为空时使用默认方案
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateUserQuotaRequest {
+    pub plan: Option<String>,
+    pub daily_token_limit: Option<i64>,
+    pub monthly_token_limit: Option<i64>,
+}
+
+// 用户实际生效的额度
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct QuotaLimits {
+    pub plan: String,
+    pub daily_token_limit: Option<i64>,
+    pub monthly_token_limit: Option<i64>,
+}
+
+pub struct NewUsage<'a> {
+    pub user_id: Uuid,
+    pub day: NaiveDate,
+    pub provider: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub total_tokens: i64,
+    // 命中响应缓存，不计 token
+    pub cached: bool,
+}
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct ModelUsage {
+    pub provider: String,
+    pub model: String,
+    pub request_count: i64,
+    pub cache_hits: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub total_tokens: i64,
+}
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct UserUsageReport {
+    pub user_id: Uuid,
+    pub username: String,
+    pub email: String,
+    pub request_count: i64,
+    pub cache_hits: i64,
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub total_tokens: i64,
+}
+
+// 日期按 UTC 计算，包含 from 和 to 当天；默认为本月
+#[derive(Debug, Deserialize)]
+pub struct UsageReportQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+impl UsageReportQuery {
+    pub fn pagination(&self) -> PaginationQuery {
+        PaginationQuery {
+            page: self.page,
+            per_page: self.per_page,
+        }
+    }
+}
+
+fn validate_limit(limit: Option<i64>) -> Result<(), AppError> {
+    if limit.is_some_and(|limit| limit < 0) {
+        return Err(AppError::ValidationError("额度不能为负数".to_string()));
+    }
+    Ok(())
+}
+
+impl AIPlan {
+    pub async fn list(pool: &crate::db::DbPool) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as::<_, AIPlan>("SELECT * FROM ai_plans ORDER BY name")
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("查询 AI 额度方案失败: {}", e)))
+    }
+
+    pub async fn exists(pool: &crate::db::DbPool, name: &str) -> Result<bool, AppError> {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM ai_plans WHERE name = $1)")
+            .bind(name)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("查询 AI 额度方案失败: {}", e)))
+    }
+
+    // 创建或更新方案
+    pub async fn upsert(pool: &crate::db::DbPool, name: &str, req: &UpdatePlanRequest) -> Result<Self, AppError> {
+        validate_limit(req.daily_token_limit)?;
+        validate_limit(req.monthly_token_limit)?;
+
+        sqlx::query_as::<_, AIPlan>(
+            r#"
+            INSERT INTO ai_plans (name, daily_token_limit, monthly_token_limit)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (name) DO UPDATE
+            SET daily_token_limit = EXCLUDED.daily_token_limit,
+                monthly_token_limit = EXCLUDED.monthly_token_limit,
+                updated_at = NOW()
+            RETURNING *
+            "#,
+        )
+        .bind(name)
+        .bind(req.daily_token_limit)
+        .bind(req.monthly_token_limit)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存 AI 额度方案失败: {}", e)))
+    }
+}
+
+impl UserQuota {
+    pub async fn upsert(
+        pool: &crate::db::DbPool,
+        user_id: Uuid,
+        req: &UpdateUserQuotaRequest,
+    ) -> Result<Self, AppError> {
+        validate_limit(req.daily_token_limit)?;
+        validate_limit(req.monthly_token_limit)?;
+        if let Some(plan) = &req.plan {
+            if !AIPlan::exists(pool, plan).await? {
+                return Err(AppError::ValidationError(format!("AI 额度方案不存在: {}", plan)));
+            }
+        }
+
+        sqlx::query_as::<_, UserQuota>(
+            r#"
+            INSERT INTO ai_user_quotas (user_id, plan, daily_token_limit, monthly_token_limit)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id) DO UPDATE
+            SET plan = EXCLUDED.plan,
+                daily_token_limit = EXCLUDED.daily_token_limit,
+                monthly_token_limit = EXCLUDED.monthly_token_limit,
+                updated_at = NOW()
+            RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(&req.plan)
+        .bind(req.daily_token_limit)
+        .bind(req.monthly_token_limit)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存用户 AI 额度失败: {}", e)))
+    }
+}
+
+impl QuotaLimits {
+    pub async fn for_user(pool: &crate::db::DbPool, user_id: Uuid, default_plan: &str) -> Result<Self, AppError> {
+        sqlx::query_as::<_, QuotaLimits>(
+            r#"
+            SELECT COALESCE(q.plan, d.name) AS plan,
+                   COALESCE(q.daily_token_limit, p.daily_token_limit) AS daily_token_limit,
+                   COALESCE(q.monthly_token_limit, p.monthly_token_limit) AS monthly_token_limit
+            FROM (SELECT $2::VARCHAR AS name) d
+            LEFT JOIN ai_user_quotas q ON q.user_id = $1
+            LEFT JOIN ai_plans p ON p.name = COALESCE(q.plan, d.name)
+            "#,
+        )
+        .bind(user_id)
+        .bind(default_plan)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询用户 AI 额度失败: {}", e)))
+    }
+}
+
+impl ModelUsage {
+    // 累加一次调用的用量
+    pub async fn add(pool: &crate::db::DbPool, usage: NewUsage<'_>) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO ai_usage_daily (
+                user_id, day, provider, model, request_count, cache_hits, input_tokens, output_tokens, total_tokens
+            )
+            VALUES ($1, $2, $3, $4, 1, $5, $6, $7, $8)
+            ON CONFLICT (user_id, day, provider, model) DO UPDATE
+            SET request_count = ai_usage_daily.request_count + 1,
+                cache_hits = ai_usage_daily.cache_hits + EXCLUDED.cache_hits,
+                input_tokens = ai_usage_daily.input_tokens + EXCLUDED.input_tokens,
+                output_tokens = ai_usage_daily.output_tokens + EXCLUDED.output_tokens,
+                total_tokens = ai_usage_daily.total_tokens + EXCLUDED.total_tokens
+            "#,
+        )
+        .bind(usage.user_id)
+        .bind(usage.day)
+        .bind(usage.provider.unwrap_or_default())
+        .bind(usage.model.unwrap_or_default())
+        .bind(i64::from(usage.cached))
+        .bind(usage.input_tokens)
+        .bind(usage.output_tokens)
+        .bind(usage.total_tokens)
+        .execute(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存 AI 用量失败: {}", e)))?;
+        Ok(())
+    }
+
+    // 从 since 当天起累计使用的 token 数
+    pub async fn tokens_since(pool: &crate::db::DbPool, user_id: Uuid, since: NaiveDate) -> Result<i64, AppError> {
+        sqlx::query_scalar(
+            "SELECT COALESCE(SUM(total_tokens), 0)::BIGINT FROM ai_usage_daily WHERE user_id = $1 AND day >= $2"
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("统计 AI 用量失败: {}", e)))
+    }
+
+    pub async fn list_for_user(
+        pool: &crate::db::DbPool,
+        user_id: Uuid,
+        since: NaiveDate,
+    ) -> Result<Vec<Self>, AppError> {
+        sqlx::query_as::<_, ModelUsage>(
+            r#"
+            SELECT provider, model,
+                   SUM(request_count)::BIGINT AS request_count,
+                   SUM(cache_hits)::BIGINT AS cache_hits,
+                   SUM(input_tokens)::BIGINT AS input_tokens,
+                   SUM(output_tokens)::BIGINT AS output_tokens,
+                   SUM(total_tokens)::BIGINT AS total_tokens
+            FROM ai_usage_daily
+            WHERE user_id = $1 AND day >= $2
+            GROUP BY provider, model
+            ORDER BY total_tokens DESC, provider, model
+            "#,
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询 AI 用量失败: {}", e)))
+    }
+}
+
+impl UserUsageReport {
+    // 按用户汇总，用量最多的在前
+    pub async fn search(
+        pool: &crate::db::DbPool,
+        from: NaiveDate,
+        to: NaiveDate,
+        pagination: &PaginationQuery,
+    ) -> Result<(Vec<Self>, i64), AppError> {
+        let rows = sqlx::query_as::<_, UserUsageReport>(
+            r#"
+            SELECT u.id AS user_id, u.username, u.email,
+                   SUM(a.request_count)::BIGINT AS request_count,
+                   SUM(a.cache_hits)::BIGINT AS cache_hits,
+                   SUM(a.input_tokens)::BIGINT AS input_tokens,
+                   SUM(a.output_tokens)::BIGINT AS output_tokens,
+                   SUM(a.total_tokens)::BIGINT AS total_tokens
+            FROM ai_usage_daily a
+            JOIN users u ON u.id = a.user_id
+            WHERE a.day BETWEEN $1 AND $2
+            GROUP BY u.id, u.username, u.email
+            ORDER BY total_tokens DESC, u.id
+            LIMIT $3 OFFSET $4
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .bind(pagination.per_page())
+        .bind(pagination.offset())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询 AI 用量报表失败: {}", e)))?;
+
+        let total: i64 = sqlx::query_scalar(
+            "SELECT COUNT(DISTINCT user_id) FROM ai_usage_daily WHERE day BETWEEN $1 AND $2"
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("统计 AI 用量报表失败: {}", e)))?;
+
+        Ok((rows, total))
+    }
+}
@@ -0,0 +1,147 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::ai::TokenUsage;
+use crate::models::pagination::PaginationQuery;
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct AIRequestRecord {
+    pub id: i64,
+    pub endpoint: String,
+    pub stream: bool,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub input_summary: String,
+    pub response: Option<String>,
+    pub finish_reason: Option<String>,
+    pub input_tokens: Option<i64>,
+    pub output_tokens: Option<i64>,
+    pub total_tokens: Option<i64>,
+    pub latency_ms: i64,
+    // 命中响应缓存，未调用提供商
+    pub cached: bool,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AIRequestStatus {
+    Success,
+    Error,
+    // 流式响应在结束事件之前中断，例如客户端断开
+    Incomplete,
+}
+
+impl AIRequestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AIRequestStatus::Success => "success",
+            AIRequestStatus::Error => "error",
+            AIRequestStatus::Incomplete => "incomplete",
+        }
+    }
+}
+
+pub struct NewAIRequestRecord<'a> {
+    pub user_id: Uuid,
+    pub endpoint: &'a str,
+    pub stream: bool,
+    pub provider: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub input_summary: &'a str,
+    pub response: Option<&'a str>,
+    pub finish_reason: Option<&'a str>,
+    pub usage: Option<&'a TokenUsage>,
+    pub latency_ms: i64,
+    pub cached: bool,
+    pub status: AIRequestStatus,
+    pub error: Option<&'a str>,
+}
+
+impl AIRequestRecord {
+    pub async fn create(pool: &crate::db::DbPool, record: NewAIRequestRecord<'_>) -> Result<(), AppError> {
+        let usage = record.usage.cloned().unwrap_or_default();
+        sqlx::query(
+            r#"
+            INSERT INTO ai_requests (
+                user_id, endpoint, stream, provider, model, input_summary, response, finish_reason,
+                input_tokens, output_tokens, total_tokens, latency_ms, cached, status, error
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
+            "#,
+        )
+        .bind(record.user_id)
+        .bind(record.endpoint)
+        .bind(record.stream)
+        .bind(record.provider)
+        .bind(record.model)
+        .bind(record.input_summary)
+        .bind(record.response)
+        .bind(record.finish_reason)
+        .bind(usage.input_tokens.map(|tokens| tokens as i64))
+        .bind(usage.output_tokens.map(|tokens| tokens as i64))
+        .bind(usage.total_tokens.map(|tokens| tokens as i64))
+        .bind(record.latency_ms)
+        .bind(record.cached)
+        .bind(record.status.as_str())
+        .bind(record.error)
+        .execute(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存 AI 调用记录失败: {}", e)))?;
+        Ok(())
+    }
+
+    pub async fn list_for_user(
+        pool: &crate::db::DbPool,
+        user_id: Uuid,
+        pagination: &PaginationQuery,
+    ) -> Result<(Vec<Self>, i64), AppError> {
+        let records = sqlx::query_as::<_, AIRequestRecord>(
+            r#"
+            SELECT * FROM ai_requests
+            WHERE user_id = $1
+            ORDER BY id DESC
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(pagination.per_page())
+        .bind(pagination.offset())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询 AI 调用记录失败: {}", e)))?;
+
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM ai_requests WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("统计 AI 调用记录失败: {}", e)))?;
+
+        Ok((records, total))
+    }
+
+    pub async fn delete_for_user(pool: &crate::db::DbPool, id: i64, user_id: Uuid) -> Result<bool, AppError> {
+        let deleted = sqlx::query("DELETE FROM ai_requests WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("删除 AI 调用记录失败: {}", e)))?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    pub async fn delete_all_for_user(pool: &crate::db::DbPool, user_id: Uuid) -> Result<u64, AppError> {
+        let deleted = sqlx::query("DELETE FROM ai_requests WHERE user_id = $1")
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("删除 AI 调用记录失败: {}", e)))?
+            .rows_affected();
+        Ok(deleted)
+    }
+}
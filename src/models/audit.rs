@@ -0,0 +1,433 @@
+use chrono::{DateTime, SecondsFormat, SubsecRound, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Postgres, QueryBuilder};
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::pagination::PaginationQuery;
+
+// 哈希链起点
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// 写入审计日志时使用的事务级咨询锁，保证哈希链串行追加
+const AUDIT_CHAIN_LOCK_KEY: i64 = 0x6175_6469_745f_6c67;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEventType {
+    Register,
+    LoginSuccess,
+    LoginFailure,
+    OAuthLogin,
+    AvatarChanged,
+    ProfileUpdated,
+    // JWT 目前无状态、没有吊销接口，暂不会写入；保留以便实现吊销后记录
+    TokenRevoked,
+    AdminAction,
+}
+
+impl AuditEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventType::Register => "register",
+            AuditEventType::LoginSuccess => "login_success",
+            AuditEventType::LoginFailure => "login_failure",
+            AuditEventType::OAuthLogin => "oauth_login",
+            AuditEventType::AvatarChanged => "avatar_changed",
+            AuditEventType::ProfileUpdated => "profile_updated",
+            AuditEventType::TokenRevoked => "token_revoked",
+            AuditEventType::AdminAction => "admin_action",
+        }
+    }
+}
+
+impl fmt::Display for AuditEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuditEventType {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "register" => Ok(AuditEventType::Register),
+            "login_success" => Ok(AuditEventType::LoginSuccess),
+            "login_failure" => Ok(AuditEventType::LoginFailure),
+            "oauth_login" => Ok(AuditEventType::OAuthLogin),
+            "avatar_changed" => Ok(AuditEventType::AvatarChanged),
+            "profile_updated" => Ok(AuditEventType::ProfileUpdated),
+            "token_revoked" => Ok(AuditEventType::TokenRevoked),
+            "admin_action" => Ok(AuditEventType::AdminAction),
+            _ => Err(AppError::ValidationError(format!("未知的审计事件类型: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub event_type: String,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub payload: serde_json::Value,
+    pub prev_hash: String,
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+    pub event_type: AuditEventType,
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub actor_id: Option<Uuid>,
+    pub target_id: Option<Uuid>,
+    pub event_type: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn pagination(&self) -> PaginationQuery {
+        PaginationQuery {
+            page: self.page,
+            per_page: self.per_page,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditChainReport {
+    pub valid: bool,
+    pub checked: i64,
+    pub broken_at: Option<i64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compute_hash(
+    prev_hash: &str,
+    event_type: &str,
+    actor_id: Option<Uuid>,
+    target_id: Option<Uuid>,
+    ip_address: Option<&str>,
+    user_agent: Option<&str>,
+    payload: &serde_json::Value,
+    created_at: &DateTime<Utc>,
+) -> String {
+    // 用 JSON 数组作为规范化表示，避免字段拼接产生歧义
+    let canonical = serde_json::json!([
+        prev_hash,
+        event_type,
+        actor_id,
+        target_id,
+        ip_address,
+        user_agent,
+        payload,
+        created_at.to_rfc3339_opts(SecondsFormat::Micros, true),
+    ]);
+    hex::encode(Sha256::digest(canonical.to_string().as_bytes()))
+}
+
+impl AuditEvent {
+    fn expected_hash(&self) -> String {
+        compute_hash(
+            &self.prev_hash,
+            &self.event_type,
+            self.actor_id,
+            self.target_id,
+            self.ip_address.as_deref(),
+            self.user_agent.as_deref(),
+            &self.payload,
+            &self.created_at,
+        )
+    }
+
+    // 记录接在 expected_prev 之后且内容未被修改
+    fn follows(&self, expected_prev: &str) -> bool {
+        self.prev_hash == expected_prev && self.hash == self.expected_hash()
+    }
+
+    pub async fn record(
+        pool: &crate::db::DbPool,
+        event: NewAuditEvent,
+    ) -> Result<Self, AppError> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("开启审计事务失败: {}", e)))?;
+
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(AUDIT_CHAIN_LOCK_KEY)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("获取审计锁失败: {}", e)))?;
+
+        let prev_hash: String = sqlx::query_scalar(
+            "SELECT hash FROM audit_events ORDER BY id DESC LIMIT 1"
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("读取审计链失败: {}", e)))?
+        .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+        // PostgreSQL 只保存到微秒，截断后哈希才能复算
+        let created_at = Utc::now().trunc_subsecs(6);
+        let event_type = event.event_type.as_str();
+        let hash = compute_hash(
+            &prev_hash,
+            event_type,
+            event.actor_id,
+            event.target_id,
+            event.ip_address.as_deref(),
+            event.user_agent.as_deref(),
+            &event.payload,
+            &created_at,
+        );
+
+        let record = sqlx::query_as::<_, AuditEvent>(
+            r#"
+            INSERT INTO audit_events
+                (event_type, actor_id, target_id, ip_address, user_agent, payload, prev_hash, hash, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            RETURNING *
+            "#,
+        )
+        .bind(event_type)
+        .bind(event.actor_id)
+        .bind(event.target_id)
+        .bind(&event.ip_address)
+        .bind(&event.user_agent)
+        .bind(&event.payload)
+        .bind(&prev_hash)
+        .bind(&hash)
+        .bind(created_at)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("写入审计日志失败: {}", e)))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("提交审计事务失败: {}", e)))?;
+
+        Ok(record)
+    }
+
+    pub async fn list_for_user(
+        pool: &crate::db::DbPool,
+        user_id: Uuid,
+        pagination: &PaginationQuery,
+    ) -> Result<(Vec<Self>, i64), AppError> {
+        let events = sqlx::query_as::<_, AuditEvent>(
+            r#"
+            SELECT * FROM audit_events
+            WHERE actor_id = $1 OR target_id = $1
+            ORDER BY id DESC
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(pagination.per_page())
+        .bind(pagination.offset())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询审计日志失败: {}", e)))?;
+
+        let total: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM audit_events WHERE actor_id = $1 OR target_id = $1"
+        )
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("统计审计日志失败: {}", e)))?;
+
+        Ok((events, total))
+    }
+
+    pub async fn search(
+        pool: &crate::db::DbPool,
+        query: &AuditQuery,
+    ) -> Result<(Vec<Self>, i64), AppError> {
+        let event_type = query
+            .event_type
+            .as_deref()
+            .map(AuditEventType::from_str)
+            .transpose()?;
+
+        let push_filters = |builder: &mut QueryBuilder<'_, Postgres>| {
+            builder.push(" WHERE TRUE");
+            if let Some(actor_id) = query.actor_id {
+                builder.push(" AND actor_id = ").push_bind(actor_id);
+            }
+            if let Some(target_id) = query.target_id {
+                builder.push(" AND target_id = ").push_bind(target_id);
+            }
+            if let Some(event_type) = event_type {
+                builder.push(" AND event_type = ").push_bind(event_type.as_str());
+            }
+            if let Some(from) = query.from {
+                builder.push(" AND created_at >= ").push_bind(from);
+            }
+            if let Some(to) = query.to {
+                builder.push(" AND created_at < ").push_bind(to);
+            }
+        };
+
+        let pagination = query.pagination();
+
+        let mut builder = QueryBuilder::<Postgres>::new("SELECT * FROM audit_events");
+        push_filters(&mut builder);
+        builder
+            .push(" ORDER BY id DESC LIMIT ")
+            .push_bind(pagination.per_page())
+            .push(" OFFSET ")
+            .push_bind(pagination.offset());
+
+        let events = builder
+            .build_query_as::<AuditEvent>()
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("查询审计日志失败: {}", e)))?;
+
+        let mut count_builder = QueryBuilder::<Postgres>::new("SELECT COUNT(*) FROM audit_events");
+        push_filters(&mut count_builder);
+        let total: i64 = count_builder
+            .build_query_scalar()
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("统计审计日志失败: {}", e)))?;
+
+        Ok((events, total))
+    }
+
+    // 按顺序复算整条哈希链，返回第一条被篡改或断链的记录
+    pub async fn verify_chain(pool: &crate::db::DbPool) -> Result<AuditChainReport, AppError> {
+        const BATCH_SIZE: i64 = 1000;
+
+        let mut last_id = 0i64;
+        let mut expected_prev = GENESIS_HASH.to_string();
+        let mut checked = 0i64;
+
+        loop {
+            let batch = sqlx::query_as::<_, AuditEvent>(
+                "SELECT * FROM audit_events WHERE id > $1 ORDER BY id ASC LIMIT $2"
+            )
+            .bind(last_id)
+            .bind(BATCH_SIZE)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("读取审计链失败: {}", e)))?;
+
+            if batch.is_empty() {
+                break;
+            }
+
+            match first_broken(&batch, &expected_prev) {
+                Ok(last_hash) => {
+                    checked += batch.len() as i64;
+                    last_id = batch[batch.len() - 1].id;
+                    expected_prev = last_hash;
+                }
+                Err(broken_id) => {
+                    return Ok(AuditChainReport {
+                        valid: false,
+                        checked: checked + batch.iter().take_while(|event| event.id != broken_id).count() as i64,
+                        broken_at: Some(broken_id),
+                    });
+                }
+            }
+        }
+
+        Ok(AuditChainReport {
+            valid: true,
+            checked,
+            broken_at: None,
+        })
+    }
+}
+
+// 校验一段接在 expected_prev 之后的记录，成功时返回最后一条的哈希，否则返回第一条断开记录的 ID
+fn first_broken(events: &[AuditEvent], expected_prev: &str) -> Result<String, i64> {
+    let mut expected_prev = expected_prev;
+    for event in events {
+        if !event.follows(expected_prev) {
+            return Err(event.id);
+        }
+        expected_prev = &event.hash;
+    }
+    Ok(expected_prev.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chain(len: i64) -> Vec<AuditEvent> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        (1..=len)
+            .map(|id| {
+                let created_at = Utc::now().trunc_subsecs(6);
+                let payload = json!({ "seq": id });
+                let hash = compute_hash(
+                    &prev_hash, "login_success", None, None, Some("203.0.113.7"), Some("curl/8.0"), &payload, &created_at,
+                );
+                AuditEvent {
+                    id,
+                    event_type: "login_success".to_string(),
+                    actor_id: None,
+                    target_id: None,
+                    ip_address: Some("203.0.113.7".to_string()),
+                    user_agent: Some("curl/8.0".to_string()),
+                    payload,
+                    prev_hash: std::mem::replace(&mut prev_hash, hash.clone()),
+                    hash,
+                    created_at,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_chain_verifies() {
+        let events = chain(3);
+
+        assert_eq!(first_broken(&events, GENESIS_HASH), Ok(events[2].hash.clone()));
+    }
+
+    #[test]
+    fn batches_continue_from_previous_hash() {
+        let events = chain(3);
+
+        let last_hash = first_broken(&events[..2], GENESIS_HASH).unwrap();
+        assert_eq!(first_broken(&events[2..], &last_hash), Ok(events[2].hash.clone()));
+        assert_eq!(first_broken(&events[2..], GENESIS_HASH), Err(3));
+        assert_eq!(first_broken(&[], &last_hash), Ok(last_hash));
+    }
+
+    #[test]
+    fn tampered_row_breaks_chain() {
+        let mut events = chain(3);
+        events[1].payload = json!({ "seq": 99 });
+
+        assert_eq!(first_broken(&events, GENESIS_HASH), Err(2));
+
+        // 重新计算被改记录的哈希后，下一条记录的 prev_hash 不再匹配
+        events[1].hash = events[1].expected_hash();
+        assert_eq!(first_broken(&events, GENESIS_HASH), Err(3));
+    }
+}
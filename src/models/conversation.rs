@@ -0,0 +1,406 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+use crate::errors::AppError;
+use crate::models::ai::ChatRole;
+use crate::models::pagination::PaginationQuery;
+
+pub const DEFAULT_CONVERSATION_TITLE: &str = "新对话";
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Conversation {
+    pub id: Uuid,
+    pub title: String,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub system_prompt: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Message {
+    pub id: i64,
+    pub conversation_id: Uuid,
+    pub role: String,
+    pub content: String,
+    pub attachment_ids: Vec<Uuid>,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub conversation_id: Uuid,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    pub content_type: String,
+    pub size_bytes: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateConversationRequest {
+    pub title: Option<String>,
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub system_prompt: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameConversationRequest {
+    pub title: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SendMessageRequest {
+    pub content: String,
+    // 先通过 attachments 接口上传，再在消息中引用
+    #[serde(default)]
+    pub attachment_ids: Vec<Uuid>,
+    // 覆盖对话设置的提供商和模型，仅对本条消息生效
+    pub provider: Option<String>,
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendMessageResponse {
+    pub user_message: Message,
+    pub assistant_message: Message,
+}
+
+// 新的一问一答，成功调用 AI 后一起写入
+pub struct NewExchange<'a> {
+    pub user_content: &'a str,
+    pub attachment_ids: &'a [Uuid],
+    pub assistant_content: &'a str,
+    pub provider: Option<&'a str>,
+    pub model: Option<&'a str>,
+}
+
+impl Conversation {
+    pub async fn create(
+        pool: &crate::db::DbPool,
+        user_id: Uuid,
+        title: &str,
+        req: &CreateConversationRequest,
+    ) -> Result<Self, AppError> {
+        sqlx::query_as::<_, Conversation>(
+            r#"
+            INSERT INTO conversations (user_id, title, provider, model, system_prompt)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *
+            "#,
+        )
+        .bind(user_id)
+        .bind(title)
+        .bind(&req.provider)
+        .bind(&req.model)
+        .bind(&req.system_prompt)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("创建对话失败: {}", e)))
+    }
+
+    pub async fn find_for_user(
+        pool: &crate::db::DbPool,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as::<_, Conversation>(
+            "SELECT * FROM conversations WHERE id = $1 AND user_id = $2"
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询对话失败: {}", e)))
+    }
+
+    pub async fn list_for_user(
+        pool: &crate::db::DbPool,
+        user_id: Uuid,
+        pagination: &PaginationQuery,
+    ) -> Result<(Vec<Self>, i64), AppError> {
+        let conversations = sqlx::query_as::<_, Conversation>(
+            r#"
+            SELECT * FROM conversations
+            WHERE user_id = $1
+            ORDER BY updated_at DESC, id
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(user_id)
+        .bind(pagination.per_page())
+        .bind(pagination.offset())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询对话列表失败: {}", e)))?;
+
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversations WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("统计对话失败: {}", e)))?;
+
+        Ok((conversations, total))
+    }
+
+    pub async fn rename(
+        pool: &crate::db::DbPool,
+        id: Uuid,
+        user_id: Uuid,
+        title: &str,
+    ) -> Result<Option<Self>, AppError> {
+        sqlx::query_as::<_, Conversation>(
+            r#"
+            UPDATE conversations SET title = $3, updated_at = NOW()
+            WHERE id = $1 AND user_id = $2
+            RETURNING *
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(title)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("重命名对话失败: {}", e)))
+    }
+
+    // 删除对话及其消息，返回需要从存储中删除的附件键；对话不存在时返回 None
+    pub async fn delete(
+        pool: &crate::db::DbPool,
+        id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Option<Vec<String>>, AppError> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("开启事务失败: {}", e)))?;
+
+        let keys: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT a.storage_key FROM conversation_attachments a
+            JOIN conversations c ON c.id = a.conversation_id
+            WHERE c.id = $1 AND c.user_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询对话附件失败: {}", e)))?;
+
+        let deleted = sqlx::query("DELETE FROM conversations WHERE id = $1 AND user_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("删除对话失败: {}", e)))?
+            .rows_affected();
+
+        tx.commit()
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("提交事务失败: {}", e)))?;
+
+        Ok((deleted > 0).then_some(keys))
+    }
+}
+
+impl Message {
+    pub fn chat_role(&self) -> ChatRole {
+        match self.role.as_str() {
+            "assistant" => ChatRole::Assistant,
+            _ => ChatRole::User,
+        }
+    }
+
+    pub async fn list(
+        pool: &crate::db::DbPool,
+        conversation_id: Uuid,
+        pagination: &PaginationQuery,
+    ) -> Result<(Vec<Self>, i64), AppError> {
+        let messages = sqlx::query_as::<_, Message>(
+            r#"
+            SELECT * FROM messages
+            WHERE conversation_id = $1
+            ORDER BY id
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(pagination.per_page())
+        .bind(pagination.offset())
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询消息失败: {}", e)))?;
+
+        let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation_id = $1")
+            .bind(conversation_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("统计消息失败: {}", e)))?;
+
+        Ok((messages, total))
+    }
+
+    // 最近的 limit 条消息，按时间正序；截断后以助手消息开头时去掉这些消息，
+    // 因为 Anthropic 等接口要求对话从用户消息开始
+    pub async fn recent(
+        pool: &crate::db::DbPool,
+        conversation_id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<Self>, AppError> {
+        let mut messages = sqlx::query_as::<_, Message>(
+            r#"
+            SELECT * FROM messages
+            WHERE conversation_id = $1
+            ORDER BY id DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询对话历史失败: {}", e)))?;
+
+        messages.reverse();
+        Ok(skip_leading_assistant(messages))
+    }
+
+    pub async fn insert_exchange(
+        pool: &crate::db::DbPool,
+        conversation_id: Uuid,
+        exchange: NewExchange<'_>,
+    ) -> Result<(Self, Self), AppError> {
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("开启事务失败: {}", e)))?;
+
+        let user_message = sqlx::query_as::<_, Message>(
+            r#"
+            INSERT INTO messages (conversation_id, role, content, attachment_ids)
+            VALUES ($1, 'user', $2, $3)
+            RETURNING *
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(exchange.user_content)
+        .bind(exchange.attachment_ids)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存消息失败: {}", e)))?;
+
+        let assistant_message = sqlx::query_as::<_, Message>(
+            r#"
+            INSERT INTO messages (conversation_id, role, content, provider, model)
+            VALUES ($1, 'assistant', $2, $3, $4)
+            RETURNING *
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(exchange.assistant_content)
+        .bind(exchange.provider)
+        .bind(exchange.model)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存消息失败: {}", e)))?;
+
+        sqlx::query("UPDATE conversations SET updated_at = NOW() WHERE id = $1")
+            .bind(conversation_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("更新对话失败: {}", e)))?;
+
+        tx.commit()
+            .await
+            .map_err(|e| AppError::DatabaseError(format!("提交事务失败: {}", e)))?;
+
+        Ok((user_message, assistant_message))
+    }
+}
+
+impl Attachment {
+    pub async fn create(
+        pool: &crate::db::DbPool,
+        conversation_id: Uuid,
+        storage_key: &str,
+        content_type: &str,
+        size_bytes: i32,
+    ) -> Result<Self, AppError> {
+        sqlx::query_as::<_, Attachment>(
+            r#"
+            INSERT INTO conversation_attachments (conversation_id, storage_key, content_type, size_bytes)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *
+            "#,
+        )
+        .bind(conversation_id)
+        .bind(storage_key)
+        .bind(content_type)
+        .bind(size_bytes)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("保存附件失败: {}", e)))
+    }
+
+    // 只返回属于该对话的附件
+    pub async fn find_many(
+        pool: &crate::db::DbPool,
+        conversation_id: Uuid,
+        ids: &[Uuid],
+    ) -> Result<Vec<Self>, AppError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        sqlx::query_as::<_, Attachment>(
+            "SELECT * FROM conversation_attachments WHERE conversation_id = $1 AND id = ANY($2)"
+        )
+        .bind(conversation_id)
+        .bind(ids)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| AppError::DatabaseError(format!("查询附件失败: {}", e)))
+    }
+}
+
+fn skip_leading_assistant(messages: Vec<Message>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .skip_while(|message| message.chat_role() == ChatRole::Assistant)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, role: &str) -> Message {
+        Message {
+            id,
+            conversation_id: Uuid::nil(),
+            role: role.to_string(),
+            content: String::new(),
+            attachment_ids: Vec::new(),
+            provider: None,
+            model: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn history_starts_with_a_user_message() {
+        let messages = vec![message(2, "assistant"), message(3, "user"), message(4, "assistant")];
+
+        let ids: Vec<i64> = skip_leading_assistant(messages).iter().map(|message| message.id).collect();
+
+        assert_eq!(ids, [3, 4]);
+        assert!(skip_leading_assistant(vec![message(1, "assistant")]).is_empty());
+    }
+}
@@ -1,2 +1,7 @@
 pub mod user;
-pub mod ai;
\ No newline at end of file
+pub mod ai;
+pub mod audit;
+pub mod pagination;
+pub mod conversation;
+pub mod ai_request;
+pub mod ai_quota;
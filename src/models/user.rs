@@ -3,7 +3,24 @@ use chrono::{DateTime, Utc};
 use uuid::Uuid;
 use sqlx::FromRow;
 
-pub const DEFAULT_AVATAR: &str = "/default-avatar.png";
+use crate::auth::validation::{normalize_email, username_skeleton, username_with_suffix};
+use crate::config::UsernamePolicy;
+
+// 未上传头像的用户使用按 ID 生成的默认头像
+pub const GENERATED_AVATAR_PREFIX: &str = "/avatars";
+pub const ROLE_ADMIN: &str = "admin";
+
+// PostgreSQL unique_violation
+const UNIQUE_VIOLATION: &str = "23505";
+
+fn map_insert_error(e: sqlx::Error, context: &str) -> crate::errors::AppError {
+    if let sqlx::Error::Database(ref db_err) = e {
+        if db_err.code().as_deref() == Some(UNIQUE_VIOLATION) {
+            return crate::errors::AppError::ValidationError("邮箱或用户名已被使用".to_string());
+        }
+    }
+    crate::errors::AppError::DatabaseError(format!("{}: {}", context, e))
+}
 
 #[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
 pub struct User {
@@ -15,6 +32,12 @@ pub struct User {
     pub avatar_url: Option<String>,
     pub oauth_provider: Option<String>,
     pub oauth_id: Option<String>,
+    pub role: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    pub username_changed_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -32,10 +55,24 @@ pub struct LoginRequest {
     pub password: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Default)]
 pub struct UpdateUserRequest {
     pub username: Option<String>,
     pub avatar_url: Option<String>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+}
+
+// PATCH /user/profile 请求体，空字符串表示清空该字段
+#[derive(Debug, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -50,6 +87,10 @@ pub struct UserResponse {
     pub email: String,
     pub username: String,
     pub avatar: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -59,16 +100,33 @@ pub struct Claims {
     pub iat: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct OAuthUserProfile {
-    pub provider: String,
-    pub provider_user_id: String,
-    pub email: String,
-    pub name: Option<String>,
-    pub picture: Option<String>,
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        let avatar = user.avatar();
+        UserResponse {
+            id: user.id.to_string(),
+            email: user.email,
+            username: user.username,
+            avatar,
+            display_name: user.display_name,
+            bio: user.bio,
+            locale: user.locale,
+            timezone: user.timezone,
+        }
+    }
 }
 
 impl User {
+    pub fn avatar(&self) -> String {
+        self.avatar_url
+            .clone()
+            .unwrap_or_else(|| format!("{}/{}", GENERATED_AVATAR_PREFIX, self.id))
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN
+    }
+
     pub async fn create(
         pool: &crate::db::DbPool,
         email: String,
@@ -77,17 +135,19 @@ impl User {
     ) -> Result<Self, crate::errors::AppError> {
         let user = sqlx::query_as::<_, User>(
             r#"
-            INSERT INTO users (email, username, password_hash, created_at, updated_at)
-            VALUES ($1, $2, $3, NOW(), NOW())
+            INSERT INTO users (email, email_normalized, username, username_skeleton, password_hash, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, NOW(), NOW())
             RETURNING *
             "#,
         )
         .bind(&email)
+        .bind(normalize_email(&email))
         .bind(&username)
+        .bind(username_skeleton(&username))
         .bind(&password_hash)
         .fetch_one(pool)
         .await
-        .map_err(|e| crate::errors::AppError::DatabaseError(format!("创建用户失败: {}", e)))?;
+        .map_err(|e| map_insert_error(e, "创建用户失败"))?;
 
         Ok(user)
     }
@@ -96,10 +156,17 @@ impl User {
         pool: &crate::db::DbPool,
         email: &str,
     ) -> Result<Option<Self>, crate::errors::AppError> {
+        // 规范化后重复的旧账号没有 email_normalized，只能按原邮箱精确匹配
         let user = sqlx::query_as::<_, User>(
-            "SELECT * FROM users WHERE email = $1"
+            r#"
+            SELECT * FROM users
+            WHERE email_normalized = $1 OR (email_normalized IS NULL AND email = $2)
+            ORDER BY email_normalized IS NULL
+            LIMIT 1
+            "#,
         )
-        .bind(email)
+        .bind(normalize_email(email))
+        .bind(email.trim())
         .fetch_optional(pool)
         .await
         .map_err(|e| crate::errors::AppError::DatabaseError(format!("查找用户失败: {}", e)))?;
@@ -122,6 +189,72 @@ impl User {
         Ok(user)
     }
 
+    // 查找骨架相同（视觉上可混淆）的用户名
+    pub async fn find_by_username_skeleton(
+        pool: &crate::db::DbPool,
+        username: &str,
+    ) -> Result<Option<Self>, crate::errors::AppError> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT * FROM users WHERE username_skeleton = $1"
+        )
+        .bind(username_skeleton(username))
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| crate::errors::AppError::DatabaseError(format!("查找用户失败: {}", e)))?;
+
+        Ok(user)
+    }
+
+    // 为迁移前创建的用户补全规范化邮箱；按注册时间顺序回填，规范化后重复的较新账号保留为空并告警
+    pub async fn backfill_normalized_emails(
+        pool: &crate::db::DbPool,
+    ) -> Result<(), crate::errors::AppError> {
+        let rows: Vec<(Uuid, String)> = sqlx::query_as(
+            "SELECT id, email FROM users WHERE email_normalized IS NULL ORDER BY created_at, id"
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| crate::errors::AppError::DatabaseError(format!("查找用户失败: {}", e)))?;
+
+        for (id, email) in rows {
+            let result = sqlx::query("UPDATE users SET email_normalized = $1 WHERE id = $2")
+                .bind(normalize_email(&email))
+                .bind(id)
+                .execute(pool)
+                .await;
+            if let Err(e) = result {
+                log::warn!("规范化邮箱回填失败，可能与其他账号重复 {} ({}): {}", email, id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // 为迁移前创建的用户补全用户名骨架，冲突的记录保留为空并告警
+    pub async fn backfill_username_skeletons(
+        pool: &crate::db::DbPool,
+    ) -> Result<(), crate::errors::AppError> {
+        let rows: Vec<(Uuid, String)> = sqlx::query_as(
+            "SELECT id, username FROM users WHERE username_skeleton IS NULL"
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| crate::errors::AppError::DatabaseError(format!("查找用户失败: {}", e)))?;
+
+        for (id, username) in rows {
+            let result = sqlx::query("UPDATE users SET username_skeleton = $1 WHERE id = $2")
+                .bind(username_skeleton(&username))
+                .bind(id)
+                .execute(pool)
+                .await;
+            if let Err(e) = result {
+                log::warn!("用户名骨架回填失败 {} ({}): {}", username, id, e);
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn find_by_id(
         pool: &crate::db::DbPool,
         id: Uuid,
@@ -144,20 +277,44 @@ impl User {
     ) -> Result<(), crate::errors::AppError> {
         let mut query_parts = vec!["UPDATE users SET updated_at = NOW()".to_string()];
         let mut param_count = 1;
-        let mut params: Vec<&str> = vec![];
+        // 资料字段传空字符串表示清空
+        let mut params: Vec<Option<&str>> = vec![];
+        let skeleton = update_req.username.as_deref().map(username_skeleton);
+        let username_changed = update_req.username.as_deref()
+            .is_some_and(|username| username != self.username);
 
-        if let Some(ref username) = update_req.username {
+        if let (Some(username), Some(skeleton)) = (update_req.username.as_deref(), skeleton.as_deref()) {
             query_parts.push(format!("username = ${}", param_count));
-            params.push(username);
+            params.push(Some(username));
             param_count += 1;
+            query_parts.push(format!("username_skeleton = ${}", param_count));
+            params.push(Some(skeleton));
+            param_count += 1;
+            if username_changed {
+                query_parts.push("username_changed_at = NOW()".to_string());
+            }
         }
 
         if let Some(ref avatar_url) = update_req.avatar_url {
             query_parts.push(format!("avatar_url = ${}", param_count));
-            params.push(avatar_url);
+            params.push(Some(avatar_url));
             param_count += 1;
         }
 
+        let profile_fields = [
+            ("display_name", &update_req.display_name),
+            ("bio", &update_req.bio),
+            ("locale", &update_req.locale),
+            ("timezone", &update_req.timezone),
+        ];
+        for (column, value) in profile_fields {
+            if let Some(value) = value {
+                query_parts.push(format!("{} = ${}", column, param_count));
+                params.push(Some(value.as_str()).filter(|v| !v.is_empty()));
+                param_count += 1;
+            }
+        }
+
         if params.is_empty() {
             return Ok(());
         }
@@ -169,6 +326,26 @@ impl User {
             param_count
         );
 
+        let mut tx = pool
+            .begin()
+            .await
+            .map_err(|e| crate::errors::AppError::DatabaseError(format!("开启事务失败: {}", e)))?;
+
+        if username_changed {
+            sqlx::query(
+                r#"
+                INSERT INTO username_history (user_id, old_username, old_username_skeleton, changed_at)
+                VALUES ($1, $2, $3, NOW())
+                "#,
+            )
+            .bind(self.id)
+            .bind(&self.username)
+            .bind(username_skeleton(&self.username))
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| crate::errors::AppError::DatabaseError(format!("记录用户名历史失败: {}", e)))?;
+        }
+
         let mut sqlx_query = sqlx::query_as::<_, User>(&query);
         for param in params {
             sqlx_query = sqlx_query.bind(param);
@@ -176,14 +353,45 @@ impl User {
         sqlx_query = sqlx_query.bind(self.id);
 
         let updated_user = sqlx_query
-            .fetch_one(pool)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| map_insert_error(e, "更新用户失败"))?;
+
+        tx.commit()
             .await
-            .map_err(|e| crate::errors::AppError::DatabaseError(format!("更新用户失败: {}", e)))?;
+            .map_err(|e| crate::errors::AppError::DatabaseError(format!("提交事务失败: {}", e)))?;
 
         *self = updated_user;
         Ok(())
     }
 
+    // 用户名在保留期内是否属于其他用户的旧用户名
+    pub async fn is_username_reserved(
+        pool: &crate::db::DbPool,
+        username: &str,
+        requester: Option<Uuid>,
+        reservation_days: i64,
+    ) -> Result<bool, crate::errors::AppError> {
+        let reserved: bool = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM username_history
+                WHERE old_username_skeleton = $1
+                  AND changed_at > NOW() - make_interval(days => $2)
+                  AND ($3::uuid IS NULL OR user_id <> $3)
+            )
+            "#,
+        )
+        .bind(username_skeleton(username))
+        .bind(reservation_days as i32)
+        .bind(requester)
+        .fetch_one(pool)
+        .await
+        .map_err(|e| crate::errors::AppError::DatabaseError(format!("查询用户名保留失败: {}", e)))?;
+
+        Ok(reserved)
+    }
+
     pub async fn find_or_create_oauth_user(
         pool: &crate::db::DbPool,
         email: String,
@@ -191,6 +399,7 @@ impl User {
         avatar_url: Option<String>,
         oauth_provider: String,
         oauth_id: String,
+        policy: &UsernamePolicy,
     ) -> Result<Self, crate::errors::AppError> {
         if let Some(user) = sqlx::query_as::<_, User>(
             "SELECT * FROM users WHERE oauth_provider = $1 AND oauth_id = $2"
@@ -224,38 +433,43 @@ impl User {
         }
 
         // 确保用户名唯一
-        let final_username = Self::ensure_unique_username(pool, username).await?;
+        let final_username = Self::ensure_unique_username(pool, username, policy).await?;
 
         // 如果都不存在，创建新的 OAuth 用户
         let user = sqlx::query_as::<_, User>(
             r#"
-            INSERT INTO users (email, username, password_hash, avatar_url, oauth_provider, oauth_id, created_at, updated_at)
-            VALUES ($1, $2, '', $3, $4, $5, NOW(), NOW())
+            INSERT INTO users (email, email_normalized, username, username_skeleton, password_hash, avatar_url, oauth_provider, oauth_id, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, '', $5, $6, $7, NOW(), NOW())
             RETURNING *
             "#,
         )
         .bind(&email)
+        .bind(normalize_email(&email))
         .bind(&final_username)
+        .bind(username_skeleton(&final_username))
         .bind(&avatar_url)
         .bind(&oauth_provider)
         .bind(&oauth_id)
         .fetch_one(pool)
         .await
-        .map_err(|e| crate::errors::AppError::DatabaseError(format!("创建 OAuth 用户失败: {}", e)))?;
+        .map_err(|e| map_insert_error(e, "创建 OAuth 用户失败"))?;
 
         Ok(user)
     }
 
-    // 确保用户名唯一
+    // 确保用户名唯一（含视觉混淆）
     async fn ensure_unique_username(
         pool: &crate::db::DbPool,
         base_username: String,
+        policy: &UsernamePolicy,
     ) -> Result<String, crate::errors::AppError> {
         let mut username = base_username.clone();
         let mut counter = 1;
 
-        while Self::find_by_username(pool, &username).await?.is_some() {
-            username = format!("{}_{}", base_username, counter);
+        while Self::find_by_username_skeleton(pool, &username).await?.is_some()
+            || Self::is_username_reserved(pool, &username, None, policy.reservation_days).await?
+        {
+            username = username_with_suffix(&base_username, counter, policy.max_length);
             counter += 1;
             
             // 避免无限循环
@@ -0,0 +1,481 @@
+use std::io::Cursor;
+
+use image::imageops::FilterType;
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+
+use crate::errors::AppError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageKind {
+    Jpeg,
+    Png,
+    Gif,
+    WebP,
+}
+
+impl ImageKind {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "image/jpeg",
+            ImageKind::Png => "image/png",
+            ImageKind::Gif => "image/gif",
+            ImageKind::WebP => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageKind::Jpeg => "jpg",
+            ImageKind::Png => "png",
+            ImageKind::Gif => "gif",
+            ImageKind::WebP => "webp",
+        }
+    }
+
+    fn format(&self) -> ImageFormat {
+        match self {
+            ImageKind::Jpeg => ImageFormat::Jpeg,
+            ImageKind::Png => ImageFormat::Png,
+            ImageKind::Gif => ImageFormat::Gif,
+            ImageKind::WebP => ImageFormat::WebP,
+        }
+    }
+}
+
+// 根据文件头魔数识别真实格式，不信任客户端声明的 Content-Type
+pub fn sniff_image_kind(data: &[u8]) -> Option<ImageKind> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageKind::Jpeg)
+    } else if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some(ImageKind::Png)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(ImageKind::Gif)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(ImageKind::WebP)
+    } else {
+        None
+    }
+}
+
+// 只读取文件头，检查尺寸是否超出 max_dimension
+fn open_decoder(data: &[u8], kind: ImageKind, max_dimension: u32) -> Result<impl ImageDecoder + '_, AppError> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    reader.set_format(kind.format());
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_dimension);
+    limits.max_image_height = Some(max_dimension);
+    reader.limits(limits);
+
+    let decoder = reader
+        .into_decoder()
+        .map_err(|e| AppError::ValidationError(format!("无法解析图片: {}", e)))?;
+
+    let (width, height) = decoder.dimensions();
+    if width == 0 || height == 0 || width > max_dimension || height > max_dimension {
+        return Err(AppError::ValidationError(format!(
+            "图片尺寸 {}x{} 超出限制 (最大 {}px)",
+            width, height, max_dimension
+        )));
+    }
+    Ok(decoder)
+}
+
+// 图片宽高和 EXIF 方向，不解码像素
+pub fn image_info(data: &[u8], kind: ImageKind, max_dimension: u32) -> Result<(u32, u32, Orientation), AppError> {
+    let mut decoder = open_decoder(data, kind, max_dimension)?;
+    let (width, height) = decoder.dimensions();
+    let orientation = decoder
+        .orientation()
+        .map_err(|e| AppError::ValidationError(format!("无法读取图片方向: {}", e)))?;
+    Ok((width, height, orientation))
+}
+
+// 解码图片：先读取尺寸再完整解码，防止解压炸弹；按 EXIF 方向摆正
+pub fn decode_image(data: &[u8], kind: ImageKind, max_dimension: u32) -> Result<DynamicImage, AppError> {
+    let mut decoder = open_decoder(data, kind, max_dimension)?;
+
+    let orientation = decoder
+        .orientation()
+        .map_err(|e| AppError::ValidationError(format!("无法读取图片方向: {}", e)))?;
+
+    let mut image = DynamicImage::from_decoder(decoder)
+        .map_err(|e| AppError::ValidationError(format!("无法解析图片: {}", e)))?;
+    image.apply_orientation(orientation);
+
+    Ok(image)
+}
+
+// 居中裁剪为正方形后缩放
+pub fn square_thumbnail(image: &DynamicImage, size: u32) -> DynamicImage {
+    let side = image.width().min(image.height());
+    let x = (image.width() - side) / 2;
+    let y = (image.height() - side) / 2;
+    image
+        .crop_imm(x, y, side, side)
+        .resize_exact(size, size, FilterType::Lanczos3)
+}
+
+// 重新编码为 PNG，原始元数据（包括 EXIF）不会被保留
+pub fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, AppError> {
+    let mut buffer = Cursor::new(Vec::new());
+    image
+        .write_to(&mut buffer, ImageFormat::Png)
+        .map_err(|e| AppError::InternalError(format!("图片编码失败: {}", e)))?;
+    Ok(buffer.into_inner())
+}
+
+// 预处理时允许解码的最大边长，超过的图片直接拒绝
+const MAX_SOURCE_DIMENSION: u32 = 16_384;
+// 依次尝试的 JPEG 质量
+const JPEG_QUALITIES: &[u8] = &[85, 75, 60];
+// 所有质量都超出字节上限时，每轮把边长缩小到的比例和最多轮数
+const SHRINK_FACTOR: f64 = 0.75;
+const MAX_SHRINK_ROUNDS: usize = 4;
+
+// 提供商对输入图片的限制
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ImageLimits {
+    pub formats: &'static [ImageKind],
+    pub max_bytes: usize,
+    // 最长边
+    pub max_dimension: u32,
+    pub max_pixels: u64,
+}
+
+#[derive(Debug)]
+pub struct PreparedImage {
+    pub kind: ImageKind,
+    pub data: Vec<u8>,
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, AppError> {
+    let mut buffer = Vec::new();
+    image
+        .to_rgb8()
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, quality))
+        .map_err(|e| AppError::InternalError(format!("图片编码失败: {}", e)))?;
+    Ok(buffer)
+}
+
+// 按最长边和总像素数等比缩小，不放大
+fn fit_within(image: DynamicImage, limits: &ImageLimits) -> DynamicImage {
+    let (width, height) = (image.width() as f64, image.height() as f64);
+    let scale = (limits.max_dimension as f64 / width.max(height))
+        .min((limits.max_pixels as f64 / (width * height)).sqrt())
+        .min(1.0);
+    if scale >= 1.0 {
+        return image;
+    }
+    let width = ((width * scale) as u32).max(1);
+    let height = ((height * scale) as u32).max(1);
+    image.resize(width, height, FilterType::Lanczos3)
+}
+
+// 只有存在非不透明像素时才需要保留透明通道，GIF 等解码为 RGBA 的图片多数并不透明
+fn is_transparent(image: &DynamicImage) -> bool {
+    image.color().has_alpha() && image.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+// 有透明像素时优先 PNG，否则按质量从高到低尝试 JPEG，返回第一个不超过字节上限的结果
+fn encode_within(image: &DynamicImage, limits: &ImageLimits) -> Result<Option<PreparedImage>, AppError> {
+    if is_transparent(image) && limits.formats.contains(&ImageKind::Png) {
+        let data = encode_png(image)?;
+        if data.len() <= limits.max_bytes {
+            return Ok(Some(PreparedImage { kind: ImageKind::Png, data }));
+        }
+    }
+    for quality in JPEG_QUALITIES {
+        let data = encode_jpeg(image, *quality)?;
+        if data.len() <= limits.max_bytes {
+            return Ok(Some(PreparedImage { kind: ImageKind::Jpeg, data }));
+        }
+    }
+    Ok(None)
+}
+
+// 不重新编码，直接去掉可能包含拍摄地点、设备等信息的元数据段：
+// JPEG 的 APP1（EXIF/XMP）、APP13（IPTC）和注释，PNG 的文本、eXIf 和 tIME 块，WebP 的 EXIF 和 XMP 块。
+// 颜色配置等影响显示的段保留；GIF 没有标准的元数据段，原样返回。结构无法解析时返回 None
+fn strip_metadata(data: &[u8], kind: ImageKind) -> Option<Vec<u8>> {
+    match kind {
+        ImageKind::Jpeg => strip_jpeg_metadata(data),
+        ImageKind::Png => strip_png_metadata(data),
+        ImageKind::WebP => strip_webp_metadata(data),
+        ImageKind::Gif => Some(data.to_vec()),
+    }
+}
+
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = data.get(..2)?.to_vec();
+    let mut pos = 2;
+    loop {
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        // 标记前允许有填充的 0xFF
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        // 扫描数据开始后不再有元数据段
+        if marker == 0xDA {
+            output.extend_from_slice(&data[pos..]);
+            return Some(output);
+        }
+        let length = usize::from(u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]));
+        let end = pos + 2 + length;
+        let segment = data.get(pos..end)?;
+        if !matches!(marker, 0xE1 | 0xED | 0xFE) {
+            output.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = data.get(..8)?.to_vec();
+    let mut pos = 8;
+    while pos < data.len() {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let end = pos + 12 + length;
+        let chunk = data.get(pos..end)?;
+        if !matches!(&chunk[4..8], b"tEXt" | b"zTXt" | b"iTXt" | b"eXIf" | b"tIME") {
+            output.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+    Some(output)
+}
+
+fn strip_webp_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = data.get(..12)?.to_vec();
+    let mut pos = 12;
+    while pos < data.len() {
+        let size = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        // 块按偶数字节对齐，文件末尾的块可能省略填充字节
+        let end = (pos + 8 + size + size % 2).min(data.len());
+        let chunk = data.get(pos..end)?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                // 清除扩展头中的 EXIF 和 XMP 标志位
+                *chunk.get_mut(8)? &= !0x0C;
+                output.extend_from_slice(&chunk);
+            }
+            _ => output.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    let riff_size = u32::try_from(output.len() - 8).ok()?;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(output)
+}
+
+// 识别真实格式；格式、大小和尺寸都符合限制且无需旋转时只去掉元数据，
+// 否则按 EXIF 方向摆正、缩小并重新编码（不保留元数据）
+pub fn prepare_image(data: Vec<u8>, limits: &ImageLimits) -> Result<PreparedImage, AppError> {
+    let kind = sniff_image_kind(&data)
+        .ok_or_else(|| AppError::ValidationError("不支持的图片格式，仅支持 JPEG/PNG/GIF/WebP".to_string()))?;
+    let (width, height, orientation) = image_info(&data, kind, MAX_SOURCE_DIMENSION)?;
+
+    let fits = limits.formats.contains(&kind)
+        && data.len() <= limits.max_bytes
+        && width.max(height) <= limits.max_dimension
+        && u64::from(width) * u64::from(height) <= limits.max_pixels;
+    if fits && orientation == Orientation::NoTransforms {
+        if let Some(data) = strip_metadata(&data, kind) {
+            return Ok(PreparedImage { kind, data });
+        }
+    }
+
+    let mut image = fit_within(decode_image(&data, kind, MAX_SOURCE_DIMENSION)?, limits);
+    for _ in 0..=MAX_SHRINK_ROUNDS {
+        if let Some(prepared) = encode_within(&image, limits)? {
+            log::debug!(
+                "图片已预处理: {}x{} {} 字节 -> {}x{} {} {} 字节",
+                width, height, data.len(), image.width(), image.height(),
+                prepared.kind.mime_type(), prepared.data.len(),
+            );
+            return Ok(prepared);
+        }
+        let width = ((image.width() as f64 * SHRINK_FACTOR) as u32).max(1);
+        let height = ((image.height() as f64 * SHRINK_FACTOR) as u32).max(1);
+        image = image.resize(width, height, FilterType::Lanczos3);
+    }
+
+    Err(AppError::ValidationError(format!("图片压缩后仍超过 {} 字节", limits.max_bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage, RgbaImage};
+
+    const LIMITS: ImageLimits = ImageLimits {
+        formats: &[ImageKind::Jpeg, ImageKind::Png, ImageKind::WebP],
+        max_bytes: 1024 * 1024,
+        max_dimension: 1024,
+        max_pixels: 1024 * 1024,
+    };
+
+    fn photo() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 24, |x, y| image::Rgb([x as u8 * 8, y as u8 * 10, 128])))
+    }
+
+    // 只有 IFD 头、没有条目的 EXIF，加上一段假的 GPS 字符串便于检查
+    fn exif_payload() -> Vec<u8> {
+        [b"Exif\0\0II*\0\x08\0\0\0\0\0\0\0\0\0".as_slice(), b"GPS 31.2304N 121.4737E"].concat()
+    }
+
+    fn with_jpeg_exif(jpeg: &[u8]) -> Vec<u8> {
+        let payload = exif_payload();
+        let length = (payload.len() + 2) as u16;
+        [&jpeg[..2], &[0xFF, 0xE1], &length.to_be_bytes(), &payload, &jpeg[2..]].concat()
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn fitting_jpeg_is_passed_through_without_exif() {
+        let jpeg = encode_jpeg(&photo(), 85).unwrap();
+        let tagged = with_jpeg_exif(&jpeg);
+        assert!(contains(&tagged, b"GPS"));
+
+        let prepared = prepare_image(tagged, &LIMITS).unwrap();
+
+        assert_eq!(prepared.kind, ImageKind::Jpeg);
+        assert_eq!(prepared.data, jpeg);
+    }
+
+    #[test]
+    fn png_text_chunks_are_removed() {
+        let png = encode_png(&photo()).unwrap();
+        let text = b"Comment\0GPS 31.2304N 121.4737E";
+        let mut chunk = (text.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(b"tEXt");
+        chunk.extend_from_slice(text);
+        chunk.extend_from_slice(&[0; 4]);
+        // 放在 IHDR（8 字节签名 + 25 字节块）之后
+        let tagged = [&png[..33], &chunk, &png[33..]].concat();
+
+        assert_eq!(strip_metadata(&tagged, ImageKind::Png), Some(png));
+    }
+
+    #[test]
+    fn webp_exif_chunk_and_flag_are_removed() {
+        let exif = exif_payload();
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        data.extend_from_slice(b"VP8X");
+        data.extend_from_slice(&10u32.to_le_bytes());
+        data.extend_from_slice(&[0x08, 0, 0, 0, 31, 0, 0, 23, 0, 0]);
+        data.extend_from_slice(b"VP8L");
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(&[1, 2, 3, 0]);
+        data.extend_from_slice(b"EXIF");
+        data.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+        data.extend_from_slice(&exif);
+        if exif.len() % 2 == 1 {
+            data.push(0);
+        }
+        let riff_size = (data.len() - 8) as u32;
+        data[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+        let stripped = strip_metadata(&data, ImageKind::WebP).unwrap();
+
+        assert!(!contains(&stripped, b"EXIF"));
+        assert!(!contains(&stripped, b"GPS"));
+        assert_eq!(stripped[20], 0);
+        assert_eq!(stripped.len(), 12 + 18 + 12);
+        assert_eq!(u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize, stripped.len() - 8);
+    }
+
+    // 不可压缩的伪随机噪声
+    fn noise(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let seed = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)).wrapping_mul(2_654_435_761);
+            image::Rgb([(seed >> 24) as u8, (seed >> 16) as u8, (seed >> 8) as u8])
+        }))
+    }
+
+    fn dimensions(prepared: &PreparedImage) -> (u32, u32) {
+        decode_image(&prepared.data, prepared.kind, MAX_SOURCE_DIMENSION).unwrap().dimensions()
+    }
+
+    #[test]
+    fn downscales_to_max_dimension_and_max_pixels() {
+        let wide = prepare_image(encode_png(&DynamicImage::new_rgb8(4096, 1024)).unwrap(), &LIMITS).unwrap();
+        assert_eq!(wide.kind, ImageKind::Jpeg);
+        assert_eq!(dimensions(&wide), (1024, 256));
+
+        let limits = ImageLimits { max_pixels: 250_000, ..LIMITS };
+        let square = prepare_image(encode_png(&DynamicImage::new_rgb8(1000, 1000)).unwrap(), &limits).unwrap();
+        assert_eq!(dimensions(&square), (500, 500));
+    }
+
+    #[test]
+    fn lowers_quality_and_shrinks_until_within_max_bytes() {
+        let limits = ImageLimits { max_bytes: 40 * 1024, ..LIMITS };
+        let data = encode_png(&noise(512, 512)).unwrap();
+        assert!(encode_jpeg(&noise(512, 512), *JPEG_QUALITIES.last().unwrap()).unwrap().len() > limits.max_bytes);
+
+        let prepared = prepare_image(data, &limits).unwrap();
+
+        assert_eq!(prepared.kind, ImageKind::Jpeg);
+        assert!(prepared.data.len() <= limits.max_bytes);
+        let (width, height) = dimensions(&prepared);
+        assert!(width < 512 && width == height, "{}x{}", width, height);
+
+        let impossible = ImageLimits { max_bytes: 100, ..LIMITS };
+        assert!(matches!(
+            prepare_image(encode_png(&noise(512, 512)).unwrap(), &impossible),
+            Err(AppError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn converts_unaccepted_formats() {
+        let mut gif = Cursor::new(Vec::new());
+        photo().write_to(&mut gif, ImageFormat::Gif).unwrap();
+
+        let prepared = prepare_image(gif.into_inner(), &LIMITS).unwrap();
+
+        assert_eq!(prepared.kind, ImageKind::Jpeg);
+        assert_eq!(dimensions(&prepared), (32, 24));
+
+        // 有透明像素时保留为 PNG
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2048, 8, image::Rgba([0, 0, 0, 0])));
+        let prepared = prepare_image(encode_png(&transparent).unwrap(), &LIMITS).unwrap();
+        assert_eq!(prepared.kind, ImageKind::Png);
+        assert_eq!(dimensions(&prepared), (1024, 4));
+    }
+
+    #[test]
+    fn applies_exif_orientation() {
+        // 方向标签 6：显示时顺时针旋转 90°
+        let exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0";
+        let jpeg = encode_jpeg(&photo(), 95).unwrap();
+        let length = (exif.len() + 2) as u16;
+        let tagged = [&jpeg[..2], &[0xFF, 0xE1], &length.to_be_bytes(), exif.as_slice(), &jpeg[2..]].concat();
+
+        let prepared = prepare_image(tagged, &LIMITS).unwrap();
+
+        let image = decode_image(&prepared.data, prepared.kind, MAX_SOURCE_DIMENSION).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (24, 32));
+        // 原图左下角（红 0、绿 230）转到左上角，右上角（红 248、绿 0）转到右下角
+        let (top_left, bottom_right) = (image.get_pixel(0, 0), image.get_pixel(23, 31));
+        assert!(top_left[0] < 40 && top_left[1] > 190, "{:?}", top_left);
+        assert!(bottom_right[0] > 210 && bottom_right[1] < 40, "{:?}", bottom_right);
+    }
+
+    #[test]
+    fn unparseable_structure_is_not_stripped() {
+        assert_eq!(strip_metadata(&[0xFF, 0xD8, 0x00, 0x01], ImageKind::Jpeg), None);
+    }
+}
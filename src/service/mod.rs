@@ -1 +1,3 @@
-pub mod redis_service;
\ No newline at end of file
+pub mod redis_service;
+pub mod image_processing;
+pub mod storage;
\ No newline at end of file
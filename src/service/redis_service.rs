@@ -1,14 +1,67 @@
+use std::sync::Arc;
+
+use redis::aio::MultiplexedConnection;
+use redis::{FromRedisValue, RedisError};
+use tokio::sync::Mutex;
+
 use crate::errors::AppError;
 
 #[derive(Clone)]
 pub struct RedisService {
     client: redis::Client,
+    // 首次使用时建立，多路复用连接可在请求间共享
+    connection: Arc<Mutex<Option<MultiplexedConnection>>>,
 }
 
 impl RedisService {
     pub fn new(redis_url: &str) -> Result<Self, AppError> {
         let client = redis::Client::open(redis_url)
             .map_err(|e| AppError::RedisError(e.to_string()))?;
-        Ok(Self { client })
+        Ok(Self { client, connection: Arc::new(Mutex::new(None)) })
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, AppError> {
+        let mut cached = self.connection.lock().await;
+        if let Some(connection) = cached.as_ref() {
+            return Ok(connection.clone());
+        }
+        let connection = self.client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| AppError::RedisError(e.to_string()))?;
+        *cached = Some(connection.clone());
+        Ok(connection)
+    }
+
+    // 连接断开后丢弃缓存的连接，下次调用重新建立
+    async fn handle_error(&self, error: RedisError) -> AppError {
+        if error.is_io_error() || error.is_connection_dropped() || error.is_connection_refusal() {
+            self.connection.lock().await.take();
+        }
+        AppError::RedisError(error.to_string())
     }
-}
\ No newline at end of file
+
+    pub async fn query<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> Result<T, AppError> {
+        let mut connection = self.connection().await?;
+        match cmd.query_async(&mut connection).await {
+            Ok(value) => Ok(value),
+            Err(e) => Err(self.handle_error(e).await),
+        }
+    }
+
+    pub async fn invoke<T: FromRedisValue>(&self, invocation: &redis::ScriptInvocation<'_>) -> Result<T, AppError> {
+        let mut connection = self.connection().await?;
+        match invocation.invoke_async(&mut connection).await {
+            Ok(value) => Ok(value),
+            Err(e) => Err(self.handle_error(e).await),
+        }
+    }
+
+    pub async fn pipeline<T: FromRedisValue>(&self, pipeline: &redis::Pipeline) -> Result<T, AppError> {
+        let mut connection = self.connection().await?;
+        match pipeline.query_async(&mut connection).await {
+            Ok(value) => Ok(value),
+            Err(e) => Err(self.handle_error(e).await),
+        }
+    }
+}